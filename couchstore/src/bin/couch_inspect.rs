@@ -0,0 +1,432 @@
+//! Inspect couchstore files, in the spirit of couch_dbdump and couch_dbinfo.
+
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use couchstore::{DocInfo, Header, OpenOptions, Reader};
+use serde_json::{json, Value};
+use std::{io::Write, ops::ControlFlow, path::PathBuf, process::exit};
+
+const USAGE: &str = "\
+Usage: couch_inspect [--json] <command> ...
+
+Commands:
+    headers <file>                          List every header, newest first
+    dump <file> [--by-seq] [--body]         Dump every document
+    dump <file> --key <id> [--body]         Dump a single document by id
+    dump <file> --seq <seqno> [--body]      Dump a single document by seqno
+    vbstate <file>                          Print the _local/vbstate document
+    verify [--scrub] <file or directory>... Verify the checksum of every chunk
+
+Options:
+    --json            Print one JSON object per line instead of text
+    --header <pos>    Read dump and vbstate from an older header
+    --scrub           Verify every header and everything reachable from them,
+                      and report every problem rather than the first";
+
+const LOCAL_DOC_KEY_VBSTATE: &str = "_local/vbstate";
+
+/// The metadata ep_engine stores in `rev_meta`
+struct Metadata {
+    cas: u64,
+    expiry_time: u32,
+    flags: u32,
+    data_type: u8,
+}
+
+impl Metadata {
+    fn decode(mut r: &[u8]) -> Option<Self> {
+        let cas = r.read_u64::<BigEndian>().ok()?;
+        let expiry_time = r.read_u32::<BigEndian>().ok()?;
+        let flags = r.read_u32::<LittleEndian>().ok()?;
+        let _flex_code = r.read_u8().ok()?;
+        let data_type = r.read_u8().ok()?;
+        Some(Metadata {
+            cas,
+            expiry_time,
+            flags,
+            data_type,
+        })
+    }
+
+    fn data_type_names(&self) -> Vec<&'static str> {
+        let names = [(0x01, "json"), (0x02, "snappy"), (0x04, "xattr")];
+        let mut res = names
+            .iter()
+            .filter(|(bit, _)| self.data_type & bit != 0)
+            .map(|&(_, name)| name)
+            .collect::<Vec<_>>();
+        if res.is_empty() {
+            res.push("raw");
+        }
+        res
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    fn print(self, text: String, json: Value) {
+        let line = match self {
+            Format::Text => text,
+            Format::Json => json.to_string(),
+        };
+        // Stop quietly if the output was closed, e.g. piped into `head`
+        if writeln!(std::io::stdout(), "{line}").is_err() {
+            exit(0);
+        }
+    }
+}
+
+/// Command line arguments, split into `--flag [value]` options and the rest
+struct Args {
+    positional: Vec<String>,
+    flags: Vec<(String, Option<String>)>,
+}
+
+impl Args {
+    /// Options that are followed by a value
+    const WITH_VALUE: [&'static str; 3] = ["--key", "--seq", "--header"];
+
+    fn parse(args: impl Iterator<Item = String>) -> Args {
+        let mut positional = vec![];
+        let mut flags = vec![];
+        let mut args = args.peekable();
+
+        while let Some(arg) = args.next() {
+            if Self::WITH_VALUE.contains(&arg.as_str()) {
+                let value = args.next().unwrap_or_else(|| usage());
+                flags.push((arg, Some(value)));
+            } else if arg.starts_with("--") {
+                flags.push((arg, None));
+            } else {
+                positional.push(arg);
+            }
+        }
+
+        Args { positional, flags }
+    }
+
+    fn has(&self, flag: &str) -> bool {
+        self.flags.iter().any(|(name, _)| name == flag)
+    }
+
+    fn value(&self, flag: &str) -> Option<&str> {
+        self.flags
+            .iter()
+            .find(|(name, _)| name == flag)
+            .and_then(|(_, value)| value.as_deref())
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    exit(2);
+}
+
+fn main() {
+    let args = Args::parse(std::env::args().skip(1));
+
+    let format = if args.has("--json") {
+        Format::Json
+    } else {
+        Format::Text
+    };
+
+    let (command, paths) = match args.positional.split_first() {
+        Some((command, paths)) if !paths.is_empty() => (command.as_str(), paths),
+        _ => usage(),
+    };
+
+    let result = match command {
+        "headers" => open(paths, &args).and_then(|reader| headers(&reader, format)),
+        "dump" => open(paths, &args).and_then(|reader| dump(&reader, &args, format)),
+        "vbstate" => open(paths, &args).and_then(|reader| vbstate(&reader, format)),
+        "verify" => {
+            if !verify(paths, args.has("--scrub"), format) {
+                exit(1);
+            }
+            Ok(())
+        }
+        _ => usage(),
+    };
+
+    if let Err(err) = result {
+        eprintln!("{err}");
+        exit(1);
+    }
+}
+
+fn open(paths: &[String], args: &Args) -> couchstore::Result<Reader> {
+    let [path] = paths else { usage() };
+
+    match args.value("--header") {
+        Some(pos) => Reader::open_at_header(path, pos.parse().unwrap_or_else(|_| usage())),
+        None => Reader::open(path),
+    }
+}
+
+fn headers(reader: &Reader, format: Format) -> couchstore::Result<()> {
+    for header in reader.headers()? {
+        print_header(&header, format);
+    }
+    Ok(())
+}
+
+fn print_header(header: &Header, format: Format) {
+    format.print(
+        format!(
+            "position={} version={} update_seq={} purge_seq={} timestamp={}",
+            header.position(),
+            u8::from(header.disk_version()),
+            header.update_seq,
+            header.purge_seq,
+            header.timestamp(),
+        ),
+        json!({
+            "position": header.position(),
+            "version": u8::from(header.disk_version()),
+            "update_seq": header.update_seq,
+            "purge_seq": header.purge_seq,
+            "timestamp": header.timestamp(),
+        }),
+    );
+}
+
+fn dump(reader: &Reader, args: &Args, format: Format) -> couchstore::Result<()> {
+    let with_body = args.has("--body");
+
+    if let Some(key) = args.value("--key") {
+        let docinfo = reader.docinfo_by_id(key)?;
+        return print_doc(reader, &docinfo, with_body, format);
+    }
+
+    if let Some(seq) = args.value("--seq") {
+        let seq = seq.parse().unwrap_or_else(|_| usage());
+        let docinfo = reader.docinfo_by_sequence(seq)?;
+        return print_doc(reader, &docinfo, with_body, format);
+    }
+
+    if args.has("--by-seq") {
+        reader
+            .changes_since_range(0, u64::MAX, |docinfo| {
+                print_doc(reader, &docinfo, with_body, format)?;
+                Ok(ControlFlow::Continue(()))
+            })
+            .map(|_| ())
+    } else {
+        reader.all_docs(&[], None, false, |docinfo| {
+            print_doc(reader, &docinfo, with_body, format)
+        })
+    }
+}
+
+fn print_doc(
+    reader: &Reader,
+    docinfo: &DocInfo,
+    with_body: bool,
+    format: Format,
+) -> couchstore::Result<()> {
+    let body = if with_body && docinfo.bp != 0 {
+        Some(String::from_utf8_lossy(&read_body(reader, docinfo)?).into_owned())
+    } else {
+        None
+    };
+
+    // Keys start with a collection id, so escape anything unprintable
+    let mut text = format!(
+        "id={} seq={} rev={} deleted={} size={}",
+        docinfo.id.escape_ascii(),
+        docinfo.db_seq,
+        docinfo.rev_seq,
+        docinfo.deleted,
+        docinfo.physical_size
+    );
+    let mut json = json!({
+        "id": String::from_utf8_lossy(&docinfo.id),
+        "seq": docinfo.db_seq,
+        "rev": docinfo.rev_seq,
+        "deleted": docinfo.deleted,
+        "size": docinfo.physical_size,
+    });
+
+    match Metadata::decode(&docinfo.rev_meta) {
+        Some(meta) => {
+            let data_type = meta.data_type_names();
+            text += &format!(
+                " cas={} expiry={} flags={:#010x} datatype={}",
+                meta.cas,
+                meta.expiry_time,
+                meta.flags,
+                data_type.join(",")
+            );
+            json["cas"] = meta.cas.into();
+            json["expiry"] = meta.expiry_time.into();
+            json["flags"] = meta.flags.into();
+            json["datatype"] = data_type.into();
+        }
+        None if !docinfo.rev_meta.is_empty() => {
+            let rev_meta = hex::encode(&docinfo.rev_meta);
+            text += &format!(" rev_meta={rev_meta}");
+            json["rev_meta"] = rev_meta.into();
+        }
+        None => {}
+    }
+
+    if let Some(body) = body {
+        text += &format!(" body={body}");
+        json["body"] = body.into();
+    }
+
+    format.print(text, json);
+
+    Ok(())
+}
+
+/// The document's body, inflated. ep_engine may store a snappy body without
+/// the content meta saying so, in which case the datatype in the metadata
+/// might, or the body is simply valid snappy.
+fn read_body(reader: &Reader, docinfo: &DocInfo) -> couchstore::Result<Vec<u8>> {
+    let doc = reader.open_doc_with_docinfo(docinfo, OpenOptions::DECOMPRESS_DOC_BODIES)?;
+    let snappy = Metadata::decode(&docinfo.rev_meta).is_some_and(|meta| meta.data_type & 0x02 != 0);
+
+    let mut decoder = snap::raw::Decoder::new();
+    match decoder.decompress_vec(&doc.data) {
+        Ok(body) => Ok(body),
+        Err(_) if snappy => Err(couchstore::Error::Corrupt("snappy compressed body")),
+        Err(_) => Ok(doc.data),
+    }
+}
+
+fn vbstate(reader: &Reader, format: Format) -> couchstore::Result<()> {
+    let doc = reader.open_local_document(LOCAL_DOC_KEY_VBSTATE)?;
+    let json = doc.json.ok_or(couchstore::Error::DocNotFound)?;
+    let value: Value = serde_json::from_slice(&json)
+        .map_err(|_| couchstore::Error::Corrupt(LOCAL_DOC_KEY_VBSTATE))?;
+
+    format.print(format!("{value:#}"), value);
+
+    Ok(())
+}
+
+/// Verify every couchstore file in `paths`, descending into directories.
+/// Returns false if any file failed.
+fn verify(paths: &[String], scrub: bool, format: Format) -> bool {
+    let mut files = vec![];
+    for path in paths {
+        let path = PathBuf::from(path);
+        if path.is_dir() {
+            match couch_files(&path) {
+                Ok(found) => files.extend(found),
+                Err(err) => {
+                    eprintln!("{}: {err}", path.display());
+                    return false;
+                }
+            }
+        } else {
+            files.push(path);
+        }
+    }
+
+    let mut ok = true;
+
+    for file in files {
+        let name = file.display().to_string();
+
+        if scrub {
+            ok &= scrub_file(&file, &name, format);
+            continue;
+        }
+
+        let result = Reader::open(&file).and_then(|reader| reader.verify());
+
+        match result {
+            Ok(chunks) => format.print(
+                format!("{name}: ok ({chunks} chunks)"),
+                json!({ "file": name, "ok": true, "chunks": chunks }),
+            ),
+            Err(err) => {
+                ok = false;
+                format.print(
+                    format!("{name}: {err}"),
+                    json!({ "file": name, "ok": false, "error": err.to_string() }),
+                );
+            }
+        }
+    }
+
+    ok
+}
+
+/// Scrub a single file, printing every problem found. Returns false if there
+/// were any.
+fn scrub_file(file: &PathBuf, name: &str, format: Format) -> bool {
+    let report = match Reader::open(file).and_then(|reader| reader.scrub()) {
+        Ok(report) => report,
+        Err(err) => {
+            format.print(
+                format!("{name}: {err}"),
+                json!({ "file": name, "ok": false, "error": err.to_string() }),
+            );
+            return false;
+        }
+    };
+
+    for err in &report.errors {
+        format.print(
+            format!("{name}: {err}"),
+            json!({ "file": name, "ok": false, "error": err.to_string() }),
+        );
+    }
+
+    format.print(
+        format!(
+            "{name}: {} ({} headers, {} chunks, {} errors)",
+            if report.is_ok() { "ok" } else { "corrupt" },
+            report.headers,
+            report.chunks,
+            report.errors.len()
+        ),
+        json!({
+            "file": name,
+            "ok": report.is_ok(),
+            "headers": report.headers,
+            "chunks": report.chunks,
+            "errors": report.errors.len(),
+        }),
+    );
+
+    report.is_ok()
+}
+
+/// The couchstore files in `dir`, sorted by name
+fn couch_files(dir: &PathBuf) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.contains(".couch.") && !name.ends_with(".compact") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_body() {
+        // Stored snappy compressed, with neither the content meta nor the
+        // datatype saying so
+        let reader = Reader::open("../test-data/travel-sample/0.couch.1").unwrap();
+        let docinfo = reader.docinfo_by_id(b"\0landmark_16320").unwrap();
+        let body = read_body(&reader, &docinfo).unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["name"], "Gabriel's Wharf");
+    }
+}
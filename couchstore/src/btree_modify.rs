@@ -5,7 +5,8 @@ use byteorder::WriteBytesExt;
 use crate::{
     btree_read::NodeType,
     node_types::{read_kv, write_kv},
-    NodePointer, TreeFile,
+    reduces::ReduceFn,
+    Error, NodePointer, Result, TreeFile,
 };
 
 #[derive(Debug)]
@@ -33,24 +34,34 @@ impl<'a, Ctx> CouchfileModifyResult<'a, Ctx> {
     }
 }
 
-trait Modifier: Sized {
-    fn on_fetch(&mut self, req: CouchfileModifyRequest<Self>, key: &[u8], value: &[u8]);
+/// Context attached to a modify request that is told about the existing
+/// value of every key touched by a `Fetch` or `FetchInsert` action.
+pub trait Modifier: Debug + Default {
+    /// Called with the value currently stored for `key`, or `None` if the
+    /// key is not in the tree.
+    fn on_fetch(&mut self, _key: &[u8], _value: Option<&[u8]>) {}
 }
 
-#[derive(Debug)]
+impl Modifier for () {}
+
+#[derive(Debug, Default)]
 pub struct UpdateIdContext {
     pub seq_actions: Vec<CouchfileModifyAction>,
 }
 
 impl Modifier for UpdateIdContext {
-    fn on_fetch(&mut self, _req: CouchfileModifyRequest<Self>, _key: &[u8], value: &[u8]) {
-        let old_seq = value[0..6].to_vec();
-
-        self.seq_actions.push(CouchfileModifyAction {
-            key: old_seq,
-            data: None,
-            action_type: CouchfileModifyActionType::Remove,
-        });
+    fn on_fetch(&mut self, _key: &[u8], value: Option<&[u8]>) {
+        // The first 48 bits of a by-id value is the seqno of the previous
+        // revision, which must be removed from the by-seq tree.
+        if let Some(value) = value {
+            let old_seq = value[0..6].to_vec();
+
+            self.seq_actions.push(CouchfileModifyAction {
+                key: old_seq,
+                data: None,
+                action_type: CouchfileModifyActionType::Remove,
+            });
+        }
     }
 }
 
@@ -60,6 +71,10 @@ pub struct CouchfileModifyRequest<Ctx> {
     pub context: Ctx,
     pub kv_chunk_threshold: usize,
     pub kp_chunk_threshold: usize,
+    /// Computes the reduce value of a KV node from its items
+    pub reduce: Option<ReduceFn>,
+    /// Computes the reduce value of a KP node from its child pointers
+    pub rereduce: Option<ReduceFn>,
 }
 
 #[derive(Debug)]
@@ -85,15 +100,27 @@ pub enum CouchfileModifyActionType {
 }
 
 impl TreeFile {
-    pub fn modify_btree<Ctx: Debug>(
+    /// Apply the actions in `req` to the tree rooted at `root` and return the
+    /// new root. Actions must be sorted by key. Any values fetched along the
+    /// way are passed to `req.context`, which is left on the request for the
+    /// caller to inspect.
+    pub fn modify_btree<Ctx: Modifier>(
         &mut self,
-        req: CouchfileModifyRequest<Ctx>,
+        req: &mut CouchfileModifyRequest<Ctx>,
         mut root: Option<NodePointer>,
-    ) -> Option<NodePointer> {
+    ) -> Result<Option<NodePointer>> {
+        let mut ctx = std::mem::take(&mut req.context);
         let num_actions = req.actions.len();
-        let mut root_result = CouchfileModifyResult::new(&req);
+        let mut root_result = CouchfileModifyResult::new(req);
         root_result.node_type = NodeType::KPNode;
-        self.modify_node(&req, root.as_mut(), 0, num_actions, &mut root_result);
+        self.modify_node(
+            req,
+            &mut ctx,
+            root.as_mut(),
+            0,
+            num_actions,
+            &mut root_result,
+        )?;
 
         let mut new_root = root;
 
@@ -101,20 +128,64 @@ impl TreeFile {
             if root_result.values.len() > 1 || !root_result.pointers.is_empty() {
                 // The root was split
                 // Write it to disk and return the pointer to it.
-                new_root = self.finish_root(&req, &mut root_result);
+                new_root = self.finish_root(req, &mut root_result)?;
             } else {
-                new_root = root_result.values.back().unwrap().pointer.clone();
+                // Either a single node, or nothing left at all if every key
+                // was removed.
+                new_root = root_result
+                    .values
+                    .back()
+                    .and_then(|value| value.pointer.clone());
             }
         }
 
-        new_root
+        req.context = ctx;
+
+        Ok(new_root)
+    }
+
+    /// Start building a new tree from scratch. Items must then be pushed with
+    /// [TreeFile::mr_push_item] in key order, and the tree completed with
+    /// [TreeFile::complete_new_btree].
+    pub fn start_new_btree<Ctx: Debug>(
+        req: &CouchfileModifyRequest<Ctx>,
+    ) -> CouchfileModifyResult<'_, Ctx> {
+        let mut result = CouchfileModifyResult::new(req);
+        result.node_type = NodeType::KVNode;
+        result.modified = true;
+        result.compacting = true;
+        result
+    }
+
+    /// Flush the remaining items of a tree started with
+    /// [TreeFile::start_new_btree] and write out its KP nodes. Returns the
+    /// root, or `None` if no items were pushed.
+    pub fn complete_new_btree<'a, Ctx: Debug>(
+        &mut self,
+        req: &'a CouchfileModifyRequest<Ctx>,
+        mut result: CouchfileModifyResult<'a, Ctx>,
+    ) -> Result<Option<NodePointer>> {
+        self.flush_mr(&mut result)?;
+
+        let mut target = CouchfileModifyResult::new(req);
+        target.modified = true;
+        target.node_type = NodeType::KPNode;
+        target.compacting = true;
+
+        self.mr_move_pointers(&mut result, &mut target)?;
+
+        if target.values.len() > 1 || !target.pointers.is_empty() {
+            self.finish_root(req, &mut target)
+        } else {
+            Ok(target.values.pop_back().and_then(|value| value.pointer))
+        }
     }
 
     fn finish_root<'a, Ctx: Debug>(
         &mut self,
         req: &'a CouchfileModifyRequest<Ctx>,
-        root_result: &'a mut CouchfileModifyResult<'a, Ctx>,
-    ) -> Option<NodePointer> {
+        root_result: &mut CouchfileModifyResult<'a, Ctx>,
+    ) -> Result<Option<NodePointer>> {
         let new_root;
 
         let mut collector = CouchfileModifyResult::new(req);
@@ -122,7 +193,7 @@ impl TreeFile {
         collector.modified = true;
         collector.node_type = NodeType::KPNode;
 
-        self.flush_mr(root_result);
+        self.flush_mr(root_result)?;
 
         loop {
             if root_result.pointers.len() == 1 {
@@ -133,43 +204,44 @@ impl TreeFile {
             } else {
                 // The root result split into more than one kp_node.
                 // Move the pointer list to the value list and write out the new node.
-                self.mr_move_pointers(root_result, &mut collector);
+                self.mr_move_pointers(root_result, &mut collector)?;
 
-                self.flush_mr(&mut collector);
+                self.flush_mr(&mut collector)?;
 
                 std::mem::swap(root_result, &mut collector);
             }
         }
 
-        new_root
+        Ok(new_root)
     }
 
-    pub fn modify_node<'a, Ctx: Debug>(
+    pub fn modify_node<'a, Ctx: Modifier>(
         &mut self,
         req: &'a CouchfileModifyRequest<Ctx>,
+        ctx: &mut Ctx,
         node_pointer: Option<&mut NodePointer>,
         mut start: usize,
         end: usize,
         dst: &mut CouchfileModifyResult<'a, Ctx>,
-    ) {
+    ) -> Result<()> {
         let mut node_buf = Vec::new();
 
         if let Some(node_pointer) = &node_pointer {
-            node_buf = self.read_compressed(node_pointer.pointer as usize);
+            node_buf = self.read_compressed(node_pointer.pointer as usize)?;
         }
 
         let mut cursor = Cursor::new(node_buf.as_ref());
 
         let mut local_result = CouchfileModifyResult::new(req);
 
-        if node_pointer.is_none() || node_buf[0] == NodeType::KVNode as u8 {
+        if node_pointer.is_none() || node_buf.first() == Some(&(NodeType::KVNode as u8)) {
             cursor.set_position(1);
 
             // KV Node
             local_result.node_type = NodeType::KVNode;
 
             while (cursor.position() as usize) < node_buf.len() {
-                let (cmp_key, value) = read_kv(&mut cursor).unwrap();
+                let (cmp_key, value) = read_kv(&mut cursor)?;
 
                 let mut advance = false;
 
@@ -177,71 +249,45 @@ impl TreeFile {
                     advance = true;
                     match cmp_key.cmp(&req.actions[start].key[..]) {
                         Ordering::Less => {
-                            self.maybe_purge_kv(req, cmp_key, value, &mut local_result);
+                            self.maybe_purge_kv(req, cmp_key, value, &mut local_result)?;
                         }
                         Ordering::Greater => {
-                            local_result.modified = true;
-                            self.mr_push_item(
-                                &req.actions[start].key[..],
-                                &req.actions[start].data.as_ref().unwrap()[..],
-                                &mut local_result,
-                            );
-
+                            self.apply_action(&req.actions[start], None, ctx, &mut local_result)?;
                             start += 1;
                             advance = false;
                         }
                         Ordering::Equal => {
-                            local_result.modified = true;
-                            self.mr_push_item(
-                                &req.actions[start].key[..],
-                                &req.actions[start].data.as_ref().unwrap()[..],
+                            self.apply_action(
+                                &req.actions[start],
+                                Some(value),
+                                ctx,
                                 &mut local_result,
-                            );
+                            )?;
                             start += 1;
                         }
                     }
                 }
                 if start == end && !advance {
-                    self.maybe_purge_kv(req, cmp_key, value, &mut local_result)
+                    self.maybe_purge_kv(req, cmp_key, value, &mut local_result)?;
                 }
             }
             while start < end {
-                let action_type = req.actions[start].action_type;
-                if matches!(
-                    action_type,
-                    CouchfileModifyActionType::Fetch | CouchfileModifyActionType::FetchInsert
-                ) {
-                    // not found to fetch callback
-                }
-                match req.actions[start].action_type {
-                    CouchfileModifyActionType::Remove => {
-                        local_result.modified = true;
-                    }
-                    CouchfileModifyActionType::Insert | CouchfileModifyActionType::FetchInsert => {
-                        local_result.modified = true;
-                        self.mr_push_item(
-                            &req.actions[start].key,
-                            req.actions[start].data.as_ref().unwrap(),
-                            &mut local_result,
-                        );
-                    }
-                    _ => {}
-                }
+                self.apply_action(&req.actions[start], None, ctx, &mut local_result)?;
                 start += 1;
             }
-        } else if node_buf[0] == 0 {
+        } else if node_buf.first() == Some(&(NodeType::KPNode as u8)) {
             cursor.set_position(1);
 
             // KP Node
             local_result.node_type = NodeType::KPNode;
             while (cursor.position() as usize) < node_buf.len() && start < end {
-                let (cmp_key, value) = read_kv(&mut cursor).unwrap();
+                let (cmp_key, value) = read_kv(&mut cursor)?;
                 if cursor.position() as usize == node_buf.len() {
                     //We're at the last item in the kpnode, must apply all our
                     //actions here.
-                    let mut desc = NodePointer::read_pointer(cmp_key, value);
+                    let mut desc = NodePointer::read_pointer(cmp_key, value)?;
 
-                    self.modify_node(req, Some(&mut desc), start, end, &mut local_result);
+                    self.modify_node(req, ctx, Some(&mut desc), start, end, &mut local_result)?;
 
                     break;
                 }
@@ -249,9 +295,9 @@ impl TreeFile {
                     Ordering::Less => {
                         //Key in node item less than action item and not at end
                         //position, so just add it and continue.
-                        let add = NodePointer::read_pointer(cmp_key, value);
+                        let add = NodePointer::read_pointer(cmp_key, value)?;
 
-                        self.maybe_purge_kp(req, add, &mut local_result);
+                        self.maybe_purge_kp(req, add, &mut local_result)?;
                     }
                     Ordering::Equal | Ordering::Greater => {
                         let mut range_end = start;
@@ -260,38 +306,79 @@ impl TreeFile {
                             range_end += 1;
                         }
 
-                        let mut desc = NodePointer::read_pointer(cmp_key, value);
+                        let mut desc = NodePointer::read_pointer(cmp_key, value)?;
 
-                        self.modify_node(req, Some(&mut desc), start, range_end, &mut local_result);
+                        self.modify_node(
+                            req,
+                            ctx,
+                            Some(&mut desc),
+                            start,
+                            range_end,
+                            &mut local_result,
+                        )?;
                         start = range_end;
                     }
                 }
             }
             while (cursor.position() as usize) < node_buf.len() {
-                let (cmp_key, value) = read_kv(&mut cursor).unwrap();
-                let add = NodePointer::read_pointer(cmp_key, value);
+                let (cmp_key, value) = read_kv(&mut cursor)?;
+                let add = NodePointer::read_pointer(cmp_key, value)?;
 
-                self.maybe_purge_kp(req, add, &mut local_result);
+                self.maybe_purge_kp(req, add, &mut local_result)?;
             }
         } else {
-            panic!("Invalid node type");
+            return Err(Error::Corrupt("node type"));
         }
 
-        self.flush_mr(&mut local_result);
+        self.flush_mr(&mut local_result)?;
 
         if !local_result.modified && node_pointer.is_some() {
-            self.mr_push_pointerinfo(node_pointer.cloned().unwrap(), dst);
+            self.mr_push_pointerinfo(node_pointer.cloned().unwrap(), dst)
         } else {
             dst.modified = true;
             self.mr_move_pointers(&mut local_result, dst)
         }
     }
 
+    /// Apply a single action to a KV node, given the value currently stored
+    /// for its key (if any).
+    fn apply_action<Ctx: Modifier>(
+        &mut self,
+        action: &CouchfileModifyAction,
+        existing: Option<&[u8]>,
+        ctx: &mut Ctx,
+        result: &mut CouchfileModifyResult<Ctx>,
+    ) -> Result<()> {
+        match action.action_type {
+            CouchfileModifyActionType::Fetch => {
+                ctx.on_fetch(&action.key, existing);
+                if let Some(existing) = existing {
+                    self.mr_push_item(&action.key, existing, result)?;
+                }
+            }
+            CouchfileModifyActionType::Remove => {
+                if existing.is_some() {
+                    result.modified = true;
+                }
+            }
+            CouchfileModifyActionType::Insert => {
+                result.modified = true;
+                self.mr_push_item(&action.key, action.data.as_ref().unwrap(), result)?;
+            }
+            CouchfileModifyActionType::FetchInsert => {
+                ctx.on_fetch(&action.key, existing);
+                result.modified = true;
+                self.mr_push_item(&action.key, action.data.as_ref().unwrap(), result)?;
+            }
+        }
+        Ok(())
+    }
+
     fn mr_push_pointerinfo<Ctx: Debug>(
         &mut self,
         ptr: NodePointer,
         dst: &mut CouchfileModifyResult<Ctx>,
-    ) {
+    ) -> Result<()> {
         let mut data = Vec::new();
         ptr.encode_pointer(&mut data).unwrap();
 
@@ -304,19 +391,20 @@ impl TreeFile {
         dst.node_length += raw_ptr.key.len() + raw_ptr.data.len() + 5;
         dst.values.push_back(raw_ptr);
 
-        self.maybe_flush(dst);
+        self.maybe_flush(dst)
     }
 
     fn mr_move_pointers<Ctx: Debug>(
         &mut self,
         src: &mut CouchfileModifyResult<Ctx>,
         dst: &mut CouchfileModifyResult<Ctx>,
-    ) {
+    ) -> Result<()> {
         while let Some(val) = src.pointers.pop_front() {
             dst.node_length += val.data.len() + val.key.len() + 5;
             dst.values.push_back(val);
-            self.maybe_flush(dst);
+            self.maybe_flush(dst)?;
         }
+        Ok(())
     }
 
     pub fn mr_push_item<Ctx: Debug>(
@@ -324,14 +412,14 @@ impl TreeFile {
         key: &[u8],
         value: &[u8],
         result: &mut CouchfileModifyResult<Ctx>,
-    ) {
+    ) -> Result<()> {
         result.values.push_back(Node {
             data: value.to_vec(),
             key: key.to_vec(),
             pointer: None,
         });
         result.node_length += key.len() + value.len() + 5; // key + value + 48 bit packed key + value length
-        self.maybe_flush(result);
+        self.maybe_flush(result)
     }
 
     pub fn maybe_purge_kv<Ctx: Debug>(
@@ -340,7 +428,7 @@ impl TreeFile {
         key: &[u8],
         value: &[u8],
         result: &mut CouchfileModifyResult<Ctx>,
-    ) {
+    ) -> Result<()> {
         // TODO: Support purging???
 
         self.mr_push_item(key, value, result)
@@ -351,17 +439,32 @@ impl TreeFile {
         _req: &CouchfileModifyRequest<Ctx>,
         node: NodePointer,
         result: &mut CouchfileModifyResult<Ctx>,
-    ) {
+    ) -> Result<()> {
         // TODO: Support purging???
 
-        self.mr_push_pointerinfo(node, result);
+        self.mr_push_pointerinfo(node, result)
     }
 }
 
 impl TreeFile {
-    pub fn maybe_flush<Ctx: Debug>(&mut self, result: &mut CouchfileModifyResult<Ctx>) {
+    pub fn maybe_flush<Ctx: Debug>(
+        &mut self,
+        result: &mut CouchfileModifyResult<Ctx>,
+    ) -> Result<()> {
         if result.compacting {
-            todo!()
+            // The compactor writes nodes out as soon as they are full so it
+            // doesn't have to hold the whole tree in memory. Items arrive in
+            // key order, so nodes are written in the same order as the tree.
+            let threshold = match result.node_type {
+                NodeType::KVNode => result.req.kv_chunk_threshold,
+                NodeType::KPNode => result.req.kp_chunk_threshold,
+            };
+            if result.modified
+                && result.node_length > threshold * 2 / 3
+                && (result.node_type == NodeType::KVNode || result.values.len() > 1)
+            {
+                self.flush_mr(result)?;
+            }
         } else if result.modified && result.values.len() > 3 {
             let threshold = match result.node_type {
                 NodeType::KVNode => result.req.kv_chunk_threshold,
@@ -369,14 +472,15 @@ impl TreeFile {
             };
             if result.node_length > threshold {
                 let quota = threshold * 2 / 3;
-                self.flush_mr_partial(result, quota);
+                self.flush_mr_partial(result, quota)?;
             }
         }
+        Ok(())
     }
 
     /// Write the current contents of the values list to disk as a node
     /// and add the resulting pointer to the pointers list.
-    pub fn flush_mr<Ctx: Debug>(&mut self, result: &mut CouchfileModifyResult<Ctx>) {
+    pub fn flush_mr<Ctx: Debug>(&mut self, result: &mut CouchfileModifyResult<Ctx>) -> Result<()> {
         self.flush_mr_partial(result, result.node_length)
     }
 
@@ -386,9 +490,9 @@ impl TreeFile {
         &mut self,
         result: &mut CouchfileModifyResult<Ctx>,
         mr_quota: usize,
-    ) {
+    ) -> Result<()> {
         if result.values.is_empty() || !result.modified {
-            return;
+            return Ok(());
         }
 
         let mut nodebuf = Vec::with_capacity(result.node_length + 1);
@@ -400,6 +504,7 @@ impl TreeFile {
         let mut disksize = 0;
         let mut item_count = 0;
         let mut final_key = Vec::new();
+        let mut items = Vec::new();
 
         let mut mr_quota = mr_quota as isize;
 
@@ -417,15 +522,24 @@ impl TreeFile {
             mr_quota -= (value.key.len() + value.data.len() + 5) as isize;
             final_key = value.key.clone();
             item_count += 1;
+            items.push(value);
         }
 
-        self.db_write_buf_compressed(&nodebuf, &mut diskpos, &mut disksize);
+        self.db_write_buf_compressed(&nodebuf, &mut diskpos, &mut disksize)?;
+
+        let reduce = match result.node_type {
+            NodeType::KVNode => result.req.reduce,
+            NodeType::KPNode => result.req.rereduce,
+        };
 
         let ptr = NodePointer {
             pointer: diskpos,
             subtree_size: u64::from(disksize) + subtreesize,
             key: Some(final_key.clone()),
-            reduce_value: vec![],
+            reduce_value: match reduce {
+                Some(reduce) => reduce(&items)?,
+                None => Vec::new(),
+            },
         };
 
         let mut data = Vec::new();
@@ -440,5 +554,7 @@ impl TreeFile {
 
         result.node_length -= nodebuf.len() - 1;
         result.pointers.push_back(raw_ptr);
+
+        Ok(())
     }
 }
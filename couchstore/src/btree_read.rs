@@ -1,4 +1,7 @@
-use crate::{btree::CouchfileLookupRequest, node_types::read_kv, Db};
+use crate::{
+    btree::CouchfileLookupRequest, error::corrupt, node_types::read_kv, Db, Error, NodePointer,
+    Result,
+};
 use byteorder::ReadBytesExt;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use std::{cmp::Ordering, io::Cursor};
@@ -20,23 +23,24 @@ impl Db {
         diskpos: usize,
         mut current: usize,
         end: usize,
-    ) where
-        F: FnMut(&mut Self, &[u8], Option<&[u8]>),
+    ) -> Result<()>
+    where
+        F: FnMut(&mut Self, &[u8], Option<&[u8]>) -> Result<()>,
     {
         if current == end {
-            return;
+            return Ok(());
         }
 
-        let node = self.file.read_compressed(diskpos);
+        let node = self.file.read_compressed(diskpos)?;
 
         let mut cursor = Cursor::new(node.as_ref());
 
-        let node_type = NodeType::try_from_primitive(cursor.read_u8().unwrap()).unwrap();
+        let node_type = read_node_type(&mut cursor)?;
 
         match node_type {
             NodeType::KPNode => {
                 while (cursor.position() as usize) < node.len() && current < end {
-                    let (cmp_key, value) = read_kv(&mut cursor).unwrap();
+                    let (cmp_key, value) = read_kv(&mut cursor)?;
 
                     let key = &req.keys[current][..];
 
@@ -62,11 +66,14 @@ impl Db {
                         }
                     }
 
-                    let pointer = (&value[..]).read_u48::<byteorder::BigEndian>().unwrap() as usize;
+                    let pointer = (&value[..])
+                        .read_u48::<byteorder::BigEndian>()
+                        .map_err(corrupt("node pointer"))?
+                        as usize;
 
                     // In interior nodes the Value parts of these pairs are pointers to another
                     // B-tree node, where keys less than or equal to that pair's Key will be.
-                    self.btree_lookup_inner(req, on_fetch, pointer, current, last_item);
+                    self.btree_lookup_inner(req, on_fetch, pointer, current, last_item)?;
 
                     if !req.in_fold {
                         current = last_item;
@@ -82,7 +89,7 @@ impl Db {
                     // Only try and read the next-key if requested and we're still in
                     // the node length
                     if next_key && (cursor.position() as usize) < node.len() {
-                        (cmp_key, value) = read_kv(&mut cursor).unwrap();
+                        (cmp_key, value) = read_kv(&mut cursor)?;
                     } else if next_key {
                         // else if next_key is true and we're out of buf space, break
                         break;
@@ -117,9 +124,9 @@ impl Db {
                     }
 
                     if cmp_val == Ordering::Equal || req.in_fold {
-                        on_fetch(self, cmp_key, Some(value));
+                        on_fetch(self, cmp_key, Some(value))?;
                     } else {
-                        on_fetch(self, key, None);
+                        on_fetch(self, key, None)?;
                     }
 
                     if !req.in_fold {
@@ -133,9 +140,11 @@ impl Db {
         }
 
         while current < end {
-            on_fetch(self, &req.keys[current], None);
+            on_fetch(self, &req.keys[current], None)?;
             current += 1;
         }
+
+        Ok(())
     }
 
     pub fn btree_lookup<F>(
@@ -143,10 +152,83 @@ impl Db {
         req: &mut CouchfileLookupRequest,
         mut on_fetch: F,
         root_pointer: usize,
-    ) where
-        F: Sized + FnMut(&mut Self, &[u8], Option<&[u8]>),
+    ) -> Result<()>
+    where
+        F: Sized + FnMut(&mut Self, &[u8], Option<&[u8]>) -> Result<()>,
     {
         req.in_fold = false;
-        self.btree_lookup_inner(req, &mut on_fetch, root_pointer, 0, req.keys.len());
+        self.btree_lookup_inner(req, &mut on_fetch, root_pointer, 0, req.keys.len())
+    }
+
+    /// Count the items in the tree whose keys fall in `[start, end]`. Subtrees
+    /// that lie entirely inside the range are counted using `reduce_count` on
+    /// their reduce value rather than being read.
+    pub(crate) fn btree_count_range(
+        &mut self,
+        diskpos: usize,
+        start: &[u8],
+        end: &[u8],
+        reduce_count: fn(&[u8]) -> Result<u64>,
+    ) -> Result<u64> {
+        let node = self.file.read_compressed(diskpos)?;
+
+        let mut cursor = Cursor::new(node.as_ref());
+
+        let node_type = read_node_type(&mut cursor)?;
+
+        let mut count = 0;
+
+        match node_type {
+            NodeType::KPNode => {
+                // Each pointer covers the keys after the previous pointer's
+                // key, up to and including its own key.
+                let mut prev_key: Option<&[u8]> = None;
+
+                while (cursor.position() as usize) < node.len() {
+                    let (key, value) = read_kv(&mut cursor)?;
+
+                    if prev_key.is_some_and(|prev_key| prev_key >= end) {
+                        break;
+                    }
+
+                    if key >= start {
+                        let pointer = NodePointer::read_pointer(key, value)?;
+
+                        if prev_key.is_some_and(|prev_key| prev_key >= start) && key <= end {
+                            count += reduce_count(&pointer.reduce_value)?;
+                        } else {
+                            count += self.btree_count_range(
+                                pointer.pointer as usize,
+                                start,
+                                end,
+                                reduce_count,
+                            )?;
+                        }
+                    }
+
+                    prev_key = Some(key);
+                }
+            }
+            NodeType::KVNode => {
+                while (cursor.position() as usize) < node.len() {
+                    let (key, _) = read_kv(&mut cursor)?;
+
+                    if key > end {
+                        break;
+                    }
+
+                    if key >= start {
+                        count += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(count)
     }
 }
+
+pub(crate) fn read_node_type(cursor: &mut Cursor<&[u8]>) -> Result<NodeType> {
+    NodeType::try_from_primitive(cursor.read_u8().map_err(corrupt("node"))?)
+        .map_err(|_| Error::Corrupt("node type"))
+}
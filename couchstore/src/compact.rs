@@ -0,0 +1,184 @@
+use std::path::Path;
+
+use crate::{
+    btree::CouchfileLookupRequest, btree_modify::CouchfileModifyRequest, reduces, DBOpenOptions,
+    Db, DocInfo, Result, TreeFile,
+};
+
+/// What a compaction hook wants done with an item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactDecision {
+    /// Copy the item into the compacted file
+    Keep,
+    /// Leave the item out of the compacted file
+    Drop,
+}
+
+pub type CompactHook<'a> = Box<dyn FnMut(&DocInfo) -> CompactDecision + 'a>;
+
+/// Options for [Db::compact]
+#[derive(Default)]
+pub struct CompactOptions<'a> {
+    /// Tombstones with a seqno at or below this are purged
+    purge_before_seq: u64,
+
+    /// Called for every item that would otherwise be kept
+    hook: Option<CompactHook<'a>>,
+}
+
+impl<'a> CompactOptions<'a> {
+    /// Purge deleted documents with a seqno at or below `seq`. The purge seqno
+    /// of the compacted file is advanced to the highest seqno purged.
+    pub fn purge_before_seq(mut self, seq: u64) -> Self {
+        self.purge_before_seq = seq;
+        self
+    }
+
+    /// Ask `hook` whether to keep each item, e.g. to drop expired documents.
+    pub fn hook(mut self, hook: impl FnMut(&DocInfo) -> CompactDecision + 'a) -> Self {
+        self.hook = Some(Box::new(hook));
+        self
+    }
+}
+
+impl Db {
+    /// Write a compacted copy of the database to `target`, which is created
+    /// (or truncated) first.
+    ///
+    /// Only the current revision of each document is copied, along with the
+    /// local documents. Document bodies are copied as stored, without being
+    /// decompressed. Returns the compacted database, which has already been
+    /// committed.
+    pub fn compact(&mut self, target: impl AsRef<Path>, mut options: CompactOptions) -> Result<Db> {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(target.as_ref())?;
+
+        let target_opts = DBOpenOptions {
+            create: true,
+            read_only: false,
+            ..self.opts
+        };
+        let mut target = Db::open(target, target_opts)?;
+
+        let mut purge_seq = self.header.purge_seq;
+        let mut purged = false;
+        let mut id_index = Vec::new();
+
+        let seq_req = target.new_btree_request(reduces::by_seq_reduce, reduces::by_seq_rereduce);
+        let mut seq_result = TreeFile::start_new_btree(&seq_req);
+
+        self.changes_since(0, |db, mut info| {
+            if info.deleted && info.db_seq <= options.purge_before_seq {
+                purge_seq = purge_seq.max(info.db_seq);
+                purged = true;
+                return Ok(());
+            }
+
+            if let Some(hook) = &mut options.hook {
+                if hook(&info) == CompactDecision::Drop {
+                    return Ok(());
+                }
+            }
+
+            if info.bp != 0 {
+                let body = db.file.read_uncompressed(info.bp as usize)?;
+                target
+                    .file
+                    .db_write_buf(&body, &mut info.bp, &mut info.physical_size)?;
+            }
+
+            let mut seq_value = Vec::new();
+            info.encode_seq_index_value(&mut seq_value);
+            target.file.mr_push_item(
+                &info.db_seq.to_be_bytes()[2..],
+                &seq_value,
+                &mut seq_result,
+            )?;
+
+            let mut id_value = Vec::new();
+            info.encode_id_index_value(&mut id_value);
+            id_index.push((info.id, id_value));
+
+            Ok(())
+        })?;
+
+        target.header.by_seq_root = target.file.complete_new_btree(&seq_req, seq_result)?;
+
+        id_index.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+        let id_req = target.new_btree_request(reduces::by_id_reduce, reduces::by_id_rereduce);
+        let mut id_result = TreeFile::start_new_btree(&id_req);
+        for (key, value) in id_index {
+            target.file.mr_push_item(&key, &value, &mut id_result)?;
+        }
+        target.header.by_id_root = target.file.complete_new_btree(&id_req, id_result)?;
+
+        self.compact_local_docs(&mut target)?;
+
+        target.header.update_seq = self.header.update_seq;
+        target.header.purge_seq = purge_seq;
+        target.header.purge_ptr = if purged {
+            target.file.pos as u64
+        } else {
+            self.header.purge_ptr.min(target.file.pos as u64)
+        };
+
+        target.commit()?;
+
+        Ok(target)
+    }
+
+    /// Local documents are already read in key order, so they can be copied
+    /// straight into a new tree.
+    fn compact_local_docs(&mut self, target: &mut Db) -> Result<()> {
+        let root = match &self.header.local_docs_root {
+            Some(root) => root.pointer as usize,
+            None => return Ok(()),
+        };
+
+        let local_req = CouchfileModifyRequest {
+            actions: vec![],
+            context: (),
+            kv_chunk_threshold: target.opts.kv_chunk_threshold,
+            kp_chunk_threshold: target.opts.kp_chunk_threshold,
+            reduce: None,
+            rereduce: None,
+        };
+        let mut local_result = TreeFile::start_new_btree(&local_req);
+
+        let mut req = CouchfileLookupRequest::new(vec![vec![]]).fold();
+
+        self.btree_lookup(
+            &mut req,
+            |_, key, value| {
+                if let Some(value) = value {
+                    target.file.mr_push_item(key, value, &mut local_result)?;
+                }
+                Ok(())
+            },
+            root,
+        )?;
+
+        target.header.local_docs_root = target.file.complete_new_btree(&local_req, local_result)?;
+
+        Ok(())
+    }
+
+    fn new_btree_request(
+        &self,
+        reduce: reduces::ReduceFn,
+        rereduce: reduces::ReduceFn,
+    ) -> CouchfileModifyRequest<()> {
+        CouchfileModifyRequest {
+            actions: vec![],
+            context: (),
+            kv_chunk_threshold: self.opts.kv_chunk_threshold,
+            kp_chunk_threshold: self.opts.kp_chunk_threshold,
+            reduce: Some(reduce),
+            rereduce: Some(rereduce),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use crate::DiskVersion;
+
+/// The checksum algorithm used for the chunks of a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum CrcMode {
+    /// Used by files before [DiskVersion::Twelve]
+    Crc32,
+    #[default]
+    Crc32c,
+}
+
+impl CrcMode {
+    pub(crate) fn checksum(self, buf: &[u8]) -> u32 {
+        match self {
+            CrcMode::Crc32 => crc32fast::hash(buf),
+            CrcMode::Crc32c => crc32c::crc32c(buf),
+        }
+    }
+}
+
+impl DiskVersion {
+    pub(crate) fn crc_mode(self) -> CrcMode {
+        match self {
+            DiskVersion::Eleven => CrcMode::Crc32,
+            DiskVersion::Twelve | DiskVersion::Thirteen => CrcMode::Crc32c,
+        }
+    }
+
+    /// Whether headers of this version end with the commit timestamp
+    pub(crate) fn has_timestamp(self) -> bool {
+        self == DiskVersion::Thirteen
+    }
+}
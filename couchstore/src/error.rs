@@ -0,0 +1,50 @@
+use std::io;
+
+use thiserror::Error;
+
+/// Errors returned by couchstore
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The file has no valid header, so isn't a couchstore file or every
+    /// commit in it is corrupt
+    #[error("no valid header found")]
+    NoHeader,
+
+    /// A chunk failed its checksum
+    #[error("checksum mismatch at offset {offset}")]
+    Checksum { offset: u64 },
+
+    /// Data read from the file doesn't have the expected structure
+    #[error("corrupt {0}")]
+    Corrupt(&'static str),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// The requested document doesn't exist
+    #[error("document not found")]
+    DocNotFound,
+
+    /// A write was attempted on a database opened read only
+    #[error("database is read only")]
+    ReadOnly,
+}
+
+impl Error {
+    /// Does the error indicate that the file itself is damaged, as opposed
+    /// to a failed operation that can be retried or a missing document
+    pub fn is_corruption(&self) -> bool {
+        matches!(
+            self,
+            Error::NoHeader | Error::Checksum { .. } | Error::Corrupt(_)
+        )
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Decoding from an in-memory buffer only fails if the buffer is too short,
+/// which means the data on disk is corrupt rather than an I/O error.
+pub(crate) fn corrupt(what: &'static str) -> impl Fn(io::Error) -> Error {
+    move |_| Error::Corrupt(what)
+}
@@ -1,79 +1,205 @@
 use byteorder::{BigEndian, ReadBytesExt};
-use crc32c::crc32c;
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::{fs::File, io::Cursor, os::unix::fs::FileExt};
 
-use crate::{constants::COUCH_BLOCK_SIZE, TreeFile};
+use crate::{
+    constants::COUCH_BLOCK_SIZE, crc::CrcMode, ContentMetaFlag, DiskVersion, Doc, DocInfo, Error,
+    OpenOptions, Result, TreeFile,
+};
 
 impl TreeFile {
-    pub fn read_compressed(&mut self, pos: usize) -> Vec<u8> {
-        let compressed_buf = self.read(pos, None);
+    pub fn read_compressed(&self, pos: usize) -> Result<Vec<u8>> {
+        read_compressed(&self.file, pos, self.crc_mode)
+    }
 
-        // Couchstore does not use the frame format so we need the raw decoder.
-        snap::raw::Decoder::new()
-            .decompress_vec(&compressed_buf)
-            .unwrap()
+    pub fn read_uncompressed(&self, pos: usize) -> Result<Vec<u8>> {
+        read_chunk(&self.file, pos, self.crc_mode)
     }
 
-    pub fn read_uncompressed(&mut self, pos: usize) -> Vec<u8> {
-        self.read(pos, None)
+    /// Read the header at the start of the block at `pos`
+    pub fn read_header(&self, pos: usize, max_header_size: usize) -> Result<Vec<u8>> {
+        read_header(&self.file, pos, max_header_size)
     }
+}
 
-    fn read(&mut self, mut pos: usize, max_header_size: Option<usize>) -> Vec<u8> {
-        let mut info = [0u8; 8];
+// The reads below use positioned reads rather than seeking, so they only need
+// a shared reference to the file and can run concurrently from many threads.
+// Every chunk read has its checksum verified.
 
-        self.read_skipping_prefixes(&mut pos, &mut info);
+pub(crate) fn read_compressed(file: &File, pos: usize, crc_mode: CrcMode) -> Result<Vec<u8>> {
+    let compressed_buf = read_chunk(file, pos, crc_mode)?;
 
-        let mut cursor = Cursor::new(&info);
-        // something is stored in the highest bit of the first byte
-        let mut chunk_len = cursor.read_u32::<BigEndian>().unwrap() & !0x80000000;
-        let crc32 = cursor.read_u32::<BigEndian>().unwrap();
+    // Couchstore does not use the frame format so we need the raw decoder.
+    snap::raw::Decoder::new()
+        .decompress_vec(&compressed_buf)
+        .map_err(|_| Error::Corrupt("snappy compressed chunk"))
+}
 
-        if let Some(max_header_size) = max_header_size {
-            assert!(chunk_len as usize <= max_header_size);
-            chunk_len -= 4; // Header len includes CRC len.
-        }
+/// Read the body of the document described by `docinfo`
+pub(crate) fn read_doc(
+    file: &File,
+    docinfo: &DocInfo,
+    mut options: OpenOptions,
+    crc_mode: CrcMode,
+) -> Result<Doc> {
+    if docinfo.bp == 0 {
+        return Err(Error::DocNotFound);
+    }
 
-        // TODO: Reuse buffer
-        let mut buf = vec![0u8; chunk_len as usize];
+    let bp = docinfo.bp as usize;
 
-        self.read_skipping_prefixes(&mut pos, &mut buf);
+    if !docinfo
+        .content_meta
+        .contains(ContentMetaFlag::IS_COMPRESSED)
+    {
+        options.remove(OpenOptions::DECOMPRESS_DOC_BODIES);
+    }
+
+    let docbody = if options.contains(OpenOptions::DECOMPRESS_DOC_BODIES) {
+        read_compressed(file, bp, crc_mode)?
+    } else {
+        read_chunk(file, bp, crc_mode)?
+    };
 
-        let crc32_calc = crc32c(&buf);
+    if docbody.is_empty() {
+        return Err(Error::DocNotFound);
+    }
 
-        assert_eq!(crc32, crc32_calc);
+    let doc = Doc {
+        id: docinfo.id.clone(),
+        data: docbody,
+    };
+
+    Ok(doc)
+}
 
-        buf
+/// Read the header at the start of the block at `pos`. The checksum used
+/// depends on the disk version in the header's first byte.
+pub(crate) fn read_header(file: &File, pos: usize, max_header_size: usize) -> Result<Vec<u8>> {
+    let (buf, crc32) = read_chunk_unchecked(file, pos + 1, Some(max_header_size))?;
+
+    let version = buf
+        .first()
+        .and_then(|&version| DiskVersion::try_from(version).ok())
+        .ok_or(Error::Corrupt("header disk version"))?;
+
+    if version.crc_mode().checksum(&buf) != crc32 {
+        return Err(Error::Checksum { offset: pos as u64 });
     }
 
-    pub fn read_header(&mut self, pos: usize, max_header_size: usize) -> Vec<u8> {
-        self.read(pos + 1, Some(max_header_size))
+    Ok(buf)
+}
+
+/// Read the chunk at `pos`, checking its checksum
+pub(crate) fn read_chunk(file: &File, pos: usize, crc_mode: CrcMode) -> Result<Vec<u8>> {
+    let (buf, crc32) = read_chunk_unchecked(file, pos, None)?;
+
+    if crc_mode.checksum(&buf) != crc32 {
+        return Err(Error::Checksum { offset: pos as u64 });
     }
 
-    pub fn read_skipping_prefixes(&mut self, pos: &mut usize, mut buf: &mut [u8]) {
-        if *pos % COUCH_BLOCK_SIZE == 0 {
-            *pos += 1;
+    Ok(buf)
+}
+
+/// Read the chunk at `pos` and the checksum stored with it
+fn read_chunk_unchecked(
+    file: &File,
+    mut pos: usize,
+    max_header_size: Option<usize>,
+) -> Result<(Vec<u8>, u32)> {
+    let mut info = [0u8; 8];
+
+    read_skipping_prefixes(file, &mut pos, &mut info)?;
+
+    let mut cursor = Cursor::new(&info);
+    // something is stored in the highest bit of the first byte
+    let mut chunk_len = cursor.read_u32::<BigEndian>()? & !0x80000000;
+    let crc32 = cursor.read_u32::<BigEndian>()?;
+
+    if let Some(max_header_size) = max_header_size {
+        if chunk_len < 4 || chunk_len as usize > max_header_size {
+            return Err(Error::Corrupt("header length"));
         }
+        chunk_len -= 4; // Header len includes CRC len.
+    }
+
+    // A corrupt length mustn't cause a huge allocation, and the chunk can't
+    // be longer than the rest of the file
+    if pos as u64 + chunk_len as u64 > file.metadata()?.len() {
+        return Err(Error::Corrupt("chunk extends past end of file"));
+    }
+
+    // TODO: Reuse buffer
+    let mut buf = vec![0u8; chunk_len as usize];
+
+    read_skipping_prefixes(file, &mut pos, &mut buf)?;
+
+    Ok((buf, crc32))
+}
 
-        while !buf.is_empty() {
-            let mut read_size = COUCH_BLOCK_SIZE - (*pos % COUCH_BLOCK_SIZE);
-            if read_size > buf.len() {
-                read_size = buf.len();
-            }
+/// Fill `buf` from `pos`, skipping the block prefix bytes. Reaching the end
+/// of the file first means the chunk being read is truncated.
+pub(crate) fn read_skipping_prefixes(
+    file: &File,
+    pos: &mut usize,
+    mut buf: &mut [u8],
+) -> Result<()> {
+    if (*pos).is_multiple_of(COUCH_BLOCK_SIZE) {
+        *pos += 1;
+    }
 
-            self.file.seek(SeekFrom::Start(*pos as u64)).unwrap();
-            let got_bytes = self.file.read(&mut buf[..read_size]).unwrap();
+    while !buf.is_empty() {
+        let mut read_size = COUCH_BLOCK_SIZE - (*pos % COUCH_BLOCK_SIZE);
+        if read_size > buf.len() {
+            read_size = buf.len();
+        }
 
-            if got_bytes == 0 {
-                break;
-            }
+        let got_bytes = file.read_at(&mut buf[..read_size], *pos as u64)?;
 
-            *pos += got_bytes;
+        if got_bytes == 0 {
+            return Err(Error::Corrupt("chunk extends past end of file"));
+        }
 
-            buf = &mut buf[got_bytes..];
+        *pos += got_bytes;
 
-            if *pos % COUCH_BLOCK_SIZE == 0 {
-                *pos += 1;
-            }
+        buf = &mut buf[got_bytes..];
+
+        if (*pos).is_multiple_of(COUCH_BLOCK_SIZE) {
+            *pos += 1;
         }
     }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::temp_db_path;
+
+    #[test]
+    fn test_chunk_past_end_of_file() {
+        let path = temp_db_path("chunk-past-end");
+
+        // A chunk claiming to be 2 GiB long, in a file of one block
+        let mut block = vec![0u8; COUCH_BLOCK_SIZE];
+        block[1..5].copy_from_slice(&0x7fff_ffffu32.to_be_bytes());
+        std::fs::write(&path, &block).unwrap();
+
+        let file = File::open(&path).unwrap();
+        assert!(matches!(
+            read_chunk(&file, 1, CrcMode::Crc32c),
+            Err(Error::Corrupt("chunk extends past end of file"))
+        ));
+
+        // Truncated after its length and checksum
+        block[1..5].copy_from_slice(&16u32.to_be_bytes());
+        std::fs::write(&path, &block[..12]).unwrap();
+        let file = File::open(&path).unwrap();
+        assert!(matches!(
+            read_chunk(&file, 1, CrcMode::Crc32c),
+            Err(Error::Corrupt("chunk extends past end of file"))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -1,13 +1,35 @@
 use byteorder::{BigEndian, WriteBytesExt};
 use std::io::{Cursor, Seek, SeekFrom, Write};
 
-use crate::{constants::COUCH_BLOCK_SIZE, utils::align_to_next_block, DiskBlockType, TreeFile};
+use crate::{
+    constants::COUCH_BLOCK_SIZE, utils::align_to_next_block, CommitPolicy, DiskBlockType, Result,
+    TreeFile,
+};
 
 impl TreeFile {
-    pub fn write_entire_buffer(&mut self, buf: &[u8], offset: usize) {
-        self.file.seek(SeekFrom::Start(offset as u64)).unwrap();
-        self.file.write_all(buf).unwrap();
-        self.file.flush().unwrap();
+    pub fn write_entire_buffer(&mut self, buf: &[u8], offset: usize) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.write_all(buf)?;
+
+        self.unsynced_bytes += buf.len() as u64;
+        if let Some(limit) = self.options.sync_every_n_bytes {
+            if self.unsynced_bytes >= limit {
+                self.sync()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sync the file to disk as the commit policy says
+    pub fn sync(&mut self) -> Result<()> {
+        match self.options.commit_policy {
+            CommitPolicy::None => return Ok(()),
+            CommitPolicy::DataSync => self.file.sync_data()?,
+            CommitPolicy::FullSync => self.file.sync_all()?,
+        }
+        self.unsynced_bytes = 0;
+        Ok(())
     }
 
     pub fn raw_write(
@@ -15,7 +37,7 @@ impl TreeFile {
         disk_block_type: DiskBlockType,
         mut buf: &[u8],
         pos: usize,
-    ) -> usize {
+    ) -> Result<usize> {
         let mut write_pos = pos;
         let mut block_remain;
         // break up the write buffer into blocks adding the block prefix as needed
@@ -25,25 +47,25 @@ impl TreeFile {
                 block_remain = buf.len();
             }
 
-            if write_pos % COUCH_BLOCK_SIZE == 0 {
-                self.write_entire_buffer(&[disk_block_type.into()], write_pos);
+            if write_pos.is_multiple_of(COUCH_BLOCK_SIZE) {
+                self.write_entire_buffer(&[disk_block_type.into()], write_pos)?;
                 write_pos += 1;
                 continue;
             }
 
-            self.write_entire_buffer(&buf[..block_remain], write_pos);
+            self.write_entire_buffer(&buf[..block_remain], write_pos)?;
             write_pos += block_remain;
             buf = &buf[block_remain..];
         }
 
-        write_pos - pos
+        Ok(write_pos - pos)
     }
 
-    pub fn write_header(&mut self, buf: &[u8]) -> usize {
+    pub fn write_header(&mut self, buf: &[u8]) -> Result<usize> {
         let mut write_pos = align_to_next_block(self.pos);
 
         let size = (buf.len() + 4) as u32; // Len before header includes hash len.
-        let crc32 = crc32c::crc32c(buf);
+        let crc32 = self.crc_mode.checksum(buf);
 
         let mut header_buf = [0u8; 9];
         let mut cursor = Cursor::new(&mut header_buf[..]);
@@ -51,42 +73,42 @@ impl TreeFile {
         let pos = write_pos;
 
         // Write the header's block header
-        cursor.write_u8(DiskBlockType::Header.into()).unwrap();
-        cursor.write_u32::<BigEndian>(size).unwrap();
-        cursor.write_u32::<BigEndian>(crc32).unwrap();
+        cursor.write_u8(DiskBlockType::Header.into())?;
+        cursor.write_u32::<BigEndian>(size)?;
+        cursor.write_u32::<BigEndian>(crc32)?;
 
-        self.write_entire_buffer(&header_buf, write_pos);
+        self.write_entire_buffer(&header_buf, write_pos)?;
 
         write_pos += header_buf.len();
 
         // Write actual header
-        self.raw_write(DiskBlockType::Header, buf, write_pos);
+        self.raw_write(DiskBlockType::Header, buf, write_pos)?;
         write_pos += buf.len();
         self.pos = write_pos;
 
-        pos
+        Ok(pos)
     }
 
-    pub fn db_write_buf(&mut self, buf: &[u8], pos: &mut u64, disk_size: &mut u32) {
+    pub fn db_write_buf(&mut self, buf: &[u8], pos: &mut u64, disk_size: &mut u32) -> Result<()> {
         let write_pos = self.pos;
         let mut end_pos = write_pos;
         let mut written;
 
         let size = buf.len() | 0x8000_0000;
-        let crc32 = crc32c::crc32c(buf);
+        let crc32 = self.crc_mode.checksum(buf);
 
         let mut header_buf = [0u8; 8];
         let mut cursor = Cursor::new(&mut header_buf[..]);
 
         // Write the header's block header
-        cursor.write_u32::<BigEndian>(size as u32).unwrap();
-        cursor.write_u32::<BigEndian>(crc32).unwrap();
+        cursor.write_u32::<BigEndian>(size as u32)?;
+        cursor.write_u32::<BigEndian>(crc32)?;
 
-        written = self.raw_write(DiskBlockType::Data, &header_buf, end_pos);
+        written = self.raw_write(DiskBlockType::Data, &header_buf, end_pos)?;
         end_pos += written;
 
         // Write actual buffer
-        written = self.raw_write(DiskBlockType::Data, buf, end_pos);
+        written = self.raw_write(DiskBlockType::Data, buf, end_pos)?;
         end_pos += written;
 
         *pos = write_pos as u64;
@@ -94,11 +116,20 @@ impl TreeFile {
         self.pos = end_pos;
 
         *disk_size = (header_buf.len() + buf.len()) as u32;
+
+        Ok(())
     }
 
-    pub fn db_write_buf_compressed(&mut self, buf: &[u8], pos: &mut u64, disk_size: &mut u32) {
+    pub fn db_write_buf_compressed(
+        &mut self,
+        buf: &[u8],
+        pos: &mut u64,
+        disk_size: &mut u32,
+    ) -> Result<()> {
         let mut encoder = snap::raw::Encoder::new();
-        let compressed_buf = encoder.compress_vec(buf).unwrap();
+        let compressed_buf = encoder
+            .compress_vec(buf)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
         self.db_write_buf(&compressed_buf, pos, disk_size)
     }
 }
@@ -1,27 +1,44 @@
 use std::{
     cmp::Ordering,
     fs::File,
-    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    os::unix::fs::FileExt,
     path::Path,
 };
 mod btree;
 mod btree_modify;
 mod btree_read;
+mod compact;
 mod constants;
+mod crc;
+mod error;
 mod file_read;
 mod file_write;
 mod node_types;
+mod range;
+mod reader;
+mod reduces;
 mod save;
+mod scrub;
 mod utils;
 
+pub use compact::{CompactDecision, CompactHook, CompactOptions};
+pub use error::{Error, Result};
+pub use range::RangeIter;
+pub use reader::Reader;
+pub use scrub::ScrubReport;
+
 use btree_modify::{CouchfileModifyAction, CouchfileModifyActionType, CouchfileModifyRequest};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use constants::COUCH_BLOCK_SIZE;
-use node_types::{decode_kv_length, RawFileHeaderV13};
+use node_types::{decode_kv_length, RawFileHeader};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use reduces::ByIdReduce;
 use utils::align_to_next_block;
 
-use crate::{btree::CouchfileLookupRequest, constants::MAX_DB_HEADER_SIZE};
+use crate::{
+    btree::CouchfileLookupRequest, constants::MAX_DB_HEADER_SIZE, crc::CrcMode, error::corrupt,
+};
 
 #[derive(Debug, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq)]
 #[repr(u8)]
@@ -35,6 +52,8 @@ pub struct Db {
     file: TreeFile,
     header: Header,
     opts: DBOpenOptions,
+    /// Bytes after the last valid header that were ignored on open
+    ignored_bytes: u64,
 }
 
 pub struct TreeFileOptions {}
@@ -50,6 +69,8 @@ pub struct Header {
     purge_ptr: u64,
     position: u64,
     timestamp: u64,
+    /// Offset of the first byte after the header
+    end_position: u64,
 }
 
 impl Header {
@@ -60,6 +81,153 @@ impl Header {
     }
 }
 
+impl Header {
+    pub fn disk_version(&self) -> DiskVersion {
+        self.disk_version
+    }
+
+    /// Offset of the block the header was read from
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// When the header was committed, in nanoseconds since the Unix epoch
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    fn by_id_reduce(&self) -> Result<ByIdReduce> {
+        match &self.by_id_root {
+            Some(root) if !root.reduce_value.is_empty() => ByIdReduce::decode(&root.reduce_value),
+            _ => Ok(ByIdReduce::default()),
+        }
+    }
+
+    /// Find the most recent valid header, searching backwards from the block
+    /// containing `start_pos`. Anything after it is the remains of a commit
+    /// that never completed (e.g. the process was killed mid-flush), and is
+    /// ignored.
+    fn find(file: &File, start_pos: usize) -> Result<Header> {
+        let mut pos = start_pos - start_pos % COUCH_BLOCK_SIZE;
+
+        loop {
+            if let Some(header) = Header::find_at_pos(file, pos)? {
+                return Ok(header);
+            }
+
+            if pos == 0 {
+                return Err(Error::NoHeader);
+            }
+
+            pos -= COUCH_BLOCK_SIZE;
+        }
+    }
+
+    /// The header at `pos`, which must be the start of a valid header block
+    fn at_pos(file: &File, pos: u64) -> Result<Header> {
+        if !(pos as usize).is_multiple_of(COUCH_BLOCK_SIZE) || pos >= file.metadata()?.len() {
+            return Err(Error::NoHeader);
+        }
+
+        Header::find_at_pos(file, pos as usize)?.ok_or(Error::NoHeader)
+    }
+
+    /// Find the valid header that was committed before `self`, if any
+    fn find_previous(&self, file: &File) -> Result<Option<Header>> {
+        let mut pos = self.position as usize;
+
+        while pos > 0 {
+            pos -= COUCH_BLOCK_SIZE;
+
+            if let Some(header) = Header::find_at_pos(file, pos)? {
+                return Ok(Some(header));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Every valid header from `self` back to the start of the file, newest
+    /// first
+    fn history(&self, file: &File) -> Result<Vec<Header>> {
+        let mut headers = vec![self.clone()];
+
+        while let Some(header) = headers.last().unwrap().find_previous(file)? {
+            headers.push(header);
+        }
+
+        Ok(headers)
+    }
+
+    /// Read and validate the header at `pos`, returning `None` if the block
+    /// isn't a header or the header is corrupt. Only I/O errors are returned
+    /// as errors, as a corrupt header just means the search carries on.
+    fn find_at_pos(file: &File, pos: usize) -> Result<Option<Header>> {
+        match Header::read_at_pos(file, pos) {
+            Ok(header) => Ok(header),
+            Err(Error::Io(err)) => Err(Error::Io(err)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn read_at_pos(file: &File, pos: usize) -> Result<Option<Header>> {
+        let mut block_type = [0; 1];
+        file.read_exact_at(&mut block_type, pos as u64)?;
+        let disk_block_type = match DiskBlockType::try_from(block_type[0]) {
+            Ok(disk_block_type) => disk_block_type,
+            Err(_) => return Ok(None),
+        };
+
+        if disk_block_type != DiskBlockType::Header {
+            return Ok(None);
+        }
+
+        let header_buf = file_read::read_header(file, pos, MAX_DB_HEADER_SIZE)?;
+
+        let mut cursor = Cursor::new(&header_buf[..]);
+
+        let header = RawFileHeader::decode(&mut cursor)?;
+
+        if header.purge_ptr > pos as u64 {
+            return Ok(None);
+        }
+
+        let root_sizes = [header.seqrootsize, header.idrootsize, header.localrootsize];
+
+        if root_sizes
+            .iter()
+            .any(|&size| size != 0 && (size as usize) < ROOT_BASE_SIZE)
+        {
+            return Ok(None);
+        }
+
+        if header_buf.len()
+            != RawFileHeader::on_disk_size(header.version)
+                + root_sizes.iter().map(|&size| size as usize).sum::<usize>()
+        {
+            return Ok(None);
+        }
+
+        let by_seq_root = NodePointer::read_root(&mut cursor, header.seqrootsize as usize)?;
+        let by_id_root = NodePointer::read_root(&mut cursor, header.idrootsize as usize)?;
+        let local_docs_root = NodePointer::read_root(&mut cursor, header.localrootsize as usize)?;
+
+        Ok(Some(Header {
+            disk_version: header.version,
+            update_seq: header.update_seq,
+            by_id_root,
+            by_seq_root,
+            local_docs_root,
+            purge_seq: header.purge_seq,
+            purge_ptr: header.purge_ptr,
+            position: pos as u64,
+            timestamp: header.timestamp,
+            // Block prefix, length and CRC, then the header itself
+            end_position: (pos + 9 + header_buf.len()) as u64,
+        }))
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
 #[repr(u8)]
 pub enum DiskVersion {
@@ -78,39 +246,47 @@ pub struct NodePointer {
 }
 
 impl NodePointer {
-    fn read_root(mut buf: impl io::Read, root_size: usize) -> Option<NodePointer> {
+    fn read_root(mut buf: impl io::Read, root_size: usize) -> Result<Option<NodePointer>> {
         if root_size == 0 {
-            return None;
+            return Ok(None);
         }
-        let position = buf.read_u48::<BigEndian>().unwrap();
-        let subtree_size = buf.read_u48::<BigEndian>().unwrap();
-
-        let redsize = if root_size > 0 { root_size - 12 } else { 0 };
+        if root_size < ROOT_BASE_SIZE {
+            return Err(Error::Corrupt("root size"));
+        }
+        let position = buf.read_u48::<BigEndian>().map_err(corrupt("root"))?;
+        let subtree_size = buf.read_u48::<BigEndian>().map_err(corrupt("root"))?;
 
-        let mut reduce_value = vec![0; redsize];
-        buf.read_exact(&mut reduce_value).unwrap();
+        let mut reduce_value = vec![0; root_size - ROOT_BASE_SIZE];
+        buf.read_exact(&mut reduce_value).map_err(corrupt("root"))?;
 
-        Some(NodePointer {
+        Ok(Some(NodePointer {
             key: None,
             pointer: position,
             reduce_value,
             subtree_size,
-        })
+        }))
     }
 
-    fn read_pointer(key: &[u8], mut buf: impl io::Read) -> NodePointer {
-        let pointer = buf.read_u48::<BigEndian>().unwrap();
-        let subtree_size = buf.read_u48::<BigEndian>().unwrap();
-        let reduce_value_len = buf.read_u16::<BigEndian>().unwrap() as usize;
+    fn read_pointer(key: &[u8], mut buf: impl io::Read) -> Result<NodePointer> {
+        let pointer = buf
+            .read_u48::<BigEndian>()
+            .map_err(corrupt("node pointer"))?;
+        let subtree_size = buf
+            .read_u48::<BigEndian>()
+            .map_err(corrupt("node pointer"))?;
+        let reduce_value_len = buf
+            .read_u16::<BigEndian>()
+            .map_err(corrupt("node pointer"))? as usize;
         let mut reduce_value = vec![0; reduce_value_len];
-        buf.read_exact(&mut reduce_value).unwrap();
+        buf.read_exact(&mut reduce_value)
+            .map_err(corrupt("node pointer"))?;
 
-        NodePointer {
+        Ok(NodePointer {
             key: Some(key.to_vec()),
             pointer,
             reduce_value,
             subtree_size,
-        }
+        })
     }
 
     fn encode_root(&self, mut buf: impl io::Write) -> io::Result<()> {
@@ -129,18 +305,43 @@ impl NodePointer {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct LocalDoc {
     pub id: Vec<u8>,
     pub json: Option<Vec<u8>>,
     deleted: bool,
 }
 
+impl LocalDoc {
+    pub fn new(id: impl Into<Vec<u8>>, json: impl Into<Vec<u8>>) -> Self {
+        Self {
+            id: id.into(),
+            json: Some(json.into()),
+            deleted: false,
+        }
+    }
+
+    /// A local document that removes `id` when saved with
+    /// [Db::save_local_document]
+    pub fn deleted(id: impl Into<Vec<u8>>) -> Self {
+        Self {
+            id: id.into(),
+            json: None,
+            deleted: true,
+        }
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+}
+
 pub struct Doc {
     pub id: Vec<u8>,
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct DocInfo {
     /// Document ID (key)
     pub id: Vec<u8>,
@@ -170,21 +371,23 @@ pub struct DocInfo {
 
 const BP_DELETED_FLAG: u64 = 0x800000000000;
 
+/// Largest sequence number that can be stored in the 48 bit by-seq keys
+const MAX_SEQ: u64 = 0xffff_ffff_ffff;
+
 impl DocInfo {
-    fn decode_id_index_value(key: Vec<u8>, mut value: &[u8]) -> DocInfo {
-        let db_seq = value.read_u48::<BigEndian>().unwrap();
-        let data_size = value.read_u32::<BigEndian>().unwrap();
-        let bp = value.read_u48::<BigEndian>().unwrap();
+    fn decode_id_index_value(key: Vec<u8>, mut value: &[u8]) -> Result<DocInfo> {
+        let err = corrupt("by-id index value");
+        let db_seq = value.read_u48::<BigEndian>().map_err(&err)?;
+        let data_size = value.read_u32::<BigEndian>().map_err(&err)?;
+        let bp = value.read_u48::<BigEndian>().map_err(&err)?;
         let deleted = bp & BP_DELETED_FLAG != 0;
         let bp = bp & !BP_DELETED_FLAG;
-        let content_meta = ContentMetaFlag::from_bits(value.read_u8().unwrap()).unwrap();
-        let rev_seq: u64 = value.read_u48::<BigEndian>().unwrap();
+        let content_meta = ContentMetaFlag::from_bits_retain(value.read_u8().map_err(&err)?);
+        let rev_seq: u64 = value.read_u48::<BigEndian>().map_err(&err)?;
 
-        let rev_meta_len = value.len();
-        let mut rev_meta = vec![0; rev_meta_len];
-        value.read_exact(&mut rev_meta).unwrap();
+        let rev_meta = value.to_vec();
 
-        DocInfo {
+        Ok(DocInfo {
             id: key,
             db_seq,
             rev_seq,
@@ -193,29 +396,28 @@ impl DocInfo {
             content_meta,
             bp,
             physical_size: data_size,
-        }
+        })
     }
 
-    fn decode_by_seq_index_value(mut key: &[u8], mut value: &[u8]) -> DocInfo {
+    fn decode_by_seq_index_value(mut key: &[u8], mut value: &[u8]) -> Result<DocInfo> {
+        let err = corrupt("by-seq index value");
         let mut raw = [0; 5];
-        value.read_exact(&mut raw).unwrap();
+        value.read_exact(&mut raw).map_err(&err)?;
         let (id_size, data_size) = decode_kv_length(&raw);
 
-        let bp = value.read_u48::<BigEndian>().unwrap();
+        let bp = value.read_u48::<BigEndian>().map_err(&err)?;
         let deleted = bp & BP_DELETED_FLAG != 0;
         let bp = bp & !BP_DELETED_FLAG;
-        let content_meta = ContentMetaFlag::from_bits(value.read_u8().unwrap()).unwrap();
-        let rev_seq = value.read_u48::<BigEndian>().unwrap();
-        let db_seq = key.read_u48::<BigEndian>().unwrap();
+        let content_meta = ContentMetaFlag::from_bits_retain(value.read_u8().map_err(&err)?);
+        let rev_seq = value.read_u48::<BigEndian>().map_err(&err)?;
+        let db_seq = key.read_u48::<BigEndian>().map_err(&err)?;
 
         let mut id = vec![0; id_size as usize];
-        value.read_exact(&mut id).unwrap();
+        value.read_exact(&mut id).map_err(&err)?;
 
-        let rev_meta_len = value.len();
-        let mut rev_meta = vec![0; rev_meta_len];
-        value.read_exact(&mut rev_meta).unwrap();
+        let rev_meta = value.to_vec();
 
-        DocInfo {
+        Ok(DocInfo {
             id,
             db_seq,
             rev_seq,
@@ -224,7 +426,7 @@ impl DocInfo {
             content_meta,
             bp,
             physical_size: data_size,
-        }
+        })
     }
 }
 
@@ -251,7 +453,7 @@ use bitflags::bitflags;
 use std::convert::TryFrom;
 
 bitflags! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct ContentMetaFlag: u8 {
         /// Document contents compressed via Snappy
         const IS_COMPRESSED = 128;
@@ -287,7 +489,11 @@ bitflags! {
 pub struct TreeFile {
     pos: usize,
     file: File,
-    _options: DBOpenOptions,
+    options: DBOpenOptions,
+    /// Checksum algorithm for the file's disk version
+    crc_mode: CrcMode,
+    /// Bytes written since the file was last synced
+    unsynced_bytes: u64,
 }
 
 impl TreeFile {
@@ -295,7 +501,9 @@ impl TreeFile {
         TreeFile {
             pos: 0,
             file,
-            _options: options,
+            options,
+            crc_mode: CrcMode::default(),
+            unsynced_bytes: 0,
         }
     }
 }
@@ -303,34 +511,79 @@ impl TreeFile {
 const ROOT_BASE_SIZE: usize = 12;
 
 impl Db {
-    pub fn open(filename: impl AsRef<Path>, opts: DBOpenOptions) -> Db {
+    pub fn open(filename: impl AsRef<Path>, opts: DBOpenOptions) -> Result<Db> {
         let file = std::fs::OpenOptions::new()
             .read(true)
             .write(!opts.read_only)
             .create(!opts.read_only && opts.create)
-            .open(filename)
-            .unwrap();
+            .open(filename)?;
 
         let mut tree_file = TreeFile::new(file, opts);
 
-        tree_file.pos = tree_file.file.seek(SeekFrom::End(0)).unwrap() as usize;
+        tree_file.pos = tree_file.file.seek(SeekFrom::End(0))? as usize;
 
         let mut db = Db {
             file: tree_file,
             header: Header::default(),
             opts,
+            ignored_bytes: 0,
         };
 
         if db.file.pos == 0 {
-            db.create_header();
+            if opts.read_only {
+                return Err(Error::NoHeader);
+            }
+            db.create_header()?;
         } else {
-            db.find_header(db.file.pos - 2);
+            db.header = Header::find(&db.file.file, db.file.pos - 1)?;
+            db.file.crc_mode = db.header.disk_version.crc_mode();
+
+            let end_position = db.header.end_position as usize;
+
+            if db.file.pos > end_position {
+                db.ignored_bytes = (db.file.pos - end_position) as u64;
+
+                if opts.truncate_invalid_tail && !opts.read_only {
+                    db.file.file.set_len(end_position as u64)?;
+                    db.file.pos = end_position;
+                }
+            }
         }
 
-        db
+        Ok(db)
     }
 
-    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+    /// Open the database as of the header at `pos`, one of the positions
+    /// returned by [Db::headers], to read a consistent snapshot from before
+    /// later commits.
+    ///
+    /// Unless `opts` is read only, committing writes a new header based on
+    /// the one at `pos`, rolling the database back to it.
+    pub fn open_at_header(filename: impl AsRef<Path>, opts: DBOpenOptions, pos: u64) -> Result<Db> {
+        let mut db = Db::open(filename, opts)?;
+
+        db.header = Header::at_pos(&db.file.file, pos)?;
+        db.file.crc_mode = db.header.disk_version.crc_mode();
+
+        Ok(db)
+    }
+
+    /// Every valid header in the file, newest first. These are the states the
+    /// database was in after each commit, and can be opened with
+    /// [Db::open_at_header].
+    pub fn headers(&self) -> Result<Vec<Header>> {
+        // The in-memory header may have uncommitted changes, so start from
+        // the newest header on disk
+        Header::find(&self.file.file, self.file.pos - 1)?.history(&self.file.file)
+    }
+
+    /// Number of bytes after the most recent valid header that were ignored
+    /// when the file was opened, e.g. from a flush that never committed.
+    pub fn ignored_bytes(&self) -> u64 {
+        self.ignored_bytes
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
         let doc = Doc {
             id: key.clone(),
             data: value.clone(),
@@ -349,13 +602,65 @@ impl Db {
             physical_size,
         };
 
-        self.couchstore_save_document(Some(doc), doc_info, SaveOptions::COMPRESS_DOC_BODIES);
+        self.couchstore_save_document(Some(doc), doc_info, SaveOptions::COMPRESS_DOC_BODIES)
+    }
+
+    /// Delete `key` by writing a tombstone with the given revision metadata.
+    /// The tombstone is given the next revision number of the document, and
+    /// stays in both indexes (with `deleted` set) until it is purged by
+    /// compaction.
+    pub fn delete(&mut self, key: impl Into<Vec<u8>>, rev_meta: Vec<u8>) -> Result<()> {
+        self.save_tombstone(key.into(), rev_meta, None)
     }
 
-    pub fn docinfo_by_id(&mut self, key: impl Into<Vec<u8>>) -> Option<DocInfo> {
+    /// Delete `key` like [Db::delete], but keep `body` with the tombstone,
+    /// e.g. to preserve system xattrs. The body can be read back with
+    /// [Db::open_doc_with_docinfo].
+    pub fn delete_with_body(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        rev_meta: Vec<u8>,
+        body: Vec<u8>,
+    ) -> Result<()> {
+        self.save_tombstone(key.into(), rev_meta, Some(body))
+    }
+
+    fn save_tombstone(
+        &mut self,
+        key: Vec<u8>,
+        rev_meta: Vec<u8>,
+        body: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let rev_seq = match self.docinfo_by_id(key.clone()) {
+            Ok(existing) => existing.rev_seq + 1,
+            Err(Error::DocNotFound) => 1,
+            Err(err) => return Err(err),
+        };
+
+        let doc = body.map(|data| Doc {
+            id: key.clone(),
+            data,
+        });
+
+        let doc_info = DocInfo {
+            id: key,
+            rev_seq,
+            rev_meta,
+            deleted: true,
+            content_meta: ContentMetaFlag::IS_COMPRESSED,
+            ..Default::default()
+        };
+
+        self.couchstore_save_document(doc, doc_info, SaveOptions::COMPRESS_DOC_BODIES)
+    }
+
+    pub fn docinfo_by_id(&mut self, key: impl Into<Vec<u8>>) -> Result<DocInfo> {
         let key = key.into();
 
-        let root_pointer = self.header.by_id_root.as_ref()?.pointer as usize;
+        let root_pointer = match &self.header.by_id_root {
+            Some(root) => root.pointer as usize,
+            None => return Err(Error::DocNotFound),
+        };
 
         let mut req = CouchfileLookupRequest::new(vec![key.clone()]);
 
@@ -365,23 +670,24 @@ impl Db {
             &mut req,
             |_, _, value| {
                 if let Some(value) = value {
-                    docinfo = Some(DocInfo::decode_id_index_value(key.clone(), value));
+                    docinfo = Some(DocInfo::decode_id_index_value(key.clone(), value)?);
                 }
+                Ok(())
             },
             root_pointer,
-        );
+        )?;
 
-        docinfo
+        docinfo.ok_or(Error::DocNotFound)
     }
 
     pub fn docinfos_by_id(
         &mut self,
         mut keys: Vec<Vec<u8>>,
         mut on_fetch: impl FnMut(&[u8], Option<DocInfo>),
-    ) {
+    ) -> Result<()> {
         let root_pointer = match self.header.by_id_root {
             Some(ref root) => root.pointer as usize,
-            None => return,
+            None => return Ok(()),
         };
 
         keys.sort_unstable();
@@ -391,16 +697,21 @@ impl Db {
         self.btree_lookup(
             &mut req,
             |_, key, value| {
-                let docinfo =
-                    value.map(|value| DocInfo::decode_id_index_value(Vec::from(key), value));
+                let docinfo = value
+                    .map(|value| DocInfo::decode_id_index_value(Vec::from(key), value))
+                    .transpose()?;
                 on_fetch(key, docinfo);
+                Ok(())
             },
             root_pointer,
-        );
+        )
     }
 
-    pub fn docinfo_by_sequence(&mut self, sequence: u64) -> Option<DocInfo> {
-        let root_pointer = self.header.by_seq_root.as_ref()?.pointer as usize;
+    pub fn docinfo_by_sequence(&mut self, sequence: u64) -> Result<DocInfo> {
+        let root_pointer = match &self.header.by_seq_root {
+            Some(root) => root.pointer as usize,
+            None => return Err(Error::DocNotFound),
+        };
 
         let key = sequence.to_be_bytes()[2..].to_vec();
 
@@ -412,19 +723,27 @@ impl Db {
             &mut req,
             |_, key, value| {
                 if let Some(value) = value {
-                    docinfo = Some(DocInfo::decode_by_seq_index_value(key, value));
+                    docinfo = Some(DocInfo::decode_by_seq_index_value(key, value)?);
                 }
+                Ok(())
             },
             root_pointer,
-        );
+        )?;
 
-        docinfo
+        docinfo.ok_or(Error::DocNotFound)
     }
 
-    pub fn changes_since(&mut self, sequence: u64, mut on_fetch: impl FnMut(&mut Self, DocInfo)) {
+    /// Call `on_fetch` with every change with a sequence number of at least
+    /// `sequence`, in sequence order. An error returned by `on_fetch` stops
+    /// the scan and is returned.
+    pub fn changes_since(
+        &mut self,
+        sequence: u64,
+        mut on_fetch: impl FnMut(&mut Self, DocInfo) -> Result<()>,
+    ) -> Result<()> {
         let root_pointer = match self.header.by_seq_root.as_ref() {
             Some(root) => root.pointer as usize,
-            None => return,
+            None => return Ok(()),
         };
 
         let key = sequence.to_be_bytes()[2..].to_vec();
@@ -437,15 +756,20 @@ impl Db {
             &mut req,
             |db, key, value| {
                 if let Some(value) = value {
-                    let docinfo = DocInfo::decode_by_seq_index_value(key, value);
-                    on_fetch(db, docinfo);
+                    let docinfo = DocInfo::decode_by_seq_index_value(key, value)?;
+                    on_fetch(db, docinfo)?;
                 }
+                Ok(())
             },
             root_pointer,
-        );
+        )
     }
 
-    pub fn save_local_document(&mut self, local_doc: LocalDoc) {
+    pub fn save_local_document(&mut self, local_doc: LocalDoc) -> Result<()> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
         let action_type = if local_doc.deleted {
             CouchfileModifyActionType::Remove
         } else {
@@ -458,22 +782,29 @@ impl Db {
             action_type,
         };
 
-        let req = CouchfileModifyRequest {
+        let mut req = CouchfileModifyRequest {
             actions: vec![action],
             context: (),
             kv_chunk_threshold: self.opts.kv_chunk_threshold,
             kp_chunk_threshold: self.opts.kp_chunk_threshold,
+            reduce: None,
+            rereduce: None,
         };
 
         let root = self.header.local_docs_root.clone();
 
-        self.header.local_docs_root = self.file.modify_btree(req, root);
+        self.header.local_docs_root = self.file.modify_btree(&mut req, root)?;
+
+        Ok(())
     }
 
-    pub fn open_local_document(&mut self, id: impl Into<Vec<u8>>) -> Option<LocalDoc> {
+    pub fn open_local_document(&mut self, id: impl Into<Vec<u8>>) -> Result<LocalDoc> {
         let id = id.into();
 
-        let root = self.header.local_docs_root.clone()?;
+        let root = match &self.header.local_docs_root {
+            Some(root) => root.pointer as usize,
+            None => return Err(Error::DocNotFound),
+        };
 
         let mut req = CouchfileLookupRequest::new(vec![id]);
 
@@ -483,32 +814,42 @@ impl Db {
             &mut req,
             |_, key, value| {
                 if let Some(value) = value {
-                    local_doc = Some(LocalDoc {
-                        id: key.to_vec(),
-                        json: Some(value.to_vec()),
-                        deleted: false,
-                    });
+                    local_doc = Some(LocalDoc::new(key, value));
                 }
+                Ok(())
             },
-            root.pointer as usize,
-        );
+            root,
+        )?;
 
-        local_doc
+        local_doc.ok_or(Error::DocNotFound)
     }
 
-    pub fn commit(&mut self) {
-        self.precommit();
+    pub fn commit(&mut self) -> Result<()> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let pre_commit_pos = self.file.pos;
+        let pre_commit_header = self.header.clone();
 
-        let _pre_flush_pos = self.file.pos;
+        let result = self.precommit().and_then(|()| {
+            // Flush header to kernel buffer
+            self.header.timestamp = utils::now();
+            self.write_header()?;
 
-        // Flush header to kernel buffer
-        self.header.timestamp = utils::now();
-        self.write_header();
+            // Sync header to disk
+            self.file.sync()
+        });
 
-        // Sync header to disk
-        self.file.file.flush().unwrap();
+        if result.is_err() {
+            // Forget the partly written header so that retrying the commit
+            // overwrites it. The header isn't written until the data it
+            // points to is synced, so the previous header is still good.
+            self.file.pos = pre_commit_pos;
+            self.header = pre_commit_header;
+        }
 
-        // TODO: Handle flush failures, retry and reset file.pos to pre_flush_pos
+        result
     }
 
     /// Precommit should occur before writing a header, it has two
@@ -522,7 +863,7 @@ impl Db {
     /// the fdatasync performed by writing a header doesn't have to
     /// do an additional (expensive) modified metadata flush on top
     /// of the one we're already doing.
-    fn precommit(&mut self) {
+    fn precommit(&mut self) -> Result<()> {
         let curpos = self.file.pos;
 
         self.file.pos = align_to_next_block(self.file.pos);
@@ -533,12 +874,14 @@ impl Db {
 
         // Extend file size to where end of header will land before we do first sync
         // TODO: Fix the mut 0s lol
-        self.file.db_write_buf(&[0], &mut 0, &mut 0);
+        self.file.db_write_buf(&[0], &mut 0, &mut 0)?;
 
-        self.file.file.flush().unwrap();
+        self.file.sync()?;
 
         // Move cursor back to where it was
         self.file.pos = curpos;
+
+        Ok(())
     }
 
     /// Retrieve a doc from the db, using a DocInfo.
@@ -547,85 +890,12 @@ impl Db {
     pub fn open_doc_with_docinfo(
         &mut self,
         docinfo: &DocInfo,
-        mut options: OpenOptions,
-    ) -> Option<Doc> {
-        if docinfo.bp == 0 {
-            return None;
-        }
-
-        let bp = docinfo.bp as usize;
-
-        if !docinfo
-            .content_meta
-            .contains(ContentMetaFlag::IS_COMPRESSED)
-        {
-            options.remove(OpenOptions::DECOMPRESS_DOC_BODIES);
-        }
-
-        let docbody = if options.contains(OpenOptions::DECOMPRESS_DOC_BODIES) {
-            self.file.read_compressed(bp)
-        } else {
-            self.file.read_uncompressed(bp)
-        };
-
-        if docbody.is_empty() {
-            return None;
-        }
-
-        let doc = Doc {
-            id: docinfo.id.clone(),
-            data: docbody,
-        };
-
-        Some(doc)
+        options: OpenOptions,
+    ) -> Result<Doc> {
+        file_read::read_doc(&self.file.file, docinfo, options, self.file.crc_mode)
     }
 
-    fn find_header(&mut self, start_pos: usize) {
-        let mut pos = start_pos;
-
-        pos -= pos % COUCH_BLOCK_SIZE;
-
-        self.find_header_at_pos(pos);
-
-        // TODO: loop until good header found or end of file
-    }
-
-    fn find_header_at_pos(&mut self, pos: usize) {
-        self.file.file.seek(SeekFrom::Start(pos as u64)).unwrap();
-        let disk_block_type = DiskBlockType::try_from(self.file.file.read_u8().unwrap()).unwrap();
-
-        assert_eq!(disk_block_type, DiskBlockType::Header);
-
-        let header_buf = self.file.read_header(pos, MAX_DB_HEADER_SIZE);
-
-        let mut cursor = Cursor::new(&header_buf[..]);
-
-        let header = RawFileHeaderV13::decode(&mut cursor);
-
-        assert!(header.purge_ptr <= pos as u64);
-        assert_eq!(
-            header_buf.len(),
-            RawFileHeaderV13::ON_DISK_SIZE
-                + (header.seqrootsize as usize)
-                + (header.idrootsize as usize)
-                + (header.localrootsize as usize)
-        );
-
-        let by_seq_root = NodePointer::read_root(&mut cursor, header.seqrootsize as usize);
-        let by_id_root = NodePointer::read_root(&mut cursor, header.idrootsize as usize);
-        let local_docs_root = NodePointer::read_root(&mut cursor, header.localrootsize as usize);
-
-        self.header.update_seq = header.update_seq;
-        self.header.by_id_root = by_id_root;
-        self.header.by_seq_root = by_seq_root;
-        self.header.local_docs_root = local_docs_root;
-        self.header.purge_seq = header.purge_seq;
-        self.header.purge_ptr = header.purge_ptr;
-        self.header.position = pos as u64;
-        self.header.timestamp = header.timestamp;
-    }
-
-    fn create_header(&mut self) {
+    fn create_header(&mut self) -> Result<()> {
         self.header.disk_version = DiskVersion::Thirteen;
         self.header.update_seq = 0;
         self.header.by_id_root = None;
@@ -636,10 +906,10 @@ impl Db {
         self.header.position = 0;
         self.header.timestamp = 0;
 
-        self.write_header();
+        self.write_header()
     }
 
-    fn write_header(&mut self) {
+    fn write_header(&mut self) -> Result<()> {
         let (totalsize, seqrootsize, idrootsize, localrootsize) = self.calculate_header_size();
 
         let mut b = Vec::with_capacity(totalsize);
@@ -651,7 +921,9 @@ impl Db {
         b.write_u16::<BigEndian>(seqrootsize as u16).unwrap();
         b.write_u16::<BigEndian>(idrootsize as u16).unwrap();
         b.write_u16::<BigEndian>(localrootsize as u16).unwrap();
-        b.write_u64::<BigEndian>(self.header.timestamp).unwrap();
+        if self.header.disk_version.has_timestamp() {
+            b.write_u64::<BigEndian>(self.header.timestamp).unwrap();
+        }
         if let Some(by_seq_root) = &self.header.by_seq_root {
             by_seq_root.encode_root(&mut b).unwrap();
         }
@@ -662,8 +934,11 @@ impl Db {
             local_docs_root.encode_root(&mut b).unwrap();
         }
 
-        let header_pos = self.file.write_header(&b);
+        let header_pos = self.file.write_header(&b)?;
         self.header.position = header_pos as u64;
+        self.header.end_position = self.file.pos as u64;
+
+        Ok(())
     }
 
     fn calculate_header_size(&self) -> (usize, usize, usize, usize) {
@@ -682,7 +957,10 @@ impl Db {
             localrootsize = ROOT_BASE_SIZE + local_docs_root.reduce_value.len();
         }
 
-        let total = RawFileHeaderV13::ON_DISK_SIZE + seqrootsize + idrootsize + localrootsize;
+        let total = RawFileHeader::on_disk_size(self.header.disk_version)
+            + seqrootsize
+            + idrootsize
+            + localrootsize;
 
         (total, seqrootsize, idrootsize, localrootsize)
     }
@@ -690,6 +968,39 @@ impl Db {
     pub fn header(&self) -> &Header {
         &self.header
     }
+
+    /// Number of non-deleted documents in the database
+    pub fn doc_count(&self) -> Result<u64> {
+        Ok(self.by_id_reduce()?.not_deleted)
+    }
+
+    /// Number of deleted documents (tombstones) in the database
+    pub fn deleted_count(&self) -> Result<u64> {
+        Ok(self.by_id_reduce()?.deleted)
+    }
+
+    fn by_id_reduce(&self) -> Result<ByIdReduce> {
+        self.header.by_id_reduce()
+    }
+
+    /// Count the number of changes (including deletions) with a sequence
+    /// number between `min_seq` and `max_seq`, both inclusive.
+    pub fn changes_count(&mut self, min_seq: u64, max_seq: u64) -> Result<u64> {
+        let root_pointer = match self.header.by_seq_root.as_ref() {
+            Some(root) => root.pointer as usize,
+            None => return Ok(0),
+        };
+
+        if min_seq > max_seq {
+            return Ok(0);
+        }
+
+        // Sequence keys are fixed width big endian so compare bytewise
+        let start = min_seq.min(MAX_SEQ).to_be_bytes()[2..].to_vec();
+        let end = max_seq.min(MAX_SEQ).to_be_bytes()[2..].to_vec();
+
+        self.btree_count_range(root_pointer, &start, &end, reduces::decode_by_seq_reduce)
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -703,13 +1014,37 @@ pub struct DBOpenOptions {
     kv_chunk_threshold: usize,
 
     kp_chunk_threshold: usize,
+
+    /// Truncate anything after the last valid header when opening the file
+    truncate_invalid_tail: bool,
+
+    /// How commits are synced to disk
+    commit_policy: CommitPolicy,
+
+    /// Sync the file every time this many bytes have been written
+    sync_every_n_bytes: Option<u64>,
 }
 
-fn seq_no_compare(mut a: &[u8], mut b: &[u8]) -> Ordering {
-    let a_seq = a.read_u48::<BigEndian>().unwrap();
-    let b_seq = b.read_u48::<BigEndian>().unwrap();
+/// How [Db::commit] makes data durable. The data written by a commit is
+/// always synced before its header is written, and the header after, so a
+/// crash leaves the file at the last header that was fully synced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CommitPolicy {
+    /// Don't sync, leaving the OS to write the data back. Commits are
+    /// atomic but a crash can lose recent ones.
+    None,
+    /// `fdatasync`, which skips file metadata that isn't needed to read
+    /// the data back
+    #[default]
+    DataSync,
+    /// `fsync`, also syncing all file metadata
+    FullSync,
+}
 
-    a_seq.cmp(&b_seq)
+/// Sequence keys are 48 bit big endian, so compare bytewise. Comparing
+/// bytes rather than decoding them means a short key can't panic.
+fn seq_no_compare(a: &[u8], b: &[u8]) -> Ordering {
+    a.cmp(b)
 }
 
 impl Default for DBOpenOptions {
@@ -719,6 +1054,9 @@ impl Default for DBOpenOptions {
             read_only: false,
             kv_chunk_threshold: 1279,
             kp_chunk_threshold: 1279,
+            truncate_invalid_tail: false,
+            commit_policy: CommitPolicy::default(),
+            sync_every_n_bytes: None,
         }
     }
 }
@@ -728,11 +1066,33 @@ impl DBOpenOptions {
         self.read_only = true;
         self
     }
+
+    /// Truncate the file back to the end of the last valid header if it
+    /// contains data from an incomplete commit. Ignored for read only opens.
+    pub fn truncate_invalid_tail(mut self) -> Self {
+        self.truncate_invalid_tail = true;
+        self
+    }
+
+    pub fn commit_policy(mut self, policy: CommitPolicy) -> Self {
+        self.commit_policy = policy;
+        self
+    }
+
+    /// Also sync after every `bytes` written, like kv_engine's
+    /// `fsync_after_every_n_bytes_written`, so a large commit doesn't build
+    /// up a lot of dirty pages to write back at once. Uses the
+    /// [CommitPolicy] to sync, so does nothing with [CommitPolicy::None].
+    pub fn sync_every_n_bytes(mut self, bytes: u64) -> Self {
+        self.sync_every_n_bytes = Some(bytes);
+        self
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::io::Write;
 
     #[test]
     fn test_docinfo_by_sequence() {
@@ -740,7 +1100,7 @@ mod test {
             read_only: true,
             ..Default::default()
         };
-        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts);
+        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts).unwrap();
 
         let info_by_id: DocInfo = db.docinfo_by_id("\0route_24983").unwrap();
         let info_by_seq = db.docinfo_by_sequence(info_by_id.db_seq).unwrap();
@@ -754,14 +1114,15 @@ mod test {
             read_only: true,
             ..Default::default()
         };
-        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts);
+        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts).unwrap();
 
         let keys: Vec<Vec<u8>> = vec![Vec::from("\0route_24983"), Vec::from("\0landmark_37519")];
 
         let mut doc_infos = vec![];
         db.docinfos_by_id(keys.clone(), |_, doc_info| {
             doc_infos.push(doc_info.unwrap());
-        });
+        })
+        .unwrap();
 
         // we get keys back in sorted order
         assert_eq!(doc_infos[0].id, keys[1]);
@@ -774,12 +1135,403 @@ mod test {
             read_only: true,
             ..Default::default()
         };
-        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts);
+        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts).unwrap();
         let mut seq = 1;
         db.changes_since(0, |_, doc_info| {
             assert_eq!(doc_info.db_seq, seq);
             seq += 1;
-        });
+            Ok(())
+        })
+        .unwrap();
         assert_eq!(seq, 98);
     }
+
+    #[test]
+    fn test_counts() {
+        let opts = DBOpenOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts).unwrap();
+
+        assert_eq!(db.ignored_bytes(), 0);
+        assert_eq!(db.doc_count().unwrap(), 97);
+        assert_eq!(db.deleted_count().unwrap(), 0);
+        assert_eq!(db.changes_count(0, u64::MAX).unwrap(), 97);
+        assert_eq!(db.changes_count(10, 19).unwrap(), 10);
+        assert_eq!(db.changes_count(97, 97).unwrap(), 1);
+        assert_eq!(db.changes_count(98, u64::MAX).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_compact() {
+        let opts = DBOpenOptions::default().read_only();
+        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts).unwrap();
+        let path = temp_db_path("compact");
+
+        let mut compacted = db.compact(&path, CompactOptions::default()).unwrap();
+        assert_eq!(compacted.header.update_seq, db.header.update_seq);
+        assert_eq!(compacted.doc_count().unwrap(), db.doc_count().unwrap());
+        assert_eq!(compacted.changes_count(0, u64::MAX).unwrap(), 97);
+
+        let info = compacted.docinfo_by_id("\0route_24983").unwrap();
+        assert_eq!(info, compacted.docinfo_by_sequence(info.db_seq).unwrap());
+        let original = db.docinfo_by_id("\0route_24983").unwrap();
+        assert_eq!(
+            compacted
+                .open_doc_with_docinfo(&info, OpenOptions::DECOMPRESS_DOC_BODIES)
+                .unwrap()
+                .data,
+            db.open_doc_with_docinfo(&original, OpenOptions::DECOMPRESS_DOC_BODIES)
+                .unwrap()
+                .data
+        );
+
+        assert_eq!(
+            compacted
+                .open_local_document("_local/vbstate")
+                .unwrap()
+                .json,
+            db.open_local_document("_local/vbstate").unwrap().json
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_compact_hook() {
+        let opts = DBOpenOptions::default().read_only();
+        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts).unwrap();
+        let path = temp_db_path("compact-hook");
+
+        let options = CompactOptions::default().hook(|info| {
+            if info.id.starts_with(b"\0route_") {
+                CompactDecision::Drop
+            } else {
+                CompactDecision::Keep
+            }
+        });
+        let mut compacted = db.compact(&path, options).unwrap();
+
+        assert!(compacted.docinfo_by_id("\0route_24983").is_err());
+        assert!(compacted.docinfo_by_id("\0landmark_37519").is_ok());
+        let mut count = 0;
+        compacted
+            .changes_since(0, |_, info| {
+                assert!(!info.id.starts_with(b"\0route_"));
+                count += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(compacted.doc_count().unwrap(), count);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recover_from_torn_write() {
+        let path = temp_db_path("torn-write");
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        db.set(b"a".to_vec(), b"{}".to_vec()).unwrap();
+        db.commit().unwrap();
+        let first_header = db.header.position;
+        db.set(b"b".to_vec(), b"{}".to_vec()).unwrap();
+        db.commit().unwrap();
+        let second_header = db.header.position;
+        drop(db);
+        let committed_len = std::fs::metadata(&path).unwrap().len();
+
+        // Half written data from a flush that never committed
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[0xab; 5000]).unwrap();
+        drop(file);
+
+        let mut db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+        assert_eq!(db.header.position, second_header);
+        assert_eq!(db.ignored_bytes(), 5000);
+        assert!(db.docinfo_by_id("b").is_ok());
+        drop(db);
+
+        // Corrupt the most recent header so the one before is used
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(second_header + 20)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+
+        let mut db = Db::open(&path, DBOpenOptions::default().truncate_invalid_tail()).unwrap();
+        assert_eq!(db.header.position, first_header);
+        assert_eq!(db.header.update_seq, 1);
+        assert!(db.ignored_bytes() > 5000);
+        assert!(db.docinfo_by_id("b").is_err());
+        drop(db);
+
+        assert!(std::fs::metadata(&path).unwrap().len() < committed_len);
+        let db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        assert_eq!(db.ignored_bytes(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let path = temp_db_path("errors");
+
+        let err = Db::open(&path, DBOpenOptions::default().read_only()).unwrap_err();
+        assert!(matches!(err, Error::Io(_)));
+
+        std::fs::write(&path, [0xab; 5000]).unwrap();
+        let err = Db::open(&path, DBOpenOptions::default().read_only()).unwrap_err();
+        assert!(matches!(err, Error::NoHeader));
+        assert!(err.is_corruption());
+
+        let opts = DBOpenOptions::default().read_only();
+        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts).unwrap();
+        assert!(matches!(
+            db.docinfo_by_id("missing"),
+            Err(Error::DocNotFound)
+        ));
+        assert!(matches!(
+            db.set(b"a".to_vec(), b"{}".to_vec()),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(db.commit(), Err(Error::ReadOnly)));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_documents() {
+        let path = temp_db_path("save-documents");
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        let doc = |id: &str, data: &str| Doc {
+            id: id.into(),
+            data: data.into(),
+        };
+        let info = |id: &str, db_seq: u64| DocInfo {
+            id: id.into(),
+            db_seq,
+            rev_seq: 7,
+            rev_meta: vec![1, 2, 3],
+            content_meta: ContentMetaFlag::IS_COMPRESSED,
+            ..Default::default()
+        };
+
+        let batch = vec![
+            (Some(doc("a", "{}")), info("a", 10)),
+            (None, info("b", 11)),
+            (Some(doc("c", "old")), info("c", 12)),
+            (Some(doc("c", "new")), info("c", 13)),
+        ];
+        db.save_documents(
+            &batch,
+            SaveOptions::COMPRESS_DOC_BODIES | SaveOptions::SEQUENCE_AS_IS,
+        )
+        .unwrap();
+        db.commit().unwrap();
+        drop(db);
+
+        let mut db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+        assert_eq!(db.header.update_seq, 13);
+        assert_eq!(db.doc_count().unwrap(), 2);
+        assert_eq!(db.deleted_count().unwrap(), 1);
+
+        let a = db.docinfo_by_id("a").unwrap();
+        assert_eq!((a.db_seq, a.rev_seq), (10, 7));
+        assert_eq!(a.rev_meta, vec![1, 2, 3]);
+        assert!(db.docinfo_by_id("b").unwrap().deleted);
+
+        let c = db.docinfo_by_id("c").unwrap();
+        assert_eq!(c.db_seq, 13);
+        let body = db
+            .open_doc_with_docinfo(&c, OpenOptions::DECOMPRESS_DOC_BODIES)
+            .unwrap();
+        assert_eq!(body.data, b"new");
+
+        // Only the last revision of "c" is in the by-seq index
+        assert!(db.docinfo_by_sequence(12).is_err());
+        assert_eq!(db.changes_count(0, u64::MAX).unwrap(), 3);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_delete() {
+        let path = temp_db_path("delete");
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        db.set(b"a".to_vec(), b"{}".to_vec()).unwrap();
+        db.set(b"b".to_vec(), b"{}".to_vec()).unwrap();
+        db.delete("a", vec![1]).unwrap();
+        db.delete_with_body("b", vec![2], b"xattrs".to_vec())
+            .unwrap();
+        db.commit().unwrap();
+        drop(db);
+
+        let mut db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+        assert_eq!(db.doc_count().unwrap(), 0);
+        assert_eq!(db.deleted_count().unwrap(), 2);
+
+        let mut changes = vec![];
+        db.changes_since(0, |_, info| {
+            changes.push((info.id, info.db_seq, info.deleted));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            changes,
+            vec![(b"a".to_vec(), 3, true), (b"b".to_vec(), 4, true)]
+        );
+
+        let a = db.docinfo_by_id("a").unwrap();
+        assert_eq!((a.rev_seq, a.rev_meta.clone()), (1, vec![1]));
+        assert!(matches!(
+            db.open_doc_with_docinfo(&a, OpenOptions::DECOMPRESS_DOC_BODIES),
+            Err(Error::DocNotFound)
+        ));
+
+        let b = db.docinfo_by_id("b").unwrap();
+        let body = db
+            .open_doc_with_docinfo(&b, OpenOptions::DECOMPRESS_DOC_BODIES)
+            .unwrap();
+        assert_eq!(body.data, b"xattrs");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    pub(crate) fn temp_db_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "couchstore-{}-{}.couch.1",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_set_updates_by_seq() {
+        let path = temp_db_path("set-updates-by-seq");
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        db.set(b"a".to_vec(), b"{\"v\":1}".to_vec()).unwrap();
+        db.set(b"b".to_vec(), b"{\"v\":2}".to_vec()).unwrap();
+        db.set(b"a".to_vec(), b"{\"v\":3}".to_vec()).unwrap();
+        db.commit().unwrap();
+        drop(db);
+
+        let mut db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+
+        let mut changes = vec![];
+        db.changes_since(0, |_, doc_info| {
+            changes.push((doc_info.db_seq, doc_info.id));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(changes, vec![(2, b"b".to_vec()), (3, b"a".to_vec())]);
+
+        // The overwritten revision is no longer reachable by seqno
+        assert!(db.docinfo_by_sequence(1).is_err());
+        assert_eq!(db.doc_count().unwrap(), 2);
+        assert_eq!(db.changes_count(0, u64::MAX).unwrap(), 2);
+
+        let info = db.docinfo_by_sequence(3).unwrap();
+        assert_eq!(info, db.docinfo_by_id("a").unwrap());
+        let doc = db
+            .open_doc_with_docinfo(&info, OpenOptions::DECOMPRESS_DOC_BODIES)
+            .unwrap();
+        assert_eq!(doc.data, b"{\"v\":3}");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_at_header() {
+        let path = temp_db_path("open-at-header");
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        db.set(b"a".to_vec(), b"{\"v\":1}".to_vec()).unwrap();
+        db.commit().unwrap();
+        db.set(b"a".to_vec(), b"{\"v\":2}".to_vec()).unwrap();
+        db.set(b"b".to_vec(), b"{}".to_vec()).unwrap();
+        db.commit().unwrap();
+        // Uncommitted changes aren't a header yet
+        db.set(b"c".to_vec(), b"{}".to_vec()).unwrap();
+
+        let headers = db.headers().unwrap();
+        let seqs = headers.iter().map(|h| h.update_seq).collect::<Vec<_>>();
+        assert_eq!(seqs, [3, 1, 0]);
+        assert!(headers[0].timestamp() >= headers[1].timestamp());
+        drop(db);
+
+        let opts = DBOpenOptions::default().read_only();
+        let mut old = Db::open_at_header(&path, opts, headers[1].position()).unwrap();
+        assert_eq!(old.header().update_seq, 1);
+        assert_eq!(old.doc_count().unwrap(), 1);
+        assert!(old.docinfo_by_id("b").is_err());
+        let info = old.docinfo_by_id("a").unwrap();
+        let doc = old
+            .open_doc_with_docinfo(&info, OpenOptions::DECOMPRESS_DOC_BODIES)
+            .unwrap();
+        assert_eq!(doc.data, b"{\"v\":1}");
+
+        let reader = Reader::open_at_header(&path, headers[1].position()).unwrap();
+        assert_eq!(reader.docinfo_by_id("a").unwrap(), info);
+
+        // Not the start of a header
+        assert!(matches!(
+            Db::open_at_header(&path, opts, headers[1].position() + 1),
+            Err(Error::NoHeader)
+        ));
+        assert!(matches!(
+            Reader::open_at_header(&path, 1 << 40),
+            Err(Error::NoHeader)
+        ));
+
+        // Committing from an old header rolls the database back to it
+        let mut db =
+            Db::open_at_header(&path, DBOpenOptions::default(), headers[1].position()).unwrap();
+        db.commit().unwrap();
+        drop(db);
+        let mut db = Db::open(&path, opts).unwrap();
+        assert_eq!(db.header().update_seq, 1);
+        assert!(db.docinfo_by_id("b").is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_commit_policy() {
+        let policies = [
+            DBOpenOptions::default().commit_policy(CommitPolicy::None),
+            DBOpenOptions::default(),
+            DBOpenOptions::default().commit_policy(CommitPolicy::FullSync),
+            DBOpenOptions::default().sync_every_n_bytes(4096),
+        ];
+
+        for opts in policies {
+            let path = temp_db_path("commit-policy");
+
+            let mut db = Db::open(&path, opts).unwrap();
+            for i in 0..100 {
+                db.set(format!("key_{i}").into_bytes(), vec![b'x'; 200])
+                    .unwrap();
+            }
+            db.commit().unwrap();
+            assert_eq!(
+                db.file.unsynced_bytes == 0,
+                opts.commit_policy != CommitPolicy::None
+            );
+            drop(db);
+
+            let db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+            assert_eq!(db.doc_count().unwrap(), 100);
+
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
 }
@@ -1,10 +1,10 @@
 use std::io::{self, Cursor, Read};
 
-use crate::{DiskVersion, DocInfo};
+use crate::{error::corrupt, DiskVersion, DocInfo, Error, Result, BP_DELETED_FLAG};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 #[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
-pub struct RawFileHeaderV13 {
+pub struct RawFileHeader {
     pub version: DiskVersion,
     pub update_seq: u64,
     pub purge_seq: u64,
@@ -15,19 +15,34 @@ pub struct RawFileHeaderV13 {
     pub localrootsize: u16,
 }
 
-impl RawFileHeaderV13 {
-    pub const ON_DISK_SIZE: usize = 33;
-
-    pub fn decode(mut buf: impl io::Read) -> RawFileHeaderV13 {
-        let version = DiskVersion::try_from(buf.read_u8().unwrap()).unwrap();
-        let update_seq = buf.read_u48::<BigEndian>().unwrap();
-        let purge_seq = buf.read_u48::<BigEndian>().unwrap();
-        let purge_ptr = buf.read_u48::<BigEndian>().unwrap();
-        let seqrootsize = buf.read_u16::<BigEndian>().unwrap();
-        let idrootsize = buf.read_u16::<BigEndian>().unwrap();
-        let localrootsize = buf.read_u16::<BigEndian>().unwrap();
-        let timestamp = buf.read_u64::<BigEndian>().unwrap();
-        RawFileHeaderV13 {
+impl RawFileHeader {
+    /// Size of the fixed part of a header, before the tree roots
+    pub fn on_disk_size(version: DiskVersion) -> usize {
+        if version.has_timestamp() {
+            33
+        } else {
+            25
+        }
+    }
+
+    /// Decode a header, failing if the buffer is too short or the disk
+    /// version isn't one we understand. Headers before version 13 have no
+    /// timestamp, so it is left as 0.
+    pub fn decode(mut buf: impl io::Read) -> Result<RawFileHeader> {
+        let version = DiskVersion::try_from(buf.read_u8().map_err(corrupt("header"))?)
+            .map_err(|_| Error::Corrupt("header disk version"))?;
+        let update_seq = buf.read_u48::<BigEndian>().map_err(corrupt("header"))?;
+        let purge_seq = buf.read_u48::<BigEndian>().map_err(corrupt("header"))?;
+        let purge_ptr = buf.read_u48::<BigEndian>().map_err(corrupt("header"))?;
+        let seqrootsize = buf.read_u16::<BigEndian>().map_err(corrupt("header"))?;
+        let idrootsize = buf.read_u16::<BigEndian>().map_err(corrupt("header"))?;
+        let localrootsize = buf.read_u16::<BigEndian>().map_err(corrupt("header"))?;
+        let timestamp = if version.has_timestamp() {
+            buf.read_u64::<BigEndian>().map_err(corrupt("header"))?
+        } else {
+            0
+        };
+        Ok(RawFileHeader {
             version,
             update_seq,
             purge_seq,
@@ -36,7 +51,7 @@ impl RawFileHeaderV13 {
             seqrootsize,
             idrootsize,
             localrootsize,
-        }
+        })
     }
 
     pub fn _encode(&self, mut buf: impl io::Write) {
@@ -47,7 +62,9 @@ impl RawFileHeaderV13 {
         buf.write_u16::<BigEndian>(self.seqrootsize).unwrap();
         buf.write_u16::<BigEndian>(self.idrootsize).unwrap();
         buf.write_u16::<BigEndian>(self.localrootsize).unwrap();
-        buf.write_u64::<BigEndian>(self.timestamp).unwrap();
+        if self.version.has_timestamp() {
+            buf.write_u64::<BigEndian>(self.timestamp).unwrap();
+        }
     }
 }
 
@@ -81,23 +98,23 @@ pub fn encode_kv_length(key_length: u32, value_length: u32) -> RawKvLength {
     kv
 }
 
-pub fn read_kv<'a>(buf: &mut Cursor<&'a [u8]>) -> Option<(&'a [u8], &'a [u8])> {
+pub fn read_kv<'a>(buf: &mut Cursor<&'a [u8]>) -> Result<(&'a [u8], &'a [u8])> {
     let mut kv = [0; 5];
-    buf.read_exact(&mut kv).unwrap();
+    buf.read_exact(&mut kv).map_err(corrupt("node"))?;
     let (klen, vlen) = decode_kv_length(&kv);
 
     let key = buf
         .get_ref()
         .get(buf.position() as usize..(buf.position() + klen as u64) as usize)
-        .unwrap();
+        .ok_or(Error::Corrupt("node"))?;
     buf.set_position(buf.position() + klen as u64);
     let value = buf
         .get_ref()
         .get(buf.position() as usize..(buf.position() + vlen as u64) as usize)
-        .unwrap();
+        .ok_or(Error::Corrupt("node"))?;
     buf.set_position(buf.position() + vlen as u64);
 
-    Some((key, value))
+    Ok((key, value))
 }
 
 pub fn write_kv<W: io::Write>(mut buf: W, key: &[u8], value: &[u8]) {
@@ -111,17 +128,7 @@ impl DocInfo {
     pub fn encode_id_index_value<W: io::Write>(&self, mut buf: W) {
         buf.write_u48::<BigEndian>(self.db_seq).unwrap();
         buf.write_u32::<BigEndian>(self.physical_size).unwrap();
-        buf.write_u48::<BigEndian>(
-            self.bp | {
-                // set the first bit of the first byte to 1 if deleted
-                if self.deleted {
-                    1 << 47
-                } else {
-                    0
-                }
-            },
-        )
-        .unwrap();
+        buf.write_u48::<BigEndian>(self.encoded_bp()).unwrap();
         buf.write_u8(self.content_meta.bits()).unwrap();
         buf.write_u48::<BigEndian>(self.rev_seq).unwrap();
         buf.write_all(&self.rev_meta).unwrap();
@@ -130,12 +137,21 @@ impl DocInfo {
     pub fn encode_seq_index_value<W: io::Write>(&self, mut buf: W) {
         let sizes = encode_kv_length(self.id.len() as u32, self.physical_size);
         buf.write_all(&sizes).unwrap();
-        buf.write_u48::<BigEndian>(self.bp).unwrap();
+        buf.write_u48::<BigEndian>(self.encoded_bp()).unwrap();
         buf.write_u8(self.content_meta.bits()).unwrap();
         buf.write_u48::<BigEndian>(self.rev_seq).unwrap();
         buf.write_all(&self.id).unwrap();
         buf.write_all(&self.rev_meta).unwrap();
     }
+
+    /// The body pointer, with the first bit of the first byte set if deleted
+    fn encoded_bp(&self) -> u64 {
+        if self.deleted {
+            self.bp | BP_DELETED_FLAG
+        } else {
+            self.bp
+        }
+    }
 }
 
 #[cfg(test)]
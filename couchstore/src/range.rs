@@ -0,0 +1,503 @@
+use std::{io::Cursor, ops::ControlFlow};
+
+use crate::{
+    btree_read::{read_node_type, NodeType},
+    node_types::read_kv,
+    Db, DocInfo, LocalDoc, NodePointer, Result, TreeFile, MAX_SEQ,
+};
+
+/// Why a range fold stopped before the end of the tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stop {
+    EndOfRange,
+    Callback,
+}
+
+/// A range of keys in a B-tree
+#[derive(Debug, Clone)]
+pub(crate) struct KeyRange {
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    inclusive: bool,
+}
+
+impl KeyRange {
+    pub(crate) fn new(start: &[u8], end: Option<&[u8]>, inclusive: bool) -> Self {
+        Self {
+            start: start.to_vec(),
+            end: end.map(<[u8]>::to_vec),
+            inclusive,
+        }
+    }
+
+    /// The by-seq keys from `start` to `end`, both inclusive
+    pub(crate) fn seqs(start: u64, end: u64) -> Self {
+        // Sequence keys are fixed width big endian so compare bytewise
+        Self::new(
+            &start.min(MAX_SEQ).to_be_bytes()[2..],
+            Some(&end.min(MAX_SEQ).to_be_bytes()[2..]),
+            true,
+        )
+    }
+
+    fn before_start(&self, key: &[u8]) -> bool {
+        key < &self.start[..]
+    }
+
+    fn past_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Some(end) if self.inclusive => key > &end[..],
+            Some(end) => key >= &end[..],
+            None => false,
+        }
+    }
+}
+
+impl Db {
+    /// Call `on_fetch` with every document (including deleted ones) whose id
+    /// is at least `start_key`, in id order. If `end_key` is given the scan
+    /// stops there, including `end_key` itself if `inclusive` is set.
+    ///
+    /// Subtrees that lie entirely before `start_key` are not read. An error
+    /// returned by `on_fetch` stops the scan and is returned.
+    pub fn all_docs(
+        &mut self,
+        start_key: &[u8],
+        end_key: Option<&[u8]>,
+        inclusive: bool,
+        mut on_fetch: impl FnMut(&mut Self, DocInfo) -> Result<()>,
+    ) -> Result<()> {
+        let root_pointer = match &self.header.by_id_root {
+            Some(root) => root.pointer as usize,
+            None => return Ok(()),
+        };
+
+        let range = KeyRange::new(start_key, end_key, inclusive);
+
+        fold_range(self, root_pointer, &range, &mut |db, key, value| {
+            on_fetch(db, DocInfo::decode_id_index_value(key.to_vec(), value)?)?;
+            Ok(ControlFlow::Continue(()))
+        })
+        .map(|_| ())
+    }
+
+    /// Call `on_fetch` with every change with a sequence number between
+    /// `start` and `end`, both inclusive, in sequence order.
+    ///
+    /// `on_fetch` can stop the scan early by returning
+    /// [ControlFlow::Break], in which case the seqno to resume the scan from
+    /// is returned, e.g. to process a large range in batches.
+    pub fn changes_since_range(
+        &mut self,
+        start: u64,
+        end: u64,
+        mut on_fetch: impl FnMut(&mut Self, DocInfo) -> Result<ControlFlow<()>>,
+    ) -> Result<ControlFlow<u64>> {
+        let root_pointer = match self.header.by_seq_root.as_ref() {
+            Some(root) => root.pointer as usize,
+            None => return Ok(ControlFlow::Continue(())),
+        };
+
+        if start > end {
+            return Ok(ControlFlow::Continue(()));
+        }
+
+        let range = KeyRange::seqs(start, end);
+
+        let mut last_seq = start;
+
+        let flow = fold_range(self, root_pointer, &range, &mut |db, key, value| {
+            let docinfo = DocInfo::decode_by_seq_index_value(key, value)?;
+            last_seq = docinfo.db_seq;
+            on_fetch(db, docinfo)
+        })?;
+
+        Ok(match flow {
+            ControlFlow::Break(Stop::Callback) => ControlFlow::Break(last_seq + 1),
+            _ => ControlFlow::Continue(()),
+        })
+    }
+
+    /// Call `on_fetch` with every local document whose id starts with
+    /// `prefix`, in id order, e.g. `_local/collections/` for the collection
+    /// metadata.
+    pub fn local_docs(
+        &mut self,
+        prefix: &[u8],
+        mut on_fetch: impl FnMut(&mut Self, LocalDoc) -> Result<()>,
+    ) -> Result<()> {
+        let root_pointer = match &self.header.local_docs_root {
+            Some(root) => root.pointer as usize,
+            None => return Ok(()),
+        };
+
+        let range = KeyRange::new(prefix, None, false);
+
+        fold_range(self, root_pointer, &range, &mut |db, key, value| {
+            if !key.starts_with(prefix) {
+                return Ok(ControlFlow::Break(()));
+            }
+            on_fetch(db, LocalDoc::new(key, value))?;
+            Ok(ControlFlow::Continue(()))
+        })
+        .map(|_| ())
+    }
+
+    /// Iterate over the documents in the same range as [Db::all_docs],
+    /// reading nodes from disk as they are needed.
+    pub fn iter_range(
+        &mut self,
+        start_key: &[u8],
+        end_key: Option<&[u8]>,
+        inclusive: bool,
+    ) -> RangeIter<'_> {
+        let stack = match &self.header.by_id_root {
+            Some(root) => vec![Frame::Pending(root.pointer as usize)],
+            None => vec![],
+        };
+
+        RangeIter {
+            file: &mut self.file,
+            range: KeyRange::new(start_key, end_key, inclusive),
+            stack,
+        }
+    }
+}
+
+/// Something B-tree nodes can be read from
+pub(crate) trait NodeSource {
+    fn read_node(&mut self, diskpos: usize) -> Result<Vec<u8>>;
+}
+
+impl NodeSource for Db {
+    fn read_node(&mut self, diskpos: usize) -> Result<Vec<u8>> {
+        self.file.read_compressed(diskpos)
+    }
+}
+
+/// Call `on_item` with every key and value in `range` of the tree at
+/// `diskpos`, skipping subtrees that lie entirely before the range.
+pub(crate) fn fold_range<S, F>(
+    src: &mut S,
+    diskpos: usize,
+    range: &KeyRange,
+    on_item: &mut F,
+) -> Result<ControlFlow<Stop>>
+where
+    S: NodeSource,
+    F: FnMut(&mut S, &[u8], &[u8]) -> Result<ControlFlow<()>>,
+{
+    let node = src.read_node(diskpos)?;
+
+    let mut cursor = Cursor::new(node.as_ref());
+
+    let node_type = read_node_type(&mut cursor)?;
+
+    while (cursor.position() as usize) < node.len() {
+        let (key, value) = read_kv(&mut cursor)?;
+
+        // A pointer's key is the last key in its subtree
+        if range.before_start(key) {
+            continue;
+        }
+
+        match node_type {
+            NodeType::KPNode => {
+                let pointer = NodePointer::read_pointer(key, value)?;
+
+                let flow = fold_range(src, pointer.pointer as usize, range, on_item)?;
+
+                if flow.is_break() {
+                    return Ok(flow);
+                }
+
+                if range.past_end(key) {
+                    return Ok(ControlFlow::Break(Stop::EndOfRange));
+                }
+            }
+            NodeType::KVNode => {
+                if range.past_end(key) {
+                    return Ok(ControlFlow::Break(Stop::EndOfRange));
+                }
+
+                if on_item(src, key, value)?.is_break() {
+                    return Ok(ControlFlow::Break(Stop::Callback));
+                }
+            }
+        }
+    }
+
+    Ok(ControlFlow::Continue(()))
+}
+
+#[derive(Debug)]
+enum Frame {
+    /// A node that still has to be read
+    Pending(usize),
+    /// A node that has been read, and the offset of its next item
+    Read {
+        node_type: NodeType,
+        node: Vec<u8>,
+        pos: u64,
+    },
+}
+
+/// Iterator returned by [Db::iter_range]
+#[derive(Debug)]
+pub struct RangeIter<'a> {
+    file: &'a mut TreeFile,
+    range: KeyRange,
+    stack: Vec<Frame>,
+}
+
+impl RangeIter<'_> {
+    fn next_docinfo(&mut self) -> Result<Option<DocInfo>> {
+        while let Some(frame) = self.stack.last_mut() {
+            let (node_type, node, pos) = match frame {
+                Frame::Pending(diskpos) => {
+                    let node = self.file.read_compressed(*diskpos)?;
+                    let mut cursor = Cursor::new(node.as_ref());
+                    let node_type = read_node_type(&mut cursor)?;
+                    let pos = cursor.position();
+                    *frame = Frame::Read {
+                        node_type,
+                        node,
+                        pos,
+                    };
+                    continue;
+                }
+                Frame::Read {
+                    node_type,
+                    node,
+                    pos,
+                } => (*node_type, node, pos),
+            };
+
+            if *pos as usize >= node.len() {
+                self.stack.pop();
+                continue;
+            }
+
+            let mut cursor = Cursor::new(node.as_ref());
+            cursor.set_position(*pos);
+            let (key, value) = read_kv(&mut cursor)?;
+            *pos = cursor.position();
+
+            if self.range.before_start(key) {
+                continue;
+            }
+
+            match node_type {
+                NodeType::KPNode => {
+                    let pointer = NodePointer::read_pointer(key, value)?;
+
+                    // Nothing after this subtree can be in range
+                    if self.range.past_end(key) {
+                        *pos = node.len() as u64;
+                    }
+
+                    self.stack.push(Frame::Pending(pointer.pointer as usize));
+                }
+                NodeType::KVNode => {
+                    if self.range.past_end(key) {
+                        self.stack.clear();
+                        return Ok(None);
+                    }
+
+                    return DocInfo::decode_id_index_value(key.to_vec(), value).map(Some);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Iterator for RangeIter<'_> {
+    type Item = Result<DocInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.next_docinfo().transpose();
+
+        if let Some(Err(_)) = next {
+            // Don't carry on from a corrupt node
+            self.stack.clear();
+        }
+
+        next
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ops::ControlFlow;
+
+    use crate::{
+        test::temp_db_path, DBOpenOptions, Db, Doc, DocInfo, LocalDoc, Reader, Result, SaveOptions,
+    };
+
+    fn all_docs_ids(db: &mut Db, start: &str, end: Option<&str>, inclusive: bool) -> Vec<String> {
+        let mut ids = vec![];
+        db.all_docs(
+            start.as_bytes(),
+            end.map(str::as_bytes),
+            inclusive,
+            |_, info| {
+                ids.push(String::from_utf8(info.id).unwrap());
+                Ok(())
+            },
+        )
+        .unwrap();
+        ids
+    }
+
+    fn iter_range_ids(db: &mut Db, start: &str, end: Option<&str>, inclusive: bool) -> Vec<String> {
+        db.iter_range(start.as_bytes(), end.map(str::as_bytes), inclusive)
+            .map(|info| info.map(|info| String::from_utf8(info.id).unwrap()))
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_ranges() {
+        let path = temp_db_path("ranges");
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        // Enough keys for a tree a few levels deep
+        let keys = (0..5000).map(|i| format!("key_{i:05}")).collect::<Vec<_>>();
+        let docs = keys
+            .iter()
+            .map(|key| {
+                let doc = Doc {
+                    id: key.clone().into_bytes(),
+                    data: b"{}".to_vec(),
+                };
+                let info = DocInfo {
+                    id: key.clone().into_bytes(),
+                    ..Default::default()
+                };
+                (Some(doc), info)
+            })
+            .collect::<Vec<_>>();
+        db.save_documents(&docs, SaveOptions::empty()).unwrap();
+        db.commit().unwrap();
+
+        let ranges = [
+            ("", None, false),
+            ("key_01000", Some("key_01100"), false),
+            ("key_01000", Some("key_01100"), true),
+            ("key_01000x", Some("key_04999"), true),
+            ("key_049", None, false),
+            ("key_02", Some("key_03"), false),
+            ("z", None, false),
+            ("key_03000", Some("key_03000"), false),
+        ];
+
+        for (start, end, inclusive) in ranges {
+            let expected = keys
+                .iter()
+                .filter(|key| key.as_str() >= start)
+                .filter(|key| match end {
+                    Some(end) if inclusive => key.as_str() <= end,
+                    Some(end) => key.as_str() < end,
+                    None => true,
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+
+            assert_eq!(all_docs_ids(&mut db, start, end, inclusive), expected);
+            assert_eq!(iter_range_ids(&mut db, start, end, inclusive), expected);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_changes_since_range() {
+        let opts = DBOpenOptions::default().read_only();
+        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts).unwrap();
+
+        let mut seqs = vec![];
+        let flow = db
+            .changes_since_range(10, 19, |_, info| {
+                seqs.push(info.db_seq);
+                Ok(ControlFlow::Continue(()))
+            })
+            .unwrap();
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(seqs, (10..=19).collect::<Vec<_>>());
+
+        // Read everything in batches of 30, resuming after each pause
+        let mut seqs = vec![];
+        let mut start = 0;
+        while let ControlFlow::Break(next) = db
+            .changes_since_range(start, u64::MAX, |_, info| {
+                seqs.push(info.db_seq);
+                if seqs.len() % 30 == 0 {
+                    Ok(ControlFlow::Break(()))
+                } else {
+                    Ok(ControlFlow::Continue(()))
+                }
+            })
+            .unwrap()
+        {
+            start = next;
+        }
+        assert_eq!(seqs, (1..=97).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_local_docs() {
+        let path = temp_db_path("local_docs");
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        for id in [
+            "_local/collections/dropped",
+            "_local/collections/manifest",
+            "_local/collections/open",
+            "_local/scope/open",
+            "_local/vbstate",
+        ] {
+            db.save_local_document(LocalDoc::new(id, "{}")).unwrap();
+        }
+        db.save_local_document(LocalDoc::deleted("_local/collections/dropped"))
+            .unwrap();
+        db.commit().unwrap();
+
+        let mut ids = vec![];
+        db.local_docs(b"_local/collections/", |_, doc| {
+            assert!(!doc.is_deleted());
+            assert_eq!(doc.json.as_deref(), Some(&b"{}"[..]));
+            ids.push(String::from_utf8(doc.id).unwrap());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            ids,
+            ["_local/collections/manifest", "_local/collections/open"]
+        );
+        assert!(db
+            .open_local_document("_local/collections/dropped")
+            .is_err());
+
+        let mut count = 0;
+        db.local_docs(b"", |_, _| {
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 4);
+
+        let reader = Reader::open(&path).unwrap();
+        let mut ids = vec![];
+        reader
+            .local_docs(b"_local/scope/", |doc| {
+                ids.push(doc.id);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(ids, [b"_local/scope/open".to_vec()]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,310 @@
+use std::{fs::File, ops::ControlFlow, path::Path};
+
+use crate::{
+    crc::CrcMode,
+    file_read,
+    range::{fold_range, KeyRange, NodeSource, Stop},
+    Doc, DocInfo, Error, Header, LocalDoc, OpenOptions, Result,
+};
+
+/// A read-only view of a database file as of its most recent header.
+///
+/// Unlike [Db](crate::Db) every method takes `&self`: reads are positioned
+/// (`pread`) rather than seek-then-read, so a single `Reader` can be shared
+/// between threads and opened once rather than per lookup. A `Reader` never
+/// creates or writes to the file, and doesn't see commits made after it was
+/// opened.
+#[derive(Debug)]
+pub struct Reader {
+    file: File,
+    header: Header,
+}
+
+impl Reader {
+    pub fn open(filename: impl AsRef<Path>) -> Result<Reader> {
+        let file = File::open(filename)?;
+
+        let len = file.metadata()?.len() as usize;
+
+        if len == 0 {
+            return Err(Error::NoHeader);
+        }
+
+        let header = Header::find(&file, len - 1)?;
+
+        Ok(Reader { file, header })
+    }
+
+    /// Open the file as of the header at `pos`, see
+    /// [Db::open_at_header](crate::Db::open_at_header)
+    pub fn open_at_header(filename: impl AsRef<Path>, pos: u64) -> Result<Reader> {
+        let file = File::open(filename)?;
+
+        let header = Header::at_pos(&file, pos)?;
+
+        Ok(Reader { file, header })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Every valid header in the file, newest first. The first is the header
+    /// the reader was opened at.
+    pub fn headers(&self) -> Result<Vec<Header>> {
+        self.header.history(&self.file)
+    }
+
+    pub(crate) fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub(crate) fn crc_mode(&self) -> CrcMode {
+        self.header.disk_version.crc_mode()
+    }
+
+    /// Number of non-deleted documents in the database
+    pub fn doc_count(&self) -> Result<u64> {
+        Ok(self.header.by_id_reduce()?.not_deleted)
+    }
+
+    /// Number of deleted documents (tombstones) in the database
+    pub fn deleted_count(&self) -> Result<u64> {
+        Ok(self.header.by_id_reduce()?.deleted)
+    }
+
+    pub fn docinfo_by_id(&self, key: impl AsRef<[u8]>) -> Result<DocInfo> {
+        let key = key.as_ref();
+
+        let mut docinfo = None;
+
+        self.all_docs(key, Some(key), true, |info| {
+            docinfo = Some(info);
+            Ok(())
+        })?;
+
+        docinfo.ok_or(Error::DocNotFound)
+    }
+
+    pub fn docinfo_by_sequence(&self, sequence: u64) -> Result<DocInfo> {
+        let mut docinfo = None;
+
+        self.changes_since_range(sequence, sequence, |info| {
+            docinfo = Some(info);
+            Ok(ControlFlow::Break(()))
+        })
+        .map(|_| ())?;
+
+        docinfo.ok_or(Error::DocNotFound)
+    }
+
+    pub fn open_doc_with_docinfo(&self, docinfo: &DocInfo, options: OpenOptions) -> Result<Doc> {
+        file_read::read_doc(&self.file, docinfo, options, self.crc_mode())
+    }
+
+    pub fn open_local_document(&self, id: impl AsRef<[u8]>) -> Result<LocalDoc> {
+        let id = id.as_ref();
+
+        let root_pointer = match &self.header.local_docs_root {
+            Some(root) => root.pointer as usize,
+            None => return Err(Error::DocNotFound),
+        };
+
+        let range = KeyRange::new(id, Some(id), true);
+
+        let mut local_doc = None;
+
+        fold_range(&mut &*self, root_pointer, &range, &mut |_, key, value| {
+            local_doc = Some(LocalDoc::new(key, value));
+            Ok(ControlFlow::Break(()))
+        })
+        .map(|_| ())?;
+
+        local_doc.ok_or(Error::DocNotFound)
+    }
+
+    /// See [Db::local_docs](crate::Db::local_docs)
+    pub fn local_docs(
+        &self,
+        prefix: &[u8],
+        mut on_fetch: impl FnMut(LocalDoc) -> Result<()>,
+    ) -> Result<()> {
+        let root_pointer = match &self.header.local_docs_root {
+            Some(root) => root.pointer as usize,
+            None => return Ok(()),
+        };
+
+        let range = KeyRange::new(prefix, None, false);
+
+        fold_range(&mut &*self, root_pointer, &range, &mut |_, key, value| {
+            if !key.starts_with(prefix) {
+                return Ok(ControlFlow::Break(()));
+            }
+            on_fetch(LocalDoc::new(key, value))?;
+            Ok(ControlFlow::Continue(()))
+        })
+        .map(|_| ())
+    }
+
+    /// See [Db::all_docs](crate::Db::all_docs)
+    pub fn all_docs(
+        &self,
+        start_key: &[u8],
+        end_key: Option<&[u8]>,
+        inclusive: bool,
+        mut on_fetch: impl FnMut(DocInfo) -> Result<()>,
+    ) -> Result<()> {
+        let root_pointer = match &self.header.by_id_root {
+            Some(root) => root.pointer as usize,
+            None => return Ok(()),
+        };
+
+        let range = KeyRange::new(start_key, end_key, inclusive);
+
+        fold_range(&mut &*self, root_pointer, &range, &mut |_, key, value| {
+            on_fetch(DocInfo::decode_id_index_value(key.to_vec(), value)?)?;
+            Ok(ControlFlow::Continue(()))
+        })
+        .map(|_| ())
+    }
+
+    /// See [Db::changes_since_range](crate::Db::changes_since_range)
+    pub fn changes_since_range(
+        &self,
+        start: u64,
+        end: u64,
+        mut on_fetch: impl FnMut(DocInfo) -> Result<ControlFlow<()>>,
+    ) -> Result<ControlFlow<u64>> {
+        let root_pointer = match &self.header.by_seq_root {
+            Some(root) => root.pointer as usize,
+            None => return Ok(ControlFlow::Continue(())),
+        };
+
+        if start > end {
+            return Ok(ControlFlow::Continue(()));
+        }
+
+        let range = KeyRange::seqs(start, end);
+
+        let mut last_seq = start;
+
+        let flow = fold_range(&mut &*self, root_pointer, &range, &mut |_, key, value| {
+            let docinfo = DocInfo::decode_by_seq_index_value(key, value)?;
+            last_seq = docinfo.db_seq;
+            on_fetch(docinfo)
+        })?;
+
+        Ok(match flow {
+            ControlFlow::Break(Stop::Callback) => ControlFlow::Break(last_seq + 1),
+            _ => ControlFlow::Continue(()),
+        })
+    }
+}
+
+impl NodeSource for &Reader {
+    fn read_node(&mut self, diskpos: usize) -> Result<Vec<u8>> {
+        file_read::read_compressed(&self.file, diskpos, self.crc_mode())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ops::ControlFlow;
+
+    use super::Reader;
+    use crate::{test::temp_db_path, DBOpenOptions, Db, Error, OpenOptions};
+
+    const TRAVEL_SAMPLE: &str = "../test-data/travel-sample/0.couch.1";
+
+    #[test]
+    fn test_reader_matches_db() {
+        let reader = Reader::open(TRAVEL_SAMPLE).unwrap();
+        let mut db = Db::open(TRAVEL_SAMPLE, DBOpenOptions::default().read_only()).unwrap();
+
+        assert_eq!(reader.doc_count().unwrap(), db.doc_count().unwrap());
+
+        let mut seqs = vec![];
+        let flow = reader
+            .changes_since_range(0, u64::MAX, |info| {
+                seqs.push(info.db_seq);
+                Ok(ControlFlow::Continue(()))
+            })
+            .unwrap();
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert!(!seqs.is_empty());
+
+        // Look every document up from several threads sharing one reader
+        std::thread::scope(|scope| {
+            for chunk in seqs.chunks(seqs.len().div_ceil(4)) {
+                let reader = &reader;
+                scope.spawn(move || {
+                    for &seq in chunk {
+                        let by_seq = reader.docinfo_by_sequence(seq).unwrap();
+                        let by_id = reader.docinfo_by_id(&by_seq.id).unwrap();
+                        assert_eq!(by_id.db_seq, seq);
+                        reader
+                            .open_doc_with_docinfo(&by_id, OpenOptions::DECOMPRESS_DOC_BODIES)
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        for &seq in seqs.iter().take(50) {
+            let from_reader = reader.docinfo_by_sequence(seq).unwrap();
+            let from_db = db.docinfo_by_sequence(seq).unwrap();
+            assert_eq!(from_reader.id, from_db.id);
+            assert_eq!(from_reader.rev_meta, from_db.rev_meta);
+        }
+
+        assert_eq!(
+            reader.open_local_document("_local/vbstate").unwrap().json,
+            db.open_local_document("_local/vbstate").unwrap().json
+        );
+        assert!(matches!(
+            reader.docinfo_by_id("no such key"),
+            Err(Error::DocNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_reader_headers() {
+        let path = temp_db_path("reader_headers");
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        for i in 0..3 {
+            db.set(format!("key_{i}").into_bytes(), b"{}".to_vec())
+                .unwrap();
+            db.commit().unwrap();
+        }
+
+        let reader = Reader::open(&path).unwrap();
+        let headers = reader.headers().unwrap();
+
+        // One header from creating the file, then one per commit
+        let seqs = headers.iter().map(|h| h.update_seq).collect::<Vec<_>>();
+        assert_eq!(seqs, [3, 2, 1, 0]);
+        assert_eq!(headers[0].position(), reader.header().position());
+        assert!(headers
+            .windows(2)
+            .all(|h| h[0].position() > h[1].position()));
+
+        // The header, three bodies and a root node for each of the two indexes
+        assert_eq!(reader.verify().unwrap(), 1 + 3 + 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reader_never_creates() {
+        let path = temp_db_path("reader_never_creates");
+
+        assert!(matches!(Reader::open(&path), Err(Error::Io(_))));
+        assert!(!path.exists());
+
+        std::fs::File::create(&path).unwrap();
+        assert!(matches!(Reader::open(&path), Err(Error::NoHeader)));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
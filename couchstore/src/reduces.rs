@@ -0,0 +1,121 @@
+//! Reduce functions for the by-id and by-seq trees.
+//!
+//! Every node pointer in these trees carries a reduce value summarising the
+//! subtree below it, which lets counts be answered without visiting the
+//! leaves. The encodings match the ones written by couchstore.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{btree_modify::Node, error::corrupt, Error, Result, BP_DELETED_FLAG};
+
+/// Reduce function applied to the items of a node before it is written.
+pub type ReduceFn = fn(&[Node]) -> Result<Vec<u8>>;
+
+/// Reduce value of the by-id tree: 40 bit document count, 40 bit deleted
+/// count and 48 bit total physical size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ByIdReduce {
+    pub not_deleted: u64,
+    pub deleted: u64,
+    pub size: u64,
+}
+
+impl ByIdReduce {
+    pub const ON_DISK_SIZE: usize = 16;
+
+    pub fn decode(mut buf: &[u8]) -> Result<ByIdReduce> {
+        let not_deleted = buf
+            .read_uint::<BigEndian>(5)
+            .map_err(corrupt("by-id reduce"))?;
+        let deleted = buf
+            .read_uint::<BigEndian>(5)
+            .map_err(corrupt("by-id reduce"))?;
+        let size = buf
+            .read_u48::<BigEndian>()
+            .map_err(corrupt("by-id reduce"))?;
+        Ok(ByIdReduce {
+            not_deleted,
+            deleted,
+            size,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::ON_DISK_SIZE);
+        buf.write_uint::<BigEndian>(self.not_deleted, 5).unwrap();
+        buf.write_uint::<BigEndian>(self.deleted, 5).unwrap();
+        buf.write_u48::<BigEndian>(self.size).unwrap();
+        buf
+    }
+}
+
+/// Reduce value of the by-seq tree: 40 bit count of entries.
+pub(crate) fn decode_by_seq_reduce(mut buf: &[u8]) -> Result<u64> {
+    buf.read_uint::<BigEndian>(5)
+        .map_err(corrupt("by-seq reduce"))
+}
+
+fn encode_by_seq_reduce(count: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(5);
+    buf.write_uint::<BigEndian>(count, 5).unwrap();
+    buf
+}
+
+pub(crate) fn by_id_reduce(leaves: &[Node]) -> Result<Vec<u8>> {
+    let mut reduce = ByIdReduce::default();
+
+    for leaf in leaves {
+        // Skip the 48 bit seqno to get to the size and then the bp
+        let mut value = leaf.data.get(6..).ok_or(Error::Corrupt("by-id value"))?;
+        let size = value
+            .read_u32::<BigEndian>()
+            .map_err(corrupt("by-id value"))?;
+        let bp = value
+            .read_u48::<BigEndian>()
+            .map_err(corrupt("by-id value"))?;
+
+        if bp & BP_DELETED_FLAG != 0 {
+            reduce.deleted += 1;
+        } else {
+            reduce.not_deleted += 1;
+        }
+        reduce.size += u64::from(size);
+    }
+
+    Ok(reduce.encode())
+}
+
+pub(crate) fn by_id_rereduce(pointers: &[Node]) -> Result<Vec<u8>> {
+    let mut reduce = ByIdReduce::default();
+
+    for pointer in pointers {
+        let child = ByIdReduce::decode(child_reduce_value(pointer))?;
+        reduce.not_deleted += child.not_deleted;
+        reduce.deleted += child.deleted;
+        reduce.size += child.size;
+    }
+
+    Ok(reduce.encode())
+}
+
+pub(crate) fn by_seq_reduce(leaves: &[Node]) -> Result<Vec<u8>> {
+    Ok(encode_by_seq_reduce(leaves.len() as u64))
+}
+
+pub(crate) fn by_seq_rereduce(pointers: &[Node]) -> Result<Vec<u8>> {
+    let mut count = 0;
+
+    for pointer in pointers {
+        count += decode_by_seq_reduce(child_reduce_value(pointer))?;
+    }
+
+    Ok(encode_by_seq_reduce(count))
+}
+
+fn child_reduce_value(pointer: &Node) -> &[u8] {
+    pointer
+        .pointer
+        .as_ref()
+        .map(|pointer| &pointer.reduce_value[..])
+        .unwrap_or_default()
+}
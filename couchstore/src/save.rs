@@ -1,8 +1,10 @@
+use std::{cmp::Ordering, collections::HashSet};
+
 use crate::{
     btree_modify::{
         CouchfileModifyAction, CouchfileModifyActionType, CouchfileModifyRequest, UpdateIdContext,
     },
-    ContentMetaFlag, Db, Doc, DocInfo, SaveOptions,
+    reduces, ContentMetaFlag, Db, Doc, DocInfo, Error, Result, SaveOptions,
 };
 
 impl Db {
@@ -11,16 +13,28 @@ impl Db {
         doc: Option<Doc>,
         info: DocInfo,
         options: SaveOptions,
-    ) {
-        self.save_documents_and_callback(doc.map(|doc| vec![doc]), vec![info], options);
+    ) -> Result<()> {
+        self.save_documents(&[(doc, info)], options)
     }
 
-    fn save_documents_and_callback(
+    /// Save a batch of documents, updating each index with a single B-tree
+    /// modification. A `None` doc, or a `DocInfo` with `deleted` set, saves a
+    /// tombstone. The `rev_seq`, `rev_meta` and `content_meta` of each
+    /// `DocInfo` are stored as given, and the `db_seq` too if
+    /// [SaveOptions::SEQUENCE_AS_IS] is set; otherwise new sequence numbers
+    /// are assigned in order.
+    ///
+    /// If the same id appears more than once only the last revision is kept.
+    /// Nothing is durable until [Db::commit] is called.
+    pub fn save_documents(
         &mut self,
-        docs: Option<Vec<Doc>>,
-        mut infos: Vec<DocInfo>,
+        docs: &[(Option<Doc>, DocInfo)],
         options: SaveOptions,
-    ) {
+    ) -> Result<()> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
         // TODO: Reduce allocations, couchstore uses 1 buffer for all the data
         let mut ids: Vec<Vec<u8>> = Vec::new();
         let mut seqs: Vec<u64> = Vec::new();
@@ -28,10 +42,10 @@ impl Db {
         let mut seq_idx: Vec<Vec<u8>> = Vec::new();
 
         let mut seq = self.header.update_seq;
+        let mut max_seq = seq;
 
-        for i in 0..infos.len() {
-            let info = &mut infos[i];
-            let mut doc = None;
+        for (doc, info) in docs {
+            let mut info = info.clone();
 
             if options.contains(SaveOptions::SEQUENCE_AS_IS) {
                 seq = info.db_seq;
@@ -39,25 +53,24 @@ impl Db {
                 seq += 1;
                 info.db_seq = seq;
             }
-
-            if let Some(docs) = &docs {
-                doc = Some(&docs[i])
-            }
+            max_seq = max_seq.max(seq);
 
             self.add_doc_to_update_list(
-                doc,
-                info,
+                doc.as_ref(),
+                &info,
                 &mut seqs,
                 &mut ids,
                 &mut seq_idx,
                 &mut id_idx,
                 options,
-            );
+            )?;
         }
 
-        self.update_indexes(seqs, ids, seq_idx, id_idx, infos.len());
+        self.update_indexes(seqs, ids, seq_idx, id_idx, docs.len())?;
+
+        self.header.update_seq = max_seq;
 
-        self.header.update_seq = seq;
+        Ok(())
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -70,7 +83,7 @@ impl Db {
         seq_idx: &mut Vec<Vec<u8>>,
         id_idx: &mut Vec<Vec<u8>>,
         mut options: SaveOptions,
-    ) {
+    ) -> Result<()> {
         let mut updated = info.clone();
 
         seqs.push(updated.db_seq);
@@ -83,7 +96,7 @@ impl Db {
                 options.remove(SaveOptions::COMPRESS_DOC_BODIES);
             }
 
-            self.write_doc(doc, &mut updated.bp, &mut disk_size, options);
+            self.write_doc(doc, &mut updated.bp, &mut disk_size, options)?;
 
             updated.physical_size = disk_size;
         } else {
@@ -102,45 +115,105 @@ impl Db {
 
         id_idx.push(id_index_value);
         seq_idx.push(seq_index_value);
+
+        Ok(())
     }
 
     fn update_indexes(
         &mut self,
-        _seqs: Vec<u64>,
+        seqs: Vec<u64>,
         ids: Vec<Vec<u8>>,
-        _seq_idx: Vec<Vec<u8>>,
+        seq_idx: Vec<Vec<u8>>,
         id_idx: Vec<Vec<u8>>,
-        _num_docs: usize,
-    ) {
-        let mut id_keys_and_data = ids.into_iter().zip(id_idx).collect::<Vec<_>>();
-        id_keys_and_data.sort_unstable_by(|(key_a, _), (key_b, _)| key_a.cmp(key_b));
+        num_docs: usize,
+    ) -> Result<()> {
+        // Sort by id, keeping the batch order of duplicates so that only the
+        // last revision of each id is saved. The seqnos of the earlier
+        // revisions are left out of the by-seq index.
+        let mut id_keys_and_data = ids
+            .into_iter()
+            .zip(id_idx)
+            .zip(seqs.iter().copied())
+            .collect::<Vec<_>>();
+        id_keys_and_data.sort_by(|((key_a, _), _), ((key_b, _), _)| key_a.cmp(key_b));
+
+        let mut superseded = HashSet::new();
+        id_keys_and_data.dedup_by(|later, earlier| {
+            if later.0 .0 != earlier.0 .0 {
+                return false;
+            }
+            superseded.insert(earlier.1);
+            std::mem::swap(later, earlier);
+            true
+        });
 
         let id_actions = id_keys_and_data
             .into_iter()
-            .map(|(key, data)| CouchfileModifyAction {
+            .map(|((key, data), _)| CouchfileModifyAction {
                 key,
                 data: Some(data),
                 action_type: CouchfileModifyActionType::FetchInsert,
             })
             .collect::<Vec<_>>();
 
-        let id_req = CouchfileModifyRequest {
+        let mut id_req = CouchfileModifyRequest {
             actions: id_actions,
             context: UpdateIdContext {
-                seq_actions: vec![],
+                seq_actions: Vec::with_capacity(num_docs * 2),
             },
             kv_chunk_threshold: self.opts.kv_chunk_threshold,
             kp_chunk_threshold: self.opts.kp_chunk_threshold,
+            reduce: Some(reduces::by_id_reduce),
+            rereduce: Some(reduces::by_id_rereduce),
         };
 
         let new_id_root = self
             .file
-            .modify_btree(id_req, self.header.by_id_root.clone());
+            .modify_btree(&mut id_req, self.header.by_id_root.clone())?;
+
+        // Fetching the old by-id values queued a remove for the previous
+        // seqno of every overwritten key. Add the new seqnos alongside them.
+        let mut seq_actions = id_req.context.seq_actions;
+
+        seq_actions.extend(
+            seqs.into_iter()
+                .zip(seq_idx)
+                .filter(|(seq, _)| !superseded.contains(seq))
+                .map(|(seq, data)| CouchfileModifyAction {
+                    key: seq.to_be_bytes()[2..].to_vec(),
+                    data: Some(data),
+                    action_type: CouchfileModifyActionType::Insert,
+                }),
+        );
+
+        seq_actions.sort_by(seq_action_compare);
+
+        let mut seq_req = CouchfileModifyRequest {
+            actions: seq_actions,
+            context: (),
+            kv_chunk_threshold: self.opts.kv_chunk_threshold,
+            kp_chunk_threshold: self.opts.kp_chunk_threshold,
+            reduce: Some(reduces::by_seq_reduce),
+            rereduce: Some(reduces::by_seq_rereduce),
+        };
+
+        let new_seq_root = self
+            .file
+            .modify_btree(&mut seq_req, self.header.by_seq_root.clone())?;
 
         self.header.by_id_root = new_id_root;
+        self.header.by_seq_root = new_seq_root;
+
+        Ok(())
     }
 
-    fn write_doc(&mut self, doc: &Doc, bp: &mut u64, disk_size: &mut u32, options: SaveOptions) {
+    fn write_doc(
+        &mut self,
+        doc: &Doc,
+        bp: &mut u64,
+        disk_size: &mut u32,
+        options: SaveOptions,
+    ) -> Result<()> {
         if options.contains(SaveOptions::COMPRESS_DOC_BODIES) {
             self.file.db_write_buf_compressed(&doc.data, bp, disk_size)
         } else {
@@ -148,3 +221,14 @@ impl Db {
         }
     }
 }
+
+/// Sort by seqno, and if a seqno is both removed and inserted (a document
+/// re-saved with `SEQUENCE_AS_IS`) make sure the remove is applied first.
+fn seq_action_compare(a: &CouchfileModifyAction, b: &CouchfileModifyAction) -> Ordering {
+    let rank = |action: &CouchfileModifyAction| match action.action_type {
+        CouchfileModifyActionType::Remove => 0,
+        _ => 1,
+    };
+
+    a.key.cmp(&b.key).then_with(|| rank(a).cmp(&rank(b)))
+}
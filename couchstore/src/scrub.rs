@@ -0,0 +1,249 @@
+use std::{collections::HashSet, io::Cursor};
+
+use crate::{
+    btree_read::{read_node_type, NodeType},
+    constants::COUCH_BLOCK_SIZE,
+    crc::CrcMode,
+    file_read,
+    node_types::read_kv,
+    DocInfo, Error, Header, NodePointer, Reader, Result,
+};
+
+/// The result of [Reader::scrub]
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    /// Number of valid headers in the file
+    pub headers: u64,
+    /// Number of chunks whose checksum was verified. A chunk reachable from
+    /// several headers is only read once.
+    pub chunks: u64,
+    /// Every problem found. Corrupt headers and chunks are reported as
+    /// [Error::Checksum] with their offset.
+    pub errors: Vec<Error>,
+}
+
+impl ScrubReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl Reader {
+    /// Read every index node and document body reachable from the header,
+    /// checking the checksum of each. Returns the number of chunks read,
+    /// including the header, or the first error found.
+    pub fn verify(&self) -> Result<u64> {
+        let mut scrub = Scrub::new(self);
+
+        scrub.check_header(self.header());
+
+        match scrub.report.errors.into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(scrub.report.chunks + 1),
+        }
+    }
+
+    /// Check the whole file: every header block, and every chunk reachable
+    /// from any valid header rather than just the one the reader was opened
+    /// at. Unlike [Reader::verify] this carries on past corruption to report
+    /// everything that is wrong. Only I/O errors are returned as errors.
+    pub fn scrub(&self) -> Result<ScrubReport> {
+        let mut scrub = Scrub::new(self);
+
+        let len = self.file().metadata()?.len() as usize;
+
+        for pos in (0..len).step_by(COUCH_BLOCK_SIZE) {
+            match Header::read_at_pos(self.file(), pos) {
+                Ok(Some(header)) => {
+                    scrub.report.headers += 1;
+                    scrub.check_header(&header);
+                }
+                // A data block
+                Ok(None) => {}
+                Err(Error::Io(err)) => return Err(Error::Io(err)),
+                Err(err) => scrub.report.errors.push(err),
+            }
+        }
+
+        Ok(scrub.report)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tree {
+    ById,
+    Other,
+}
+
+struct Scrub<'a> {
+    reader: &'a Reader,
+    crc_mode: CrcMode,
+    /// Offsets of the chunks already checked
+    seen: HashSet<usize>,
+    report: ScrubReport,
+}
+
+impl<'a> Scrub<'a> {
+    fn new(reader: &'a Reader) -> Self {
+        Self {
+            reader,
+            crc_mode: reader.crc_mode(),
+            seen: HashSet::new(),
+            report: ScrubReport::default(),
+        }
+    }
+
+    fn check_header(&mut self, header: &Header) {
+        if let Some(root) = &header.by_id_root {
+            self.check_tree(root.pointer as usize, Tree::ById);
+        }
+        for root in [&header.by_seq_root, &header.local_docs_root]
+            .into_iter()
+            .flatten()
+        {
+            self.check_tree(root.pointer as usize, Tree::Other);
+        }
+    }
+
+    fn check_tree(&mut self, pos: usize, tree: Tree) {
+        if !self.seen.insert(pos) {
+            return;
+        }
+
+        let result =
+            file_read::read_compressed(self.reader.file(), pos, self.crc_mode).and_then(|node| {
+                self.report.chunks += 1;
+                self.check_node(&node, tree)
+            });
+
+        if let Err(err) = result {
+            self.report.errors.push(err);
+        }
+    }
+
+    fn check_node(&mut self, node: &[u8], tree: Tree) -> Result<()> {
+        let mut cursor = Cursor::new(node);
+
+        let node_type = read_node_type(&mut cursor)?;
+
+        while (cursor.position() as usize) < node.len() {
+            let (key, value) = read_kv(&mut cursor)?;
+
+            match node_type {
+                NodeType::KPNode => {
+                    let pointer = NodePointer::read_pointer(key, value)?;
+                    self.check_tree(pointer.pointer as usize, tree);
+                }
+                NodeType::KVNode if tree == Tree::ById => {
+                    let docinfo = DocInfo::decode_id_index_value(key.to_vec(), value)?;
+                    if docinfo.bp != 0 {
+                        self.check_chunk(docinfo.bp as usize);
+                    }
+                }
+                NodeType::KVNode => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_chunk(&mut self, pos: usize) {
+        if !self.seen.insert(pos) {
+            return;
+        }
+
+        match file_read::read_chunk(self.reader.file(), pos, self.crc_mode) {
+            Ok(_) => self.report.chunks += 1,
+            Err(err) => self.report.errors.push(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Reader;
+    use crate::{test::temp_db_path, DBOpenOptions, Db, DiskVersion, Error};
+
+    #[test]
+    fn test_verify_finds_corruption() {
+        let path = temp_db_path("verify_corruption");
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        db.set(b"key".to_vec(), b"some document body".to_vec())
+            .unwrap();
+        db.commit().unwrap();
+        db.set(b"key".to_vec(), b"another document body".to_vec())
+            .unwrap();
+        db.commit().unwrap();
+        let bp = db.docinfo_by_id(b"key".to_vec()).unwrap().bp;
+        drop(db);
+
+        let reader = Reader::open(&path).unwrap();
+        assert!(reader.verify().is_ok());
+        let report = reader.scrub().unwrap();
+        assert!(report.is_ok());
+        // Three headers, two bodies and the root nodes of both indexes in
+        // each of the two commits
+        assert_eq!(report.headers, 3);
+        assert_eq!(report.chunks, 2 + 2 * 2);
+
+        // Flip a byte of the current (compressed) body, after its length
+        // and CRC
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[bp as usize + 10] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let reader = Reader::open(&path).unwrap();
+        assert!(matches!(
+            reader.verify(),
+            Err(Error::Checksum { offset }) if offset == bp
+        ));
+
+        // Corrupt the first commit's header too, which opening the file
+        // doesn't notice
+        let first_commit = reader.headers().unwrap()[1].position() as usize;
+        bytes[first_commit + 12] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let reader = Reader::open(&path).unwrap();
+        let report = reader.scrub().unwrap();
+        assert_eq!(report.headers, 2);
+        let offsets = report
+            .errors
+            .iter()
+            .map(|err| match err {
+                Error::Checksum { offset } => *offset,
+                err => panic!("unexpected error {err}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, [first_commit as u64, bp]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_crc32_file() {
+        // Written by an old version of couchstore, before CRC32C
+        let reader = Reader::open("../test-data/travel-sample/master.couch.1").unwrap();
+        assert_eq!(reader.header().disk_version(), DiskVersion::Eleven);
+        assert_eq!(reader.header().timestamp(), 0);
+        assert!(reader.scrub().unwrap().is_ok());
+
+        // Appending to it keeps using CRC32 and version 11 headers
+        let path = temp_db_path("crc32_file");
+        std::fs::copy("../test-data/travel-sample/master.couch.1", &path).unwrap();
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        db.set(b"key".to_vec(), b"{}".to_vec()).unwrap();
+        db.commit().unwrap();
+        drop(db);
+
+        let reader = Reader::open(&path).unwrap();
+        assert_eq!(reader.header().disk_version(), DiskVersion::Eleven);
+        assert_eq!(reader.headers().unwrap().len(), 2);
+        assert!(reader.docinfo_by_id("key").is_ok());
+        let report = reader.scrub().unwrap();
+        assert!(report.is_ok(), "{:?}", report.errors);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,7 +2,7 @@ use crate::constants::COUCH_BLOCK_SIZE;
 use std::time::SystemTime;
 
 pub(crate) fn align_to_next_block(offset: usize) -> usize {
-    if offset % COUCH_BLOCK_SIZE != 0 {
+    if !offset.is_multiple_of(COUCH_BLOCK_SIZE) {
         return offset + COUCH_BLOCK_SIZE - (offset % COUCH_BLOCK_SIZE);
     }
     offset
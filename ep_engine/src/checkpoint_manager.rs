@@ -0,0 +1,483 @@
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use crate::item::Item;
+
+/// Name of the cursor the flusher reads from. It is never dropped, as the
+/// items it hasn't reached yet aren't on disk.
+pub const PERSISTENCE_CURSOR: &str = "persistence";
+
+/// Items in the open checkpoint before it is closed and a new one opened
+const MAX_ITEMS_PER_CHECKPOINT: usize = 10_000;
+
+/// Memory the checkpoints of a vBucket may use before closed checkpoints are
+/// removed, dropping the cursors holding on to them if necessary
+const CHECKPOINT_MEMORY_LIMIT: usize = 32 * 1024 * 1024;
+
+/// An entry in a checkpoint
+#[derive(Debug)]
+pub enum QueuedItem {
+    /// A mutation or deletion, with its seqno assigned
+    Item(Item),
+    /// The vBucket's state changed and needs persisting
+    SetVBucketState,
+}
+
+impl QueuedItem {
+    fn mem_size(&self) -> usize {
+        let size = std::mem::size_of::<Self>();
+        match self {
+            QueuedItem::Item(item) => {
+                size + item.key.len() + item.value.as_ref().map_or(0, Vec::len)
+            }
+            QueuedItem::SetVBucketState => size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointState {
+    Open,
+    Closed,
+}
+
+/// A run of items in seqno order. Only the newest checkpoint is open.
+#[derive(Debug)]
+struct Checkpoint {
+    id: u64,
+    state: CheckpointState,
+    snap_start: u64,
+    snap_end: u64,
+    items: Vec<Arc<QueuedItem>>,
+    mem_usage: usize,
+}
+
+impl Checkpoint {
+    fn new(id: u64, last_seqno: u64) -> Self {
+        Self {
+            id,
+            state: CheckpointState::Open,
+            snap_start: last_seqno + 1,
+            snap_end: last_seqno,
+            items: vec![],
+            mem_usage: 0,
+        }
+    }
+}
+
+/// Where a cursor is: the next item it reads is `items[pos]` of the
+/// checkpoint with the given id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CursorPosition {
+    checkpoint_id: u64,
+    pos: usize,
+}
+
+/// Items read from a cursor, see [CheckpointManager::get_items_for_cursor]
+#[derive(Debug)]
+pub struct CursorItems {
+    pub items: Vec<Arc<QueuedItem>>,
+    /// The snapshot the items belong to
+    pub snap_start: u64,
+    pub snap_end: u64,
+    /// Pass to [CheckpointManager::advance_cursor] once the items have been
+    /// dealt with
+    pub end: CursorPosition,
+}
+
+/// Where a newly registered cursor starts, see
+/// [CheckpointManager::register_cursor]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorRegistration {
+    /// The first seqno the cursor will read
+    pub first_seqno: u64,
+    /// Seqnos before `first_seqno` that were asked for are no longer in
+    /// memory and have to be read from disk
+    pub needs_backfill: bool,
+}
+
+/// Orders the mutations of a vBucket by seqno, for the flusher and DCP
+/// streams to read through their own cursors. Checkpoints that every cursor
+/// has moved past are removed.
+#[derive(Debug)]
+pub struct CheckpointManager {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    checkpoints: VecDeque<Checkpoint>,
+    next_checkpoint_id: u64,
+    last_seqno: u64,
+    cursors: HashMap<String, CursorPosition>,
+    mem_usage: usize,
+}
+
+impl CheckpointManager {
+    /// A manager whose first item follows `last_seqno`, the high seqno of
+    /// the vBucket on disk
+    pub fn new(last_seqno: u64) -> Self {
+        let mut checkpoints = VecDeque::new();
+        checkpoints.push_back(Checkpoint::new(1, last_seqno));
+
+        let mut cursors = HashMap::new();
+        cursors.insert(
+            PERSISTENCE_CURSOR.to_string(),
+            CursorPosition {
+                checkpoint_id: 1,
+                pos: 0,
+            },
+        );
+
+        Self {
+            inner: Mutex::new(Inner {
+                checkpoints,
+                next_checkpoint_id: 2,
+                last_seqno,
+                cursors,
+                mem_usage: 0,
+            }),
+        }
+    }
+
+    /// Queue a mutation or deletion, giving it the next seqno, which is
+    /// returned
+    pub fn queue_dirty(&self, mut item: Item) -> u64 {
+        let mut inner = self.inner.lock();
+
+        inner.last_seqno += 1;
+        item.by_seqno = inner.last_seqno;
+
+        inner.queue(QueuedItem::Item(item));
+        inner.last_seqno
+    }
+
+    /// Queue a change of vBucket state, which doesn't take a seqno
+    pub fn queue_set_vbucket_state(&self) {
+        self.inner.lock().queue(QueuedItem::SetVBucketState);
+    }
+
+    /// The seqno of the last item queued
+    pub fn high_seqno(&self) -> u64 {
+        self.inner.lock().last_seqno
+    }
+
+    /// Start a cursor at the first item with a seqno of at least
+    /// `start_seqno`, replacing any cursor with the same name
+    pub fn register_cursor(&self, name: &str, start_seqno: u64) -> CursorRegistration {
+        let mut inner = self.inner.lock();
+
+        let mut position = None;
+        let mut first_seqno = inner.last_seqno + 1;
+
+        'checkpoints: for checkpoint in &inner.checkpoints {
+            for (pos, item) in checkpoint.items.iter().enumerate() {
+                if let QueuedItem::Item(item) = &**item {
+                    if item.by_seqno >= start_seqno {
+                        position = Some(CursorPosition {
+                            checkpoint_id: checkpoint.id,
+                            pos,
+                        });
+                        first_seqno = item.by_seqno;
+                        break 'checkpoints;
+                    }
+                }
+            }
+        }
+
+        // Past everything queued, so start at the end of the open checkpoint
+        let position = position.unwrap_or_else(|| {
+            let open = inner.checkpoints.back().unwrap();
+            CursorPosition {
+                checkpoint_id: open.id,
+                pos: open.items.len(),
+            }
+        });
+
+        let oldest_seqno = inner.checkpoints.front().unwrap().snap_start;
+        let needs_backfill = start_seqno < oldest_seqno.min(first_seqno);
+
+        inner.cursors.insert(name.to_string(), position);
+
+        CursorRegistration {
+            first_seqno,
+            needs_backfill,
+        }
+    }
+
+    pub fn remove_cursor(&self, name: &str) {
+        let mut inner = self.inner.lock();
+        if name != PERSISTENCE_CURSOR && inner.cursors.remove(name).is_some() {
+            inner.remove_unreferenced_checkpoints();
+        }
+    }
+
+    pub fn has_cursor(&self, name: &str) -> bool {
+        self.inner.lock().cursors.contains_key(name)
+    }
+
+    /// Every item after the cursor, which stays where it is until
+    /// [CheckpointManager::advance_cursor] is called. Returns None if there
+    /// is no such cursor, e.g. because it was dropped to free memory.
+    pub fn get_items_for_cursor(&self, name: &str) -> Option<CursorItems> {
+        let inner = self.inner.lock();
+        let position = *inner.cursors.get(name)?;
+
+        let mut items = vec![];
+        let mut snap_start = None;
+        let mut end = position;
+        let mut snap_end = inner.last_seqno;
+
+        for checkpoint in &inner.checkpoints {
+            if checkpoint.id < position.checkpoint_id {
+                continue;
+            }
+            let pos = if checkpoint.id == position.checkpoint_id {
+                position.pos
+            } else {
+                0
+            };
+
+            items.extend(checkpoint.items[pos..].iter().cloned());
+            snap_start.get_or_insert(checkpoint.snap_start);
+            snap_end = checkpoint.snap_end;
+            end = CursorPosition {
+                checkpoint_id: checkpoint.id,
+                pos: checkpoint.items.len(),
+            };
+        }
+
+        Some(CursorItems {
+            items,
+            snap_start: snap_start.unwrap_or(inner.last_seqno + 1),
+            snap_end,
+            end,
+        })
+    }
+
+    /// Move the cursor on to `end`, as returned by
+    /// [CheckpointManager::get_items_for_cursor], freeing any checkpoints no
+    /// longer needed
+    pub fn advance_cursor(&self, name: &str, end: CursorPosition) {
+        let mut inner = self.inner.lock();
+        if let Some(position) = inner.cursors.get_mut(name) {
+            *position = (*position).max(end);
+            inner.remove_unreferenced_checkpoints();
+        }
+    }
+
+    pub fn num_checkpoints(&self) -> usize {
+        self.inner.lock().checkpoints.len()
+    }
+
+    /// Number of items in every checkpoint
+    pub fn num_items(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.checkpoints.iter().map(|c| c.items.len()).sum()
+    }
+
+    /// Approximate memory used by the queued items
+    pub fn mem_usage(&self) -> usize {
+        self.inner.lock().mem_usage
+    }
+}
+
+impl Inner {
+    fn queue(&mut self, item: QueuedItem) {
+        let size = item.mem_size();
+        let last_seqno = self.last_seqno;
+
+        let open = self.checkpoints.back_mut().unwrap();
+        open.items.push(Arc::new(item));
+        open.snap_end = last_seqno;
+        open.mem_usage += size;
+        self.mem_usage += size;
+
+        if open.items.len() >= MAX_ITEMS_PER_CHECKPOINT {
+            self.close_open_checkpoint();
+        }
+
+        if self.mem_usage > CHECKPOINT_MEMORY_LIMIT {
+            self.reduce_memory();
+        }
+    }
+
+    /// Close the open checkpoint, if it has anything in it, and open a new
+    /// one
+    fn close_open_checkpoint(&mut self) {
+        let open = self.checkpoints.back_mut().unwrap();
+        if open.items.is_empty() {
+            return;
+        }
+        open.state = CheckpointState::Closed;
+
+        let id = self.next_checkpoint_id;
+        self.next_checkpoint_id += 1;
+        self.checkpoints
+            .push_back(Checkpoint::new(id, self.last_seqno));
+    }
+
+    /// Free memory by removing closed checkpoints, dropping the cursors of
+    /// streams that are too far behind. Those streams have to read the items
+    /// from disk instead. The persistence cursor is never dropped.
+    fn reduce_memory(&mut self) {
+        self.close_open_checkpoint();
+        self.remove_unreferenced_checkpoints();
+
+        while self.mem_usage > CHECKPOINT_MEMORY_LIMIT && self.checkpoints.len() > 1 {
+            let oldest = self.checkpoints.front().unwrap();
+            let (oldest, oldest_len) = (oldest.id, oldest.items.len());
+
+            let persistence = self.cursors[PERSISTENCE_CURSOR];
+            if persistence.checkpoint_id == oldest && persistence.pos < oldest_len {
+                break;
+            }
+
+            self.cursors.retain(|name, position| {
+                name == PERSISTENCE_CURSOR || position.checkpoint_id != oldest
+            });
+            self.remove_unreferenced_checkpoints();
+        }
+    }
+
+    /// Remove closed checkpoints from the front that no cursor is in
+    fn remove_unreferenced_checkpoints(&mut self) {
+        while let Some(oldest) = self.checkpoints.front() {
+            if oldest.state == CheckpointState::Open {
+                break;
+            }
+
+            let referenced = self.cursors.values().any(|position| {
+                position.checkpoint_id == oldest.id && position.pos < oldest.items.len()
+            });
+            if referenced {
+                break;
+            }
+
+            // Cursors at the very end of the checkpoint are moved on
+            let next = self.checkpoints[1].id;
+            for position in self.cursors.values_mut() {
+                if position.checkpoint_id == oldest.id {
+                    *position = CursorPosition {
+                        checkpoint_id: next,
+                        pos: 0,
+                    };
+                }
+            }
+
+            let oldest = self.checkpoints.pop_front().unwrap();
+            self.mem_usage -= oldest.mem_usage;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use memcached_codec::DataType;
+
+    use super::*;
+
+    fn item(key: &str) -> Item {
+        Item::new(key.into(), b"value".to_vec(), 0, 0, DataType::RAW)
+    }
+
+    fn seqnos(items: &CursorItems) -> Vec<u64> {
+        items
+            .items
+            .iter()
+            .filter_map(|item| match &**item {
+                QueuedItem::Item(item) => Some(item.by_seqno),
+                QueuedItem::SetVBucketState => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cursors() {
+        let manager = CheckpointManager::new(10);
+
+        assert_eq!(manager.queue_dirty(item("a")), 11);
+        assert_eq!(manager.queue_dirty(item("b")), 12);
+        manager.queue_set_vbucket_state();
+
+        let dcp = manager.register_cursor("dcp", 12);
+        assert_eq!(
+            dcp,
+            CursorRegistration {
+                first_seqno: 12,
+                needs_backfill: false
+            }
+        );
+        assert!(manager.register_cursor("old", 5).needs_backfill);
+        manager.remove_cursor("old");
+
+        let batch = manager.get_items_for_cursor(PERSISTENCE_CURSOR).unwrap();
+        assert_eq!(seqnos(&batch), [11, 12]);
+        assert_eq!(batch.items.len(), 3);
+        assert_eq!((batch.snap_start, batch.snap_end), (11, 12));
+
+        // Not advanced yet, so the same items are returned again
+        let again = manager.get_items_for_cursor(PERSISTENCE_CURSOR).unwrap();
+        assert_eq!(seqnos(&again), [11, 12]);
+
+        manager.advance_cursor(PERSISTENCE_CURSOR, batch.end);
+        manager.queue_dirty(item("c"));
+
+        let batch = manager.get_items_for_cursor(PERSISTENCE_CURSOR).unwrap();
+        assert_eq!(seqnos(&batch), [13]);
+
+        let stream = manager.get_items_for_cursor("dcp").unwrap();
+        assert_eq!(seqnos(&stream), [12, 13]);
+        assert!(manager.get_items_for_cursor("old").is_none());
+    }
+
+    #[test]
+    fn test_checkpoint_removal() {
+        let manager = CheckpointManager::new(0);
+
+        for i in 0..MAX_ITEMS_PER_CHECKPOINT * 2 + 1 {
+            manager.queue_dirty(item(&i.to_string()));
+        }
+        assert_eq!(manager.num_checkpoints(), 3);
+
+        let batch = manager.get_items_for_cursor(PERSISTENCE_CURSOR).unwrap();
+        assert_eq!(batch.items.len(), MAX_ITEMS_PER_CHECKPOINT * 2 + 1);
+        manager.advance_cursor(PERSISTENCE_CURSOR, batch.end);
+
+        // Only the open checkpoint is left
+        assert_eq!(manager.num_checkpoints(), 1);
+        assert_eq!(manager.num_items(), 1);
+
+        let cursor = manager.register_cursor("dcp", 1);
+        assert!(cursor.needs_backfill);
+        assert_eq!(cursor.first_seqno, MAX_ITEMS_PER_CHECKPOINT as u64 * 2 + 1);
+    }
+
+    #[test]
+    fn test_memory_based_removal() {
+        let manager = CheckpointManager::new(0);
+        manager.register_cursor("slow stream", 0);
+
+        let big = |key: &str| Item::new(key.into(), vec![0; 1024 * 1024], 0, 0, DataType::RAW);
+
+        for i in 0..10 {
+            manager.queue_dirty(big(&i.to_string()));
+        }
+        let batch = manager.get_items_for_cursor(PERSISTENCE_CURSOR).unwrap();
+        manager.advance_cursor(PERSISTENCE_CURSOR, batch.end);
+
+        // The stream holds everything in memory until the limit is reached
+        for i in 10..40 {
+            manager.queue_dirty(big(&i.to_string()));
+            let batch = manager.get_items_for_cursor(PERSISTENCE_CURSOR).unwrap();
+            manager.advance_cursor(PERSISTENCE_CURSOR, batch.end);
+        }
+
+        assert!(!manager.has_cursor("slow stream"));
+        assert!(manager.has_cursor(PERSISTENCE_CURSOR));
+        assert!(manager.mem_usage() <= CHECKPOINT_MEMORY_LIMIT);
+    }
+}
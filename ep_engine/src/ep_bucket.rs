@@ -1,12 +1,15 @@
 use parking_lot::{Mutex, MutexGuard};
-use std::{ops::Deref, sync::Arc};
+use std::{collections::HashMap, ops::Deref, sync::Arc};
 
 use crate::{
-    kv_store::CouchKVStore,
+    checkpoint_manager::{QueuedItem, PERSISTENCE_CURSOR},
+    failover_table::FailoverTable,
+    item::Item,
+    kv_store::{CompactionConfig, CouchKVStore},
     stored_value::StoredValue,
-    vbucket::{VBucketPtr, Vbid},
+    vbucket::{self, VBucket, VBucketPtr, Vbid},
     vbucket_map::VBucketMap,
-    Config,
+    Config, Error, Result,
 };
 
 pub struct EPBucket {
@@ -38,25 +41,157 @@ impl EPBucket {
 
     /// Return a pointer to the given VBucket, acquiring the appropriate VB
     /// mutex lock at the same time.
-    pub fn get_locked_vbucket(&self, vbid: Vbid) -> LockedVbucketPtr {
+    pub fn get_locked_vbucket(&self, vbid: Vbid) -> LockedVbucketPtr<'_> {
         let _guard = self.vb_mutexes[usize::from(vbid)].lock();
         let vb = self.vbucket_map.get_bucket(vbid);
         LockedVbucketPtr { vb, _guard }
     }
 
-    pub fn flush_vbucket_unlocked(&self, _vb: &LockedVbucketPtr) {}
+    /// Start a flusher for each shard, which persists writes in the
+    /// background
+    pub fn start_flusher(self: &Arc<Self>) {
+        for shard in &self.vbucket_map.shards {
+            shard.flusher().start(Arc::downgrade(self));
+        }
+    }
+
+    /// Persist every outstanding write and stop the flushers. Fails if some
+    /// writes couldn't be persisted, after retrying.
+    pub fn stop_flusher(&self) -> Result<()> {
+        let mut failed = vec![];
+        for shard in &self.vbucket_map.shards {
+            failed.extend(shard.flusher().stop());
+        }
+        if !failed.is_empty() {
+            return Err(Error::NotPersisted(failed));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn notify_flusher(&self, vbid: Vbid) {
+        self.vbucket_map
+            .get_shard_by_vb_id(vbid)
+            .flusher()
+            .notify(vbid);
+    }
 
-    pub fn get(&self, key: Vec<u8>) -> Option<StoredValue> {
-        let vbid = v_bucket_hash(&key, 1024);
-        // TODO: This is a hack to get around the fact that we don't have
-        // collection support yet. We need to add support for collections
-        let key_with_collection_id = {
-            let mut key_with_collection_id = Vec::from("\0");
-            key_with_collection_id.extend(key);
-            key_with_collection_id
+    /// Persist every dirty item in the vBucket, returning how many were
+    /// written
+    pub fn flush_vbucket(&self, vbid: Vbid) -> Result<usize> {
+        let locked_vb = self.get_locked_vbucket(vbid);
+        self.flush_vbucket_unlocked(&locked_vb)
+    }
+
+    /// Flush a vBucket the caller already holds the lock of
+    pub fn flush_vbucket_unlocked(&self, vb: &LockedVbucketPtr) -> Result<usize> {
+        let vb = match &**vb {
+            Some(vb) => vb,
+            None => return Ok(0),
         };
-        let vb = self.get_vbucket(Vbid::from(vbid)).unwrap();
-        vb.get(&key_with_collection_id)
+
+        let checkpoint_manager = vb.checkpoint_manager();
+        let Some(batch) = checkpoint_manager.get_items_for_cursor(PERSISTENCE_CURSOR) else {
+            return Ok(0);
+        };
+        if batch.items.is_empty() {
+            return Ok(0);
+        }
+
+        // Only the newest revision of each key is written
+        let mut latest = HashMap::new();
+        for queued in &batch.items {
+            if let QueuedItem::Item(item) = &**queued {
+                latest.insert(&item.key, item);
+            }
+        }
+        let items = latest.into_values().cloned().collect::<Vec<_>>();
+
+        self.vbucket_map.get_shard_by_vb_id(vb.id).store().persist(
+            vb,
+            &items,
+            batch.snap_start,
+            batch.snap_end,
+        )?;
+
+        checkpoint_manager.advance_cursor(PERSISTENCE_CURSOR, batch.end);
+        vb.mark_clean(&items);
+
+        Ok(items.len())
+    }
+
+    /// Create an empty vBucket in the given state, for a vBucket that has
+    /// never been persisted
+    pub fn create_vbucket(&self, vbid: Vbid, state: vbucket::State) -> VBucketPtr {
+        // TODO: Get from config
+        let max_entries = 25;
+
+        let vb = VBucketPtr::new(VBucket::new(
+            vbid,
+            state,
+            FailoverTable::new_empty(max_entries),
+            0,
+            0,
+            0,
+        ));
+        vb.checkpoint_manager().queue_set_vbucket_state();
+        self.vbucket_map.add_bucket(vb.clone());
+        // Warmup only finds the vBucket once its state is on disk
+        self.notify_flusher(vbid);
+        vb
+    }
+
+    /// Compact the vBucket's file into its next revision. The flusher can't
+    /// persist to the old revision meanwhile, so no write is lost when it's
+    /// removed.
+    pub fn compact_vbucket(&self, vbid: Vbid, config: CompactionConfig) -> Result<()> {
+        let _locked_vb = self.get_locked_vbucket(vbid);
+        self.vbucket_map
+            .get_shard_by_vb_id(vbid)
+            .store()
+            .compact_db(vbid, config)?;
+        Ok(())
+    }
+
+    /// Was the vBucket's file unreadable during warmup
+    pub fn is_vbucket_failed(&self, vbid: Vbid) -> bool {
+        self.vbucket_map
+            .get_shard_by_vb_id(vbid)
+            .store()
+            .is_vbucket_failed(vbid)
+    }
+
+    /// The live value of `key`, which includes its collection id
+    pub fn get(&self, vbid: Vbid, key: &[u8]) -> Result<StoredValue> {
+        let vb = self.get_vbucket(vbid).ok_or(Error::NotMyVbucket(vbid))?;
+        vb.get(key).ok_or(Error::KeyNotFound)
+    }
+
+    /// Store the item in memory, the flusher writes it to disk in the
+    /// background. A non-zero CAS on the item must match the current value's.
+    pub fn set(&self, vbid: Vbid, item: Item) -> Result<StoredValue> {
+        let vb = self.get_vbucket(vbid).ok_or(Error::NotMyVbucket(vbid))?;
+        let stored = vb.set(item)?;
+        self.notify_flusher(vbid);
+        Ok(stored)
+    }
+
+    /// Delete `key` in memory, the flusher writes the tombstone to disk in
+    /// the background. A non-zero `cas` must match the current value's.
+    pub fn delete(&self, vbid: Vbid, key: &[u8], cas: u64) -> Result<StoredValue> {
+        let vb = self.get_vbucket(vbid).ok_or(Error::NotMyVbucket(vbid))?;
+        let tombstone = vb.delete(key, cas)?;
+        self.notify_flusher(vbid);
+        Ok(tombstone)
+    }
+}
+
+impl Drop for EPBucket {
+    fn drop(&mut self) {
+        // The flushers only hold a weak reference to the bucket, and may be
+        // the ones dropping it, so don't wait for them
+        for shard in &self.vbucket_map.shards {
+            shard.flusher().request_stop();
+        }
     }
 }
 
@@ -75,6 +210,17 @@ impl Deref for LockedVbucketPtr<'_> {
     }
 }
 
+/// The key as stored, for a client that doesn't use collections
+// TODO: This is a hack to get around the fact that we don't have
+// collection support yet. We need to add support for collections
+pub fn default_collection_key(key: &[u8]) -> Vec<u8> {
+    // The default collection's id is 0, a single byte as a leb128
+    let mut key_with_collection_id = Vec::with_capacity(key.len() + 1);
+    key_with_collection_id.push(0);
+    key_with_collection_id.extend_from_slice(key);
+    key_with_collection_id
+}
+
 pub fn v_bucket_hash(key: &[u8], num_vbuckets: u32) -> u16 {
     let mut hasher = crc32fast::Hasher::new();
     hasher.update(key);
@@ -82,3 +228,263 @@ pub fn v_bucket_hash(key: &[u8], num_vbuckets: u32) -> u16 {
     let hash = (((crc) >> 16) & 0x7fff) & (num_vbuckets - 1);
     hash as u16
 }
+
+#[cfg(test)]
+mod test {
+    use memcached_codec::DataType;
+
+    use super::*;
+    use crate::{vbucket::State, warmup::Warmup};
+
+    fn warmed_up_bucket(dbname: &str) -> EPBucketPtr {
+        let config = Config {
+            max_vbuckets: 4,
+            max_shards: 2,
+            dbname: dbname.to_string(),
+        };
+        let bucket = EPBucket::new(config.clone());
+        Warmup::new(bucket.clone(), config).warmup();
+        bucket
+    }
+
+    fn store_high_seqno(bucket: &EPBucket, vbid: Vbid) -> u64 {
+        let store = bucket.get_store_by_shard(usize::from(vbid) % 2);
+        store
+            .init_by_seqno_scan_context(vbid, 0)
+            .unwrap()
+            .update_seqno
+    }
+
+    #[test]
+    fn test_flush_then_warmup() {
+        let dir = std::env::temp_dir().join(format!("ep-bucket-flush-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dbname = dir.to_str().unwrap();
+
+        let vbid = Vbid::new(1);
+        let key = default_collection_key(b"key");
+        let removed = default_collection_key(b"removed");
+
+        let bucket = warmed_up_bucket(dbname);
+        assert!(matches!(
+            bucket.get(vbid, &key),
+            Err(Error::NotMyVbucket(_))
+        ));
+
+        bucket.create_vbucket(vbid, State::Active);
+        let mut cas = 0;
+        for value in [&b"{\"v\":1}"[..], b"{\"v\":2}"] {
+            let mut item = Item::new(key.clone(), value.to_vec(), 0x02000006, 0, DataType::JSON);
+            item.cas = cas;
+            let stored = bucket.set(vbid, item).unwrap();
+            assert!(stored.cas > cas);
+            cas = stored.cas;
+        }
+        // A client that read the first revision lost the race
+        let mut item = Item::new(key.clone(), b"{}".to_vec(), 0, 0, DataType::JSON);
+        item.cas = cas - 1;
+        assert!(matches!(bucket.set(vbid, item), Err(Error::KeyExists)));
+
+        let item = Item::new(removed.clone(), b"raw".to_vec(), 0, 0, DataType::RAW);
+        bucket.set(vbid, item).unwrap();
+        let tombstone = bucket.delete(vbid, &removed, 0).unwrap();
+        assert!(tombstone.cas > cas);
+        assert!(matches!(
+            bucket.delete(vbid, &removed, 0),
+            Err(Error::KeyNotFound)
+        ));
+
+        // Stopping the flusher persists everything outstanding
+        bucket.stop_flusher().unwrap();
+        assert_eq!(bucket.flush_vbucket(vbid).unwrap(), 0);
+
+        let stored = bucket.get(vbid, &key).unwrap();
+        assert_eq!(stored.rev_seqno, 2);
+
+        let store = bucket.get_store_by_shard(usize::from(vbid) % 2);
+        let vb_state = store
+            .init_by_seqno_scan_context(vbid, 0)
+            .unwrap()
+            .vbucket_state;
+        // Every write takes a seqno, even when it's deduplicated on disk
+        let high_seqno = vb_state.high_seqno as u64;
+        assert_eq!(high_seqno, 4);
+        assert_eq!(stored.by_seqno, 2);
+        assert_eq!(vb_state.snap_end, high_seqno);
+        assert!(vb_state.snap_start <= vb_state.snap_end);
+        assert_eq!(stored.cas, cas);
+        assert_eq!(vb_state.max_cas, tombstone.cas);
+        drop(bucket);
+
+        let bucket = warmed_up_bucket(dbname);
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        assert_eq!(vb.state(), State::Active);
+
+        let warmed_up = bucket.get(vbid, &key).unwrap();
+        assert_eq!(warmed_up.flags, 0x02000006);
+        assert_eq!(warmed_up.rev_seqno, 2);
+        assert_eq!(warmed_up.by_seqno, stored.by_seqno);
+        assert_eq!(warmed_up.cas, stored.cas);
+        assert!(warmed_up.data_type.contains(DataType::JSON));
+        assert!(matches!(
+            bucket.get(vbid, &removed),
+            Err(Error::KeyNotFound)
+        ));
+
+        // Writes after warmup are flushed in the background
+        assert_eq!(vb.max_cas(), tombstone.cas);
+        let item = Item::new(removed.clone(), b"back".to_vec(), 0, 0, DataType::RAW);
+        assert!(bucket.set(vbid, item).unwrap().cas > tombstone.cas);
+        bucket.stop_flusher().unwrap();
+        assert_eq!(store_high_seqno(&bucket, vbid), high_seqno + 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_warmup_persists_vbucket_state() {
+        let dir = std::env::temp_dir().join(format!("ep-bucket-vbstate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dbname = dir.to_str().unwrap();
+        let vbid = Vbid::new(1);
+
+        let bucket = warmed_up_bucket(dbname);
+        bucket.create_vbucket(vbid, State::Active);
+        bucket.stop_flusher().unwrap();
+        drop(bucket);
+
+        // Drop the failover table from the persisted state, which warmup
+        // replaces with a new one
+        let path = format!("{}/{}.couch.1", dbname, vbid);
+        let mut db = couchstore::Db::open(&path, couchstore::DBOpenOptions::default()).unwrap();
+        let doc = db.open_local_document("_local/vbstate").unwrap();
+        let mut json: serde_json::Value = serde_json::from_slice(&doc.json.unwrap()).unwrap();
+        json["failover_table"] = serde_json::Value::Null;
+        let json = serde_json::to_vec(&json).unwrap();
+        db.save_local_document(couchstore::LocalDoc::new("_local/vbstate", json))
+            .unwrap();
+        db.commit().unwrap();
+        drop(db);
+
+        // Without any writes, the vBucket state is persisted by warmup
+        let bucket = warmed_up_bucket(dbname);
+        bucket.stop_flusher().unwrap();
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        let vb_state = bucket
+            .get_store_by_shard(usize::from(vbid) % 2)
+            .init_by_seqno_scan_context(vbid, 0)
+            .unwrap()
+            .vbucket_state;
+        assert!(!vb_state.failover_table.is_null());
+        assert_eq!(vb_state.failover_table, vb.failover_table().to_json());
+        assert_eq!(vb_state.state, State::Active);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_create_vbucket_persists_state() {
+        let dir = std::env::temp_dir().join(format!("ep-bucket-create-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dbname = dir.to_str().unwrap();
+        let vbid = Vbid::new(1);
+
+        // Without any writes, the new vBucket is found by the next warmup
+        let bucket = warmed_up_bucket(dbname);
+        bucket.create_vbucket(vbid, State::Active);
+        bucket.stop_flusher().unwrap();
+        drop(bucket);
+
+        let bucket = warmed_up_bucket(dbname);
+        bucket.stop_flusher().unwrap();
+        assert_eq!(bucket.get_vbucket(vbid).unwrap().state(), State::Active);
+        assert!(bucket.get_vbucket(Vbid::new(0)).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stop_flusher_reports_lost_writes() {
+        let dir = std::env::temp_dir().join(format!("ep-bucket-lost-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let vbid = Vbid::new(1);
+
+        // Nothing can be written once the bucket's directory has gone
+        let bucket = warmed_up_bucket(dir.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        bucket.create_vbucket(vbid, State::Active);
+        let item = Item::new(
+            default_collection_key(b"key"),
+            b"{}".to_vec(),
+            0,
+            0,
+            DataType::JSON,
+        );
+        bucket.set(vbid, item).unwrap();
+
+        assert!(matches!(
+            bucket.stop_flusher(),
+            Err(Error::NotPersisted(vbuckets)) if vbuckets == vec![vbid]
+        ));
+    }
+
+    #[test]
+    fn test_persist_during_compaction() {
+        let dir = std::env::temp_dir().join(format!("ep-bucket-compact-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dbname = dir.to_str().unwrap();
+        let vbid = Vbid::new(1);
+
+        let bucket = warmed_up_bucket(dbname);
+        bucket.create_vbucket(vbid, State::Active);
+        for i in 0..2000 {
+            let key = default_collection_key(format!("before-{}", i).as_bytes());
+            let item = Item::new(key, b"{}".to_vec(), 0, 0, DataType::JSON);
+            bucket.set(vbid, item).unwrap();
+        }
+        bucket.stop_flusher().unwrap();
+
+        // Keep persisting writes until the compaction has switched over
+        let done = std::sync::atomic::AtomicBool::new(false);
+        let written = std::thread::scope(|s| {
+            s.spawn(|| {
+                bucket
+                    .compact_vbucket(vbid, CompactionConfig::default())
+                    .unwrap();
+                done.store(true, std::sync::atomic::Ordering::SeqCst);
+            });
+
+            let mut written = 0;
+            loop {
+                let key = default_collection_key(format!("during-{}", written).as_bytes());
+                let item = Item::new(key, b"{}".to_vec(), 0, 0, DataType::JSON);
+                bucket.set(vbid, item).unwrap();
+                bucket.flush_vbucket(vbid).unwrap();
+                written += 1;
+                if done.load(std::sync::atomic::Ordering::SeqCst) {
+                    break written;
+                }
+            }
+        });
+        drop(bucket);
+
+        let bucket = warmed_up_bucket(dbname);
+        bucket.stop_flusher().unwrap();
+        for i in 0..written {
+            let key = default_collection_key(format!("during-{}", i).as_bytes());
+            assert!(bucket.get(vbid, &key).is_ok(), "during-{} was lost", i);
+        }
+        assert!(bucket
+            .get(vbid, &default_collection_key(b"before-0"))
+            .is_ok());
+        assert!(!dir.join(format!("{}.couch.1", vbid)).exists());
+        assert!(dir.join(format!("{}.couch.2", vbid)).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,30 @@
+use thiserror::Error;
+
+use crate::vbucket::Vbid;
+
+/// Errors returned by the engine for a client operation, or when stopping
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The vBucket doesn't exist on this node
+    #[error("vbucket {0} is not on this node")]
+    NotMyVbucket(Vbid),
+
+    /// The key doesn't exist, or has been deleted
+    #[error("key not found")]
+    KeyNotFound,
+
+    /// The key's CAS doesn't match the one the client expected, it has been
+    /// changed since the client read it
+    #[error("key exists with a different CAS")]
+    KeyExists,
+
+    /// The vBucket's file couldn't be read or written
+    #[error(transparent)]
+    Storage(#[from] couchstore::Error),
+
+    /// The flushers stopped with items in these vBuckets still dirty
+    #[error("failed to persist vbuckets {0:?}")]
+    NotPersisted(Vec<Vbid>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
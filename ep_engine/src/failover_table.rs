@@ -41,6 +41,12 @@ impl FailoverTable {
         table
     }
 
+    /// The table as stored in `_local/vbstate`, newest entry first
+    pub fn to_json(&self) -> serde_json::Value {
+        let table = &self.state.lock().table;
+        serde_json::to_value(table).unwrap()
+    }
+
     fn create_entry(&self, high_seqno: u64) {
         let table = &mut self.state.lock().table;
 
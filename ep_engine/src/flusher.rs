@@ -0,0 +1,129 @@
+use parking_lot::{Condvar, Mutex};
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Weak},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{ep_bucket::EPBucket, vbucket::Vbid};
+
+/// How long to wait before retrying a vBucket whose flush failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many times a failed flush is retried once the flusher is stopping,
+/// before giving up on the vBucket
+const STOP_RETRIES: usize = 2;
+
+/// Persists the dirty items of a shard's vBuckets in the background. Writes
+/// notify the flusher of their vBucket, and each wakeup flushes every vBucket
+/// notified since the last, so a burst of writes is committed together.
+#[derive(Debug)]
+pub struct Flusher {
+    shard_id: u16,
+    pending: Mutex<Pending>,
+    wakeup: Condvar,
+    /// Returns the vBuckets it couldn't flush before stopping
+    thread: Mutex<Option<JoinHandle<Vec<Vbid>>>>,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    vbuckets: BTreeSet<Vbid>,
+    stopping: bool,
+}
+
+impl Flusher {
+    pub fn new(shard_id: u16) -> Self {
+        Self {
+            shard_id,
+            pending: Default::default(),
+            wakeup: Condvar::new(),
+            thread: Mutex::new(None),
+        }
+    }
+
+    /// Start flushing on a thread of its own. Does nothing if it's already
+    /// running.
+    pub fn start(self: &Arc<Self>, bucket: Weak<EPBucket>) {
+        let mut thread = self.thread.lock();
+        if thread.is_some() {
+            return;
+        }
+
+        let flusher = self.clone();
+        let handle = std::thread::Builder::new()
+            .name(format!("flusher-{}", self.shard_id))
+            .spawn(move || flusher.run(bucket))
+            .unwrap();
+        *thread = Some(handle);
+    }
+
+    /// The vBucket has items to flush
+    pub fn notify(&self, vbid: Vbid) {
+        self.pending.lock().vbuckets.insert(vbid);
+        self.wakeup.notify_one();
+    }
+
+    /// Ask the flusher to stop once it has flushed everything it has been
+    /// notified of, without waiting for it
+    pub fn request_stop(&self) {
+        self.pending.lock().stopping = true;
+        self.wakeup.notify_one();
+    }
+
+    /// Flush everything outstanding and wait for the flusher to stop. Returns
+    /// the vBuckets whose items are still dirty as their flush kept failing.
+    pub fn stop(&self) -> Vec<Vbid> {
+        self.request_stop();
+        match self.thread.lock().take() {
+            Some(handle) => handle.join().unwrap(),
+            None => vec![],
+        }
+    }
+
+    fn run(&self, bucket: Weak<EPBucket>) -> Vec<Vbid> {
+        let mut stop_retries = 0;
+        loop {
+            let (vbuckets, stopping) = {
+                let mut pending = self.pending.lock();
+                while pending.vbuckets.is_empty() && !pending.stopping {
+                    self.wakeup.wait(&mut pending);
+                }
+                (std::mem::take(&mut pending.vbuckets), pending.stopping)
+            };
+
+            // The bucket has gone, so there's nothing left to flush
+            let Some(bucket) = bucket.upgrade() else {
+                return vec![];
+            };
+
+            let mut failed = vec![];
+            for vbid in vbuckets {
+                if let Err(err) = bucket.flush_vbucket(vbid) {
+                    println!("Failed to flush {}: {}", vbid, err);
+                    failed.push(vbid);
+                }
+            }
+            drop(bucket);
+
+            if failed.is_empty() {
+                if stopping {
+                    return failed;
+                }
+                continue;
+            }
+
+            // The items are still dirty, so try again later. Once stopping,
+            // only a few more times, then tell the caller what's left.
+            if stopping {
+                if stop_retries == STOP_RETRIES {
+                    return failed;
+                }
+                stop_retries += 1;
+            }
+            std::thread::sleep(RETRY_INTERVAL);
+            self.pending.lock().vbuckets.extend(failed);
+        }
+    }
+}
@@ -23,9 +23,49 @@ impl HashTable {
         value.mark_not_resident();
     }
 
+    /// Store a new revision of the item's key, to be persisted by the next
+    /// flush
+    pub fn set(&mut self, mut item: Item) -> &mut StoredValue {
+        item.rev_seqno = match self.map.get(&item.key) {
+            Some(existing) => existing.rev_seqno + 1,
+            None => 1,
+        };
+
+        // Replace rather than update, so a tombstone becomes a live value
+        self.map.remove(&item.key);
+
+        let value = self.add_new_stored_value(item);
+        value.mark_resident();
+        value.mark_dirty();
+        value
+    }
+
+    /// Replace the key's value with a tombstone, which is returned. Returns
+    /// None if there is no live value to delete.
+    pub fn delete(&mut self, key: &[u8]) -> Option<&mut StoredValue> {
+        match self.map.get_mut(key) {
+            Some(value) if !value.is_deleted() => {
+                value.mark_deleted();
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+
+    /// Record that `item` has been persisted. Values that have been changed
+    /// again since have a later seqno, and stay dirty until that is
+    /// persisted too.
+    pub fn mark_clean(&mut self, item: &Item) {
+        if let Some(value) = self.map.get_mut(&item.key) {
+            if value.by_seqno == item.by_seqno {
+                value.mark_clean();
+            }
+        }
+    }
+
     fn add_new_stored_value(&mut self, item: Item) -> &mut StoredValue {
         let value = StoredValue {
-            value: None,
+            value: item.value,
             cas: item.cas,
             by_seqno: item.by_seqno,
             expiry_time: item.expiry_time,
@@ -0,0 +1,195 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How far ahead of the local clock a CAS from elsewhere can be before it
+/// counts as drift
+const DRIFT_AHEAD_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// How far behind the local clock a CAS from elsewhere can be before it
+/// counts as drift
+const DRIFT_BEHIND_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// A hybrid logical clock, which generates the CAS of each mutation in a
+/// vBucket. A CAS is the wall clock time in nanoseconds, with the low 16 bits
+/// cleared, unless the clock hasn't moved past the largest CAS seen, in which
+/// case that is incremented instead (a logical tick). CAS values therefore
+/// always increase, even if the wall clock goes backwards.
+#[derive(Debug)]
+pub struct Hlc {
+    max_hlc: AtomicU64,
+    /// Items with a seqno at or above this have a CAS generated by an HLC,
+    /// rather than an older scheme, so it can be read as a time
+    epoch_seqno: i64,
+    logical_clock_ticks: AtomicU64,
+    total_abs_drift: AtomicU64,
+    total_abs_drift_count: AtomicU64,
+    drift_ahead_exceeded: AtomicU64,
+    drift_behind_exceeded: AtomicU64,
+}
+
+/// How far the CAS values from other nodes are from the local clock, see
+/// [Hlc::set_max_hlc_and_track_drift]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DriftStats {
+    /// Sum of the absolute drift in nanoseconds
+    pub total_abs_drift: u64,
+    /// Number of CAS values the drift was measured from
+    pub total_abs_drift_count: u64,
+    pub drift_ahead_exceeded: u64,
+    pub drift_behind_exceeded: u64,
+}
+
+impl Hlc {
+    /// A clock that continues from `max_cas`, the largest CAS persisted
+    pub fn new(max_cas: u64, epoch_seqno: i64) -> Self {
+        Self {
+            max_hlc: AtomicU64::new(max_cas),
+            epoch_seqno,
+            logical_clock_ticks: AtomicU64::new(0),
+            total_abs_drift: AtomicU64::new(0),
+            total_abs_drift_count: AtomicU64::new(0),
+            drift_ahead_exceeded: AtomicU64::new(0),
+            drift_behind_exceeded: AtomicU64::new(0),
+        }
+    }
+
+    /// The CAS for the next mutation
+    pub fn next_hlc(&self) -> u64 {
+        self.next_hlc_at(masked_time_now())
+    }
+
+    fn next_hlc_at(&self, now: u64) -> u64 {
+        let prev = self
+            .max_hlc
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |max| {
+                Some(if now > max { now } else { max + 1 })
+            })
+            .unwrap();
+
+        if now > prev {
+            now
+        } else {
+            self.logical_clock_ticks.fetch_add(1, Ordering::Relaxed);
+            prev + 1
+        }
+    }
+
+    /// Take a CAS generated elsewhere, e.g. by the active copy of a vBucket,
+    /// into account, recording how far it is from the local clock
+    pub fn set_max_hlc_and_track_drift(&self, hlc: u64) {
+        self.track_drift(hlc, masked_time_now());
+        self.set_max_hlc(hlc);
+    }
+
+    fn track_drift(&self, hlc: u64, now: u64) {
+        let drift = hlc.abs_diff(now);
+        self.total_abs_drift.fetch_add(drift, Ordering::Relaxed);
+        self.total_abs_drift_count.fetch_add(1, Ordering::Relaxed);
+
+        if hlc > now && drift > DRIFT_AHEAD_THRESHOLD.as_nanos() as u64 {
+            self.drift_ahead_exceeded.fetch_add(1, Ordering::Relaxed);
+        } else if hlc < now && drift > DRIFT_BEHIND_THRESHOLD.as_nanos() as u64 {
+            self.drift_behind_exceeded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Make sure later CAS values are above `hlc`
+    pub fn set_max_hlc(&self, hlc: u64) {
+        self.max_hlc.fetch_max(hlc, Ordering::SeqCst);
+    }
+
+    /// Set the largest CAS seen, even if that moves the clock backwards
+    pub fn force_max_hlc(&self, hlc: u64) {
+        self.max_hlc.store(hlc, Ordering::SeqCst);
+    }
+
+    pub fn max_hlc(&self) -> u64 {
+        self.max_hlc.load(Ordering::SeqCst)
+    }
+
+    pub fn epoch_seqno(&self) -> i64 {
+        self.epoch_seqno
+    }
+
+    /// Number of CAS values that came from incrementing the largest CAS seen
+    /// rather than the wall clock
+    pub fn logical_clock_ticks(&self) -> u64 {
+        self.logical_clock_ticks.load(Ordering::Relaxed)
+    }
+
+    pub fn drift_stats(&self) -> DriftStats {
+        DriftStats {
+            total_abs_drift: self.total_abs_drift.load(Ordering::Relaxed),
+            total_abs_drift_count: self.total_abs_drift_count.load(Ordering::Relaxed),
+            drift_ahead_exceeded: self.drift_ahead_exceeded.load(Ordering::Relaxed),
+            drift_behind_exceeded: self.drift_behind_exceeded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The wall clock in nanoseconds, with the low 16 bits left for logical ticks
+fn masked_time_now() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    now & !0xffff
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_next_hlc() {
+        let hlc = Hlc::new(0, 0);
+
+        assert_eq!(hlc.next_hlc_at(0x10000), 0x10000);
+        assert_eq!(hlc.next_hlc_at(0x20000), 0x20000);
+        assert_eq!(hlc.logical_clock_ticks(), 0);
+
+        // The clock didn't move, or went backwards
+        assert_eq!(hlc.next_hlc_at(0x20000), 0x20001);
+        assert_eq!(hlc.next_hlc_at(0x10000), 0x20002);
+        assert_eq!(hlc.logical_clock_ticks(), 2);
+
+        // Restored from a max CAS in the future, as after the clock on the
+        // node that persisted it was ahead
+        let future = masked_time_now() + Duration::from_secs(3600).as_nanos() as u64;
+        let hlc = Hlc::new(future, 0);
+        assert_eq!(hlc.next_hlc(), future + 1);
+
+        let hlc = Hlc::new(0, 0);
+        let cas = hlc.next_hlc();
+        assert_eq!(cas & 0xffff, 0);
+        assert!(hlc.next_hlc() > cas);
+    }
+
+    #[test]
+    fn test_drift() {
+        let hlc = Hlc::new(0, 0);
+        let now = 100 * 1_000_000_000;
+
+        hlc.track_drift(now + 1_000, now);
+        hlc.track_drift(now + 6_000_000_000, now);
+        hlc.track_drift(now - 7_000_000_000, now);
+
+        assert_eq!(
+            hlc.drift_stats(),
+            DriftStats {
+                total_abs_drift: 13_000_001_000,
+                total_abs_drift_count: 3,
+                drift_ahead_exceeded: 1,
+                drift_behind_exceeded: 1,
+            }
+        );
+
+        hlc.set_max_hlc(50);
+        hlc.set_max_hlc(10);
+        assert_eq!(hlc.max_hlc(), 50);
+        hlc.force_max_hlc(10);
+        assert_eq!(hlc.max_hlc(), 10);
+    }
+}
@@ -1,5 +1,6 @@
 use memcached_codec::DataType;
 
+#[derive(Debug, Clone)]
 pub struct Item {
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
@@ -9,4 +10,29 @@ pub struct Item {
     pub by_seqno: u64,
     pub rev_seqno: u64,
     pub data_type: DataType,
+    pub deleted: bool,
+}
+
+impl Item {
+    /// A new value for `key` from a client. The seqno and revision are
+    /// assigned when it's stored.
+    pub fn new(
+        key: Vec<u8>,
+        value: Vec<u8>,
+        flags: u32,
+        expiry_time: u32,
+        data_type: DataType,
+    ) -> Self {
+        Item {
+            key,
+            value: Some(value),
+            cas: 0,
+            expiry_time,
+            flags,
+            by_seqno: 0,
+            rev_seqno: 0,
+            data_type,
+            deleted: false,
+        }
+    }
 }
@@ -1,4 +1,5 @@
 use crate::{
+    flusher::Flusher,
     kv_store::{CouchKVStore, CouchKVStoreConfig},
     vbucket::{VBucketPtr, Vbid},
     Config,
@@ -11,6 +12,7 @@ pub struct KVShard {
     config: CouchKVStoreConfig,
     vbuckets: Vec<Mutex<Option<VBucketPtr>>>,
     store: CouchKVStore,
+    flusher: Arc<Flusher>,
 }
 
 impl KVShard {
@@ -29,6 +31,7 @@ impl KVShard {
             config: kv_config,
             vbuckets,
             store,
+            flusher: Arc::new(Flusher::new(shard_id)),
         }
     }
 
@@ -59,7 +62,7 @@ impl KVShard {
         self.get_locked_bucket(vb.id).replace(vb);
     }
 
-    fn get_locked_bucket(&self, id: Vbid) -> MutexGuard<Option<VBucketPtr>> {
+    fn get_locked_bucket(&self, id: Vbid) -> MutexGuard<'_, Option<VBucketPtr>> {
         assert_eq!(u16::from(id) % self.config.max_shards, self.config.shard_id);
         let idx = (u16::from(id) / self.config.max_shards) as usize;
         let bucket = &self.vbuckets[idx];
@@ -69,6 +72,10 @@ impl KVShard {
     pub fn store(&self) -> &CouchKVStore {
         &self.store
     }
+
+    pub fn flusher(&self) -> &Arc<Flusher> {
+        &self.flusher
+    }
 }
 
 pub type KVShardPtr = Arc<KVShard>;
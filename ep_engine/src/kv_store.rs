@@ -1,4 +1,7 @@
-use crate::vbucket::{VBucketState, Vbid};
+use crate::{
+    item::Item,
+    vbucket::{VBucket, VBucketState, Vbid},
+};
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 use memcached_codec::DataType;
 use parking_lot::RwLock;
@@ -7,6 +10,7 @@ use std::{
     collections::{HashMap, HashSet},
     io,
     sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 #[derive(Debug, Clone)]
@@ -30,6 +34,9 @@ pub struct CouchKVStore {
     config: CouchKVStoreConfig,
     db_file_rev_map: Arc<RevisionMap>,
     cached_vb_states: Vec<Option<VBucketState>>,
+    /// vBuckets whose file couldn't be read. They are left out of warmup
+    /// rather than taking down the whole bucket.
+    failed_vbuckets: RwLock<HashSet<Vbid>>,
 }
 
 impl CouchKVStore {
@@ -38,6 +45,7 @@ impl CouchKVStore {
             db_file_rev_map: make_revision_map(&config),
             config,
             cached_vb_states: Vec::new(),
+            failed_vbuckets: RwLock::default(),
         };
 
         let cache_size = store.config.get_cache_size();
@@ -63,9 +71,14 @@ impl CouchKVStore {
         for &vbid in map.keys() {
             let options = couchstore::DBOpenOptions::default().read_only();
 
-            let mut db = self.open_db(vbid, options);
+            let result = self.open_db(vbid, options).and_then(|mut db| {
+                self.read_vb_state_and_update_cache(&mut db, vbid)
+                    .map(|_| ())
+            });
 
-            self.read_vb_state_and_update_cache(&mut db, vbid);
+            if let Err(err) = result {
+                self.mark_vbucket_failed(vbid, &err);
+            }
         }
     }
 
@@ -73,13 +86,23 @@ impl CouchKVStore {
         &mut self,
         db: &mut couchstore::Db,
         vbid: Vbid,
-    ) -> &VBucketState {
-        let vb_state = self.read_vb_state(db, vbid);
+    ) -> couchstore::Result<&VBucketState> {
+        let vb_state = self.read_vb_state(db, vbid)?;
 
         let slot = self.get_cache_slot(vbid);
         self.cached_vb_states[slot] = Some(vb_state);
 
-        self.cached_vb_states[slot].as_ref().unwrap()
+        Ok(self.cached_vb_states[slot].as_ref().unwrap())
+    }
+
+    /// Record that the vBucket's file couldn't be read so it isn't used
+    pub fn mark_vbucket_failed(&self, vbid: Vbid, err: &couchstore::Error) {
+        println!("Marking {} as failed: {}", vbid, err);
+        self.failed_vbuckets.write().insert(vbid);
+    }
+
+    pub fn is_vbucket_failed(&self, vbid: Vbid) -> bool {
+        self.failed_vbuckets.read().contains(&vbid)
     }
 
     fn populate_rev_map_and_remove_stale_files(&self) -> HashMap<Vbid, HashSet<u64>> {
@@ -169,7 +192,11 @@ impl CouchKVStore {
         }
     }
 
-    fn open_db(&self, vbid: Vbid, options: couchstore::DBOpenOptions) -> couchstore::Db {
+    fn open_db(
+        &self,
+        vbid: Vbid,
+        options: couchstore::DBOpenOptions,
+    ) -> couchstore::Result<couchstore::Db> {
         let rev_map = self.db_file_rev_map.read();
         let file_rev = rev_map[self.get_cache_slot(vbid)];
         let file_name = get_db_file_name(&self.config.db_name, vbid, file_rev);
@@ -182,19 +209,24 @@ impl CouchKVStore {
         _file_rev: u64,
         options: couchstore::DBOpenOptions,
         file_name: String,
-    ) -> couchstore::Db {
+    ) -> couchstore::Result<couchstore::Db> {
         // TODO: args used for loggin
         couchstore::Db::open(file_name, options)
     }
 
-    fn read_vb_state(&self, db: &mut couchstore::Db, _vbid: Vbid) -> VBucketState {
+    fn read_vb_state(
+        &self,
+        db: &mut couchstore::Db,
+        _vbid: Vbid,
+    ) -> couchstore::Result<VBucketState> {
         let header = self.read_header(db);
         let high_seqno = header.update_seq as i64;
         let purge_seqno = header.purge_seq;
 
-        let vb_state = get_local_vb_state(db);
+        let vb_state = get_local_vb_state(db)?;
 
-        let mut vb_state: VBucketState = serde_json::from_value(vb_state).unwrap();
+        let mut vb_state: VBucketState = serde_json::from_value(vb_state)
+            .map_err(|_| couchstore::Error::Corrupt(LOCAL_DOC_KEY_VBSTATE))?;
 
         vb_state.high_seqno = high_seqno;
         vb_state.purge_seqno = purge_seqno;
@@ -202,17 +234,80 @@ impl CouchKVStore {
         // MB-17517: If the maxCas on disk was invalid then don't use it -
         // instead rebuild from the items we load from disk (i.e. as per
         // an upgrade from an earlier version).
-        if vb_state.max_cas == std::u64::MAX {
+        if vb_state.max_cas == u64::MAX {
             vb_state.max_cas = 0;
         }
 
-        vb_state
+        Ok(vb_state)
     }
 
     fn read_header<'a>(&self, db: &'a couchstore::Db) -> &'a couchstore::Header {
         db.header()
     }
 
+    /// Write the items to the vBucket's file with the seqnos they were
+    /// given, and commit them together with its state, updated to the
+    /// snapshot the items belong to. The file is created if the vBucket has
+    /// never been persisted.
+    pub fn persist(
+        &self,
+        vb: &VBucket,
+        items: &[Item],
+        snap_start: u64,
+        snap_end: u64,
+    ) -> couchstore::Result<()> {
+        let vbid = vb.id;
+
+        if self.get_db_revision(vbid) == 0 {
+            self.update_db_file_map(vbid, 1);
+        }
+
+        let mut db = self.open_db(vbid, couchstore::DBOpenOptions::default())?;
+
+        // Values already compressed in memory are written as they are, the
+        // rest are compressed by couchstore
+        let (compressed, uncompressed): (Vec<_>, Vec<_>) = items
+            .iter()
+            .partition(|item| item.data_type.contains(DataType::SNAPPY));
+
+        for (batch, options) in [
+            (uncompressed, couchstore::SaveOptions::COMPRESS_DOC_BODIES),
+            (compressed, couchstore::SaveOptions::empty()),
+        ] {
+            let docs = batch.into_iter().map(item_to_doc).collect::<Vec<_>>();
+
+            if !docs.is_empty() {
+                db.save_documents(&docs, options | couchstore::SaveOptions::SEQUENCE_AS_IS)?;
+            }
+        }
+
+        let high_seqno = db.header().update_seq;
+
+        let mut vb_state = match get_local_vb_state(&mut db) {
+            Ok(json) => serde_json::from_value(json)
+                .map_err(|_| couchstore::Error::Corrupt(LOCAL_DOC_KEY_VBSTATE))?,
+            Err(couchstore::Error::DocNotFound) => VBucketState::new(vb.state()),
+            Err(err) => return Err(err),
+        };
+        vb_state.state = vb.state();
+        vb_state.failover_table = vb.failover_table().to_json();
+
+        vb_state.snap_start = snap_start.min(high_seqno);
+        vb_state.snap_end = snap_end.max(high_seqno);
+        vb_state.max_visible_seqno = high_seqno;
+        vb_state.high_seqno = high_seqno as i64;
+        vb_state.max_cas = items
+            .iter()
+            .map(|item| item.cas)
+            .fold(vb_state.max_cas, u64::max);
+
+        let json = serde_json::to_vec(&vb_state)
+            .map_err(|_| couchstore::Error::Corrupt(LOCAL_DOC_KEY_VBSTATE))?;
+        db.save_local_document(couchstore::LocalDoc::new(LOCAL_DOC_KEY_VBSTATE, json))?;
+
+        db.commit()
+    }
+
     pub fn list_persisted_vbuckets(&self) -> Vec<&Option<VBucketState>> {
         let mut res = Vec::new();
         for vb in &self.cached_vb_states {
@@ -221,20 +316,81 @@ impl CouchKVStore {
         res
     }
 
-    pub fn init_by_seqno_scan_context(&self, vbid: Vbid, start_seqno: u64) -> BySeqnoScanContext {
-        let mut db = self.open_db(vbid, couchstore::DBOpenOptions::default().read_only());
+    /// Compact the vbucket's file into the next revision and switch over to
+    /// it. The new file is written as `{vbid}.couch.{rev}.compact` and only
+    /// renamed into place once complete, so a crash part way through leaves
+    /// the current revision untouched. Anything persisted to the current
+    /// revision meanwhile would be lost, so the caller holds the vBucket's
+    /// lock, see [crate::ep_bucket::EPBucket::compact_vbucket].
+    pub(crate) fn compact_db(
+        &self,
+        vbid: Vbid,
+        config: CompactionConfig,
+    ) -> couchstore::Result<()> {
+        let revision = self.get_db_revision(vbid);
+        let compact_file = get_db_file_name(&self.config.db_name, vbid, revision) + ".compact";
+
+        let mut source = self.open_db(vbid, couchstore::DBOpenOptions::default().read_only())?;
+
+        let mut options =
+            couchstore::CompactOptions::default().purge_before_seq(config.purge_before_seq);
+
+        if config.drop_expired {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            options = options.hook(move |info| {
+                if info.deleted {
+                    return couchstore::CompactDecision::Keep;
+                }
+                let metadata = match Metadata::decode(&info.rev_meta[..]) {
+                    Ok(metadata) => metadata,
+                    // Items written without metadata never expire
+                    Err(_) => return couchstore::CompactDecision::Keep,
+                };
+                if metadata.expiry_time != 0 && u64::from(metadata.expiry_time) <= now {
+                    couchstore::CompactDecision::Drop
+                } else {
+                    couchstore::CompactDecision::Keep
+                }
+            });
+        }
+
+        source.compact(&compact_file, options)?;
+        drop(source);
+
+        let new_revision = revision + 1;
+        let new_file = get_db_file_name(&self.config.db_name, vbid, new_revision);
+        std::fs::rename(&compact_file, &new_file)?;
+
+        self.update_db_file_map(vbid, new_revision);
+
+        let old_file = get_db_file_name(&self.config.db_name, vbid, revision);
+        std::fs::remove_file(&old_file)?;
+        println!("Compacted {} into {}", old_file, new_file);
+
+        Ok(())
+    }
+
+    pub fn init_by_seqno_scan_context(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+    ) -> couchstore::Result<BySeqnoScanContext> {
+        let mut db = self.open_db(vbid, couchstore::DBOpenOptions::default().read_only())?;
 
         let couchstore::Header {
             update_seq,
             purge_seq,
             ..
         } = *self.read_header(&db);
-        // TODO: get from couchstore_changes_count
-        let count = 0;
 
-        let vb_state = self.read_vb_state(&mut db, vbid);
+        let count = db.changes_count(start_seqno, u64::MAX)?;
+
+        let vb_state = self.read_vb_state(&mut db, vbid)?;
 
-        BySeqnoScanContext {
+        Ok(BySeqnoScanContext {
             vbid,
             db,
             start_seqno,
@@ -243,7 +399,7 @@ impl CouchKVStore {
             documnent_filter: DocumentFilter::AllItems,
             vbucket_state: vb_state,
             document_count: count,
-        }
+        })
     }
 }
 
@@ -259,6 +415,14 @@ pub struct BySeqnoScanContext {
     pub document_count: u64,
 }
 
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompactionConfig {
+    /// Tombstones with a seqno at or below this are purged
+    pub purge_before_seq: u64,
+    /// Drop items whose expiry time has passed
+    pub drop_expired: bool,
+}
+
 pub enum ValueFilter {
     KeysOnly,
     ValuesCompressed,
@@ -290,22 +454,72 @@ pub struct Metadata {
 }
 
 impl Metadata {
-    pub fn decode<R: io::Read>(mut r: R) -> Self {
-        let cas = r.read_u64::<BigEndian>().unwrap();
-        let expiry_time = r.read_u32::<BigEndian>().unwrap();
-        let flags = r.read_u32::<LittleEndian>().unwrap();
-        let flex_code = r.read_u8().unwrap();
-        let data_type = DataType::try_from(r.read_u8().unwrap()).unwrap();
-        Metadata {
+    pub const ON_DISK_SIZE: usize = 18;
+
+    /// Flex code of the metadata layout written by [Metadata::encode]
+    pub const FLEX_META_CODE: u8 = 0x01;
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::ON_DISK_SIZE);
+        buf.extend_from_slice(&self.cas.to_be_bytes());
+        buf.extend_from_slice(&self.expiry_time.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_le_bytes());
+        buf.push(self.flex_code);
+        buf.push(self.data_type.bits());
+        buf
+    }
+
+    pub fn decode<R: io::Read>(mut r: R) -> couchstore::Result<Self> {
+        let corrupt = |_| couchstore::Error::Corrupt("metadata");
+        let cas = r.read_u64::<BigEndian>().map_err(corrupt)?;
+        let expiry_time = r.read_u32::<BigEndian>().map_err(corrupt)?;
+        let flags = r.read_u32::<LittleEndian>().map_err(corrupt)?;
+        let flex_code = r.read_u8().map_err(corrupt)?;
+        let data_type = DataType::try_from(r.read_u8().map_err(corrupt)?)
+            .map_err(|_| couchstore::Error::Corrupt("metadata datatype"))?;
+        Ok(Metadata {
             cas,
             expiry_time,
             flags,
             flex_code,
             data_type,
-        }
+        })
     }
 }
 
+/// The document and `DocInfo` couchstore stores for an item. Whether the
+/// body is compressed on disk is recorded in the content meta rather than the
+/// datatype, as values are decompressed when they're read back.
+fn item_to_doc(item: &Item) -> (Option<couchstore::Doc>, couchstore::DocInfo) {
+    let metadata = Metadata {
+        cas: item.cas,
+        expiry_time: item.expiry_time,
+        flags: item.flags,
+        flex_code: Metadata::FLEX_META_CODE,
+        data_type: item.data_type - DataType::SNAPPY,
+    };
+
+    let doc = match (&item.value, item.deleted) {
+        (Some(value), false) => Some(couchstore::Doc {
+            id: item.key.clone(),
+            data: value.clone(),
+        }),
+        _ => None,
+    };
+
+    let info = couchstore::DocInfo {
+        id: item.key.clone(),
+        db_seq: item.by_seqno,
+        rev_seq: item.rev_seqno,
+        rev_meta: metadata.encode(),
+        deleted: item.deleted,
+        content_meta: couchstore::ContentMetaFlag::IS_COMPRESSED,
+        ..Default::default()
+    };
+
+    (doc, info)
+}
+
 fn discover_db_files(dir: &str) -> Vec<String> {
     let mut filenames = Vec::new();
     for entry in std::fs::read_dir(dir).unwrap() {
@@ -331,10 +545,10 @@ fn get_db_file_name(db_name: &str, vbid: Vbid, rev: u64) -> String {
 
 const LOCAL_DOC_KEY_VBSTATE: &str = "_local/vbstate";
 
-fn get_local_vb_state(db: &mut couchstore::Db) -> serde_json::Value {
-    let doc: couchstore::LocalDoc = db.open_local_document(LOCAL_DOC_KEY_VBSTATE).unwrap();
-    let json = doc.json.unwrap();
-    serde_json::from_slice(&json).unwrap()
+fn get_local_vb_state(db: &mut couchstore::Db) -> couchstore::Result<serde_json::Value> {
+    let doc: couchstore::LocalDoc = db.open_local_document(LOCAL_DOC_KEY_VBSTATE)?;
+    let json = doc.json.ok_or(couchstore::Error::DocNotFound)?;
+    serde_json::from_slice(&json).map_err(|_| couchstore::Error::Corrupt(LOCAL_DOC_KEY_VBSTATE))
 }
 
 #[cfg(test)]
@@ -352,4 +566,36 @@ mod test {
         };
         CouchKVStore::new(config);
     }
+
+    #[test]
+    fn test_compact_db() {
+        let dir = std::env::temp_dir().join(format!("kvstore-compact-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy(
+            "../test-data/travel-sample/0.couch.1",
+            dir.join("0.couch.1"),
+        )
+        .unwrap();
+
+        let config = CouchKVStoreConfig {
+            max_vbuckets: 1024,
+            db_name: dir.to_str().unwrap().to_string(),
+            max_shards: 1,
+            shard_id: 0,
+        };
+        let store = CouchKVStore::new(config);
+        let vbid = Vbid::new(0);
+
+        store.compact_db(vbid, CompactionConfig::default()).unwrap();
+
+        assert!(!dir.join("0.couch.1").exists());
+        assert!(dir.join("0.couch.2").exists());
+        assert_eq!(store.get_db_revision(vbid), 2);
+        let ctx = store.init_by_seqno_scan_context(vbid, 0).unwrap();
+        assert_eq!(ctx.document_count, 97);
+        assert_eq!(ctx.vbucket_state.state, crate::vbucket::State::Active);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
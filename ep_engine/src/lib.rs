@@ -1,6 +1,10 @@
+pub mod checkpoint_manager;
 pub mod ep_bucket;
+pub mod error;
 pub mod failover_table;
+pub mod flusher;
 pub mod hash_table;
+pub mod hlc;
 pub mod item;
 pub mod kv_shard;
 pub mod kv_store;
@@ -9,6 +13,8 @@ pub mod vbucket;
 pub mod vbucket_map;
 pub mod warmup;
 
+pub use error::{Error, Result};
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub max_vbuckets: u16,
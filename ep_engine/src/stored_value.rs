@@ -20,10 +20,10 @@ pub struct StoredValue {
 bitflags! {
     #[derive(Default, Debug, Clone, Copy)]
     pub struct StoredValueBits: u8 {
-        const IS_DIRTY = 0;
-        const IS_DELETED = 1;
-        const IS_RESIDENT = 2;
-        const IS_STALE = 3;
+        const IS_DIRTY = 1;
+        const IS_DELETED = 2;
+        const IS_RESIDENT = 4;
+        const IS_STALE = 8;
     }
 }
 
@@ -41,6 +41,43 @@ impl StoredValue {
         self.bits.remove(StoredValueBits::IS_DIRTY);
     }
 
+    pub fn mark_dirty(&mut self) {
+        self.bits.insert(StoredValueBits::IS_DIRTY);
+    }
+
+    /// Has the value changed since it was last persisted
+    pub fn is_dirty(&self) -> bool {
+        self.bits.contains(StoredValueBits::IS_DIRTY)
+    }
+
+    /// Is this a tombstone left by a delete
+    pub fn is_deleted(&self) -> bool {
+        self.bits.contains(StoredValueBits::IS_DELETED)
+    }
+
+    /// Turn the value into a tombstone for the next revision
+    pub fn mark_deleted(&mut self) {
+        self.value = None;
+        self.rev_seqno += 1;
+        self.bits.insert(StoredValueBits::IS_DELETED);
+        self.mark_dirty();
+    }
+
+    /// The item to persist for this value
+    pub fn to_item(&self, key: Vec<u8>) -> Item {
+        Item {
+            key,
+            value: self.value.clone(),
+            cas: self.cas,
+            expiry_time: self.expiry_time,
+            flags: self.flags,
+            by_seqno: self.by_seqno,
+            rev_seqno: self.rev_seqno,
+            data_type: self.data_type,
+            deleted: self.is_deleted(),
+        }
+    }
+
     pub fn mark_resident(&mut self) {
         self.bits.insert(StoredValueBits::IS_RESIDENT);
     }
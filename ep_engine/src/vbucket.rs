@@ -1,5 +1,6 @@
 use crate::{
-    failover_table::FailoverTable, hash_table::HashTable, item::Item, stored_value::StoredValue,
+    checkpoint_manager::CheckpointManager, error::Error, failover_table::FailoverTable,
+    hash_table::HashTable, hlc::Hlc, item::Item, stored_value::StoredValue,
 };
 use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::{Mutex, MutexGuard};
@@ -16,18 +17,31 @@ pub struct VBucket {
     pub id: Vbid,
     pub hash_table: Mutex<HashTable>,
     state: AtomicCell<State>,
-    _failover_table: FailoverTable,
+    failover_table: FailoverTable,
+    checkpoint_manager: CheckpointManager,
+    hlc: Hlc,
     // Can state just be inside the mutex??
     state_lock: Mutex<()>,
 }
 
 impl VBucket {
-    pub fn new(id: Vbid, state: State, failover_table: FailoverTable) -> Self {
+    /// A vBucket whose next mutation follows `high_seqno` and `max_cas`, the
+    /// last seqno and largest CAS persisted
+    pub fn new(
+        id: Vbid,
+        state: State,
+        failover_table: FailoverTable,
+        high_seqno: u64,
+        max_cas: u64,
+        hlc_epoch: i64,
+    ) -> Self {
         Self {
             id,
             hash_table: Mutex::new(Default::default()),
             state: AtomicCell::new(state),
-            _failover_table: failover_table,
+            failover_table,
+            checkpoint_manager: CheckpointManager::new(high_seqno),
+            hlc: Hlc::new(max_cas, hlc_epoch),
             state_lock: Mutex::new(()),
         }
     }
@@ -47,14 +61,96 @@ impl VBucket {
 
     fn set_state_unlocked(&self, state: State) {
         self.state.store(state);
+        self.checkpoint_manager.queue_set_vbucket_state();
+    }
+
+    pub fn failover_table(&self) -> &FailoverTable {
+        &self.failover_table
+    }
+
+    pub fn checkpoint_manager(&self) -> &CheckpointManager {
+        &self.checkpoint_manager
+    }
+
+    pub fn hlc(&self) -> &Hlc {
+        &self.hlc
+    }
+
+    /// The seqno of the last mutation
+    pub fn high_seqno(&self) -> u64 {
+        self.checkpoint_manager.high_seqno()
+    }
+
+    /// The largest CAS given to a mutation
+    pub fn max_cas(&self) -> u64 {
+        self.hlc.max_hlc()
     }
 
     pub fn insert_from_warmup(&self, item: Item) {
+        // The max CAS persisted may have been invalid, in which case it's
+        // rebuilt from the items
+        self.hlc.set_max_hlc(item.cas);
         self.hash_table.lock().insert_from_warmup(item);
     }
 
+    /// The live value of `key`, tombstones are treated as missing
     pub fn get(&self, key: &[u8]) -> Option<StoredValue> {
-        self.hash_table.lock().map.get(key).cloned()
+        self.hash_table
+            .lock()
+            .map
+            .get(key)
+            .filter(|value| !value.is_deleted())
+            .cloned()
+    }
+
+    /// Store the item in memory and queue it for persistence, giving it the
+    /// next seqno and CAS. If the item has a CAS, it must match that of the
+    /// current value.
+    pub fn set(&self, mut item: Item) -> Result<StoredValue, Error> {
+        // Hold the hash table lock while queueing, so the seqnos and CAS
+        // values of a key's revisions are in the same order as the revisions
+        let mut hash_table = self.hash_table.lock();
+        if item.cas != 0 {
+            check_cas(hash_table.map.get(&item.key), item.cas)?;
+        }
+        item.cas = self.hlc.next_hlc();
+
+        let key = item.key.clone();
+        let value = hash_table.set(item);
+        value.by_seqno = self.checkpoint_manager.queue_dirty(value.to_item(key));
+        Ok(value.clone())
+    }
+
+    /// Delete `key` in memory and queue the tombstone, which is returned, for
+    /// persistence. A non-zero `cas` must match that of the current value.
+    pub fn delete(&self, key: &[u8], cas: u64) -> Result<StoredValue, Error> {
+        let mut hash_table = self.hash_table.lock();
+        check_cas(hash_table.map.get(key), cas)?;
+
+        let value = hash_table.delete(key).ok_or(Error::KeyNotFound)?;
+        value.cas = self.hlc.next_hlc();
+        value.by_seqno = self
+            .checkpoint_manager
+            .queue_dirty(value.to_item(key.to_vec()));
+        Ok(value.clone())
+    }
+
+    /// Record that the items have been persisted
+    pub fn mark_clean(&self, items: &[Item]) {
+        let mut hash_table = self.hash_table.lock();
+        for item in items {
+            hash_table.mark_clean(item);
+        }
+    }
+}
+
+/// Check the CAS a client expects a key to have, where 0 matches any live
+/// value
+fn check_cas(value: Option<&StoredValue>, cas: u64) -> Result<(), Error> {
+    match value.filter(|value| !value.is_deleted()) {
+        None => Err(Error::KeyNotFound),
+        Some(value) if cas != 0 && value.cas != cas => Err(Error::KeyExists),
+        Some(_) => Ok(()),
     }
 }
 
@@ -200,6 +296,34 @@ pub struct VBucketState {
     pub replication_topology: serde_json::Value,
 }
 
+impl VBucketState {
+    /// The state written for a vBucket that has never been persisted
+    pub fn new(state: State) -> Self {
+        Self {
+            max_deleted_seqno: 0,
+            high_seqno: 0,
+            purge_seqno: 0,
+            snap_start: 0,
+            snap_end: 0,
+            max_cas: 0,
+            hlc_epoch: 0,
+            might_contain_xattrs: false,
+            namespaces_supported: true,
+            version: 4,
+            completed_seqno: 0,
+            prepared_seqno: 0,
+            high_prepared_seqno: 0,
+            max_visible_seqno: 0,
+            on_disk_prepares: 0,
+            on_disk_prepare_bytes: 0,
+            checkpoint_type: CheckpointType::default(),
+            state,
+            failover_table: serde_json::Value::Null,
+            replication_topology: serde_json::Value::Null,
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum CheckpointType {
     #[default]
@@ -2,17 +2,18 @@ use crate::{
     ep_bucket::EPBucketPtr,
     failover_table::FailoverTable,
     item::Item,
-    kv_store::Metadata,
+    kv_store::{CouchKVStore, Metadata},
     vbucket::{self, VBucket, VBucketPtr, VBucketState, Vbid},
     Config,
 };
+use couchstore::DocInfo;
 use dashmap::DashMap;
 use memcached_codec::DataType;
 use rand::{
     distributions::{Bernoulli, Distribution},
     SeedableRng,
 };
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::ControlFlow};
 
 pub struct Warmup {
     store: EPBucketPtr,
@@ -153,7 +154,14 @@ impl Warmup {
                 };
                 let _shard = self.store.get_vbuckets().get_shard_by_vb_id(vbid);
                 // TODO: get collection manifest
-                let vb = VBucketPtr::new(VBucket::new(vbid, state.state, table));
+                let vb = VBucketPtr::new(VBucket::new(
+                    vbid,
+                    state.state,
+                    table,
+                    state.high_seqno as u64,
+                    state.max_cas,
+                    state.hlc_epoch,
+                ));
 
                 self.warmed_up_vbuckets.insert(vbid, vb.clone());
 
@@ -183,7 +191,7 @@ impl Warmup {
             let locked_vb = self.store.get_locked_vbucket(vbid);
             assert!(locked_vb.is_none());
 
-            // TODO: self.checkpoint_manager.queue_set_vb_state();
+            vb.checkpoint_manager().queue_set_vbucket_state();
 
             {
                 // Note this lock is here for correctness - the VBucket is not
@@ -194,13 +202,16 @@ impl Warmup {
                 }
             }
 
-            self.store.flush_vbucket_unlocked(&locked_vb);
-
             self.store.vbucket_map.add_bucket(vb);
         }
 
         if shard_id == self.store.vbucket_map.shards.len() - 1 {
-            // TODO: start flusher
+            self.store.start_flusher();
+            // Persist the queued vBucket states now, rather than with the
+            // next client write
+            for vb in self.warmed_up_vbuckets.iter() {
+                self.store.notify_flusher(*vb.key());
+            }
             self.warmed_up_vbuckets.clear();
         }
     }
@@ -210,11 +221,16 @@ impl Warmup {
         let vbucket_map = &self.store.vbucket_map;
         let vbucket_filter = &self.shard_vb_ids[shard_id];
         for &vbid in vbucket_filter {
-            let mut ctx = store.init_by_seqno_scan_context(vbid, 0);
-            // TODO: Do this properly (in batches) like kv_engine
-            ctx.db.changes_since(0, |_, doc_info| {
+            if store.is_vbucket_failed(vbid) {
+                continue;
+            }
+            let result = scan_in_batches(store, vbid, |_, doc_info| {
+                // Deleted items aren't kept in the hash table
+                if doc_info.deleted {
+                    return Ok(());
+                }
                 let vb = vbucket_map.get_bucket(vbid).unwrap();
-                let metadata = Metadata::decode(&doc_info.rev_meta[..]);
+                let metadata = Metadata::decode(&doc_info.rev_meta[..])?;
                 let item = Item {
                     key: doc_info.id,
                     value: None,
@@ -224,9 +240,14 @@ impl Warmup {
                     by_seqno: doc_info.db_seq,
                     rev_seqno: doc_info.rev_seq,
                     data_type: metadata.data_type,
+                    deleted: false,
                 };
                 vb.insert_from_warmup(item);
+                Ok(())
             });
+            if let Err(err) = result {
+                store.mark_vbucket_failed(vbid, &err);
+            }
         }
     }
 
@@ -235,59 +256,143 @@ impl Warmup {
         let vbucket_map = &self.store.vbucket_map;
         let vbucket_filter = &self.shard_vb_ids[shard_id];
         for &vbid in vbucket_filter {
-            let mut ctx = store.init_by_seqno_scan_context(vbid, 0);
-            // TODO: Do this properly (in batches) like kv_engine
-            ctx.db.changes_since(0, move |db, doc_info| {
-                let doc = if let Some(doc) = db.open_doc_with_docinfo(
-                    &doc_info,
-                    couchstore::OpenOptions::DECOMPRESS_DOC_BODIES,
-                ) {
-                    doc
-                } else {
-                    return;
-                };
+            if store.is_vbucket_failed(vbid) {
+                continue;
+            }
+            let result = scan_in_batches(store, vbid, |db, doc_info| {
+                if doc_info.deleted {
+                    return Ok(());
+                }
+                let doc =
+                    match db.open_doc_with_docinfo(&doc_info, couchstore::OpenOptions::empty()) {
+                        Ok(doc) => doc,
+                        Err(couchstore::Error::DocNotFound) => return Ok(()),
+                        Err(err) => return Err(err),
+                    };
 
                 let vb = vbucket_map.get_bucket(vbid).unwrap();
-                let metadata = Metadata::decode(&doc_info.rev_meta[..]);
+                let metadata = Metadata::decode(&doc_info.rev_meta[..])?;
 
                 let mut data_type = metadata.data_type;
 
                 // TODO: Get from bucket compression
                 let fetch_compressed = true;
 
+                // Some files hold snappy bodies without the content meta
+                // saying so, which mustn't be compressed a second time
+                let is_compressed = doc_info
+                    .content_meta
+                    .contains(couchstore::ContentMetaFlag::IS_COMPRESSED)
+                    || is_snappy(&doc.data);
+
+                // Keep values snappy compressed in memory, using the body as
+                // it was stored on disk if it's compressed already
+                let value = match (fetch_compressed, is_compressed) {
+                    (true, true) => doc.data,
+                    (true, false) => snap::raw::Encoder::new()
+                        .compress_vec(&doc.data)
+                        .map_err(|_| couchstore::Error::Corrupt("document body"))?,
+                    (false, true) => snap::raw::Decoder::new()
+                        .decompress_vec(&doc.data)
+                        .map_err(|_| couchstore::Error::Corrupt("snappy compressed body"))?,
+                    (false, false) => doc.data,
+                };
+
                 if fetch_compressed {
                     data_type.insert(DataType::SNAPPY)
                 }
 
                 let item = Item {
                     key: doc_info.id,
-                    value: Some(doc.data),
+                    value: Some(value),
                     cas: metadata.cas,
                     expiry_time: metadata.expiry_time,
                     flags: metadata.flags,
                     by_seqno: doc_info.db_seq,
                     rev_seqno: doc_info.rev_seq,
                     data_type,
+                    deleted: false,
                 };
                 vb.insert_from_warmup(item);
+                Ok(())
             });
+            if let Err(err) = result {
+                store.mark_vbucket_failed(vbid, &err);
+            }
         }
     }
 }
 
+/// Is the body valid snappy
+fn is_snappy(body: &[u8]) -> bool {
+    snap::raw::decompress_len(body).is_ok_and(|len| {
+        snap::raw::Decoder::new()
+            .decompress(body, &mut vec![0; len])
+            .is_ok()
+    })
+}
+
+/// Number of items read from disk before a warmup scan pauses
+const WARMUP_BATCH_SIZE: usize = 10_000;
+
+/// Scan every item in the vBucket's file, pausing after every
+/// [WARMUP_BATCH_SIZE] items so other work gets a chance to run.
+fn scan_in_batches(
+    store: &CouchKVStore,
+    vbid: Vbid,
+    mut on_item: impl FnMut(&mut couchstore::Db, DocInfo) -> couchstore::Result<()>,
+) -> couchstore::Result<()> {
+    let mut ctx = store.init_by_seqno_scan_context(vbid, 0)?;
+    let mut start = ctx.start_seqno;
+
+    loop {
+        let mut loaded = 0;
+        let flow = ctx
+            .db
+            .changes_since_range(start, ctx.update_seqno, |db, doc_info| {
+                on_item(db, doc_info)?;
+                loaded += 1;
+                if loaded == WARMUP_BATCH_SIZE {
+                    Ok(ControlFlow::Break(()))
+                } else {
+                    Ok(ControlFlow::Continue(()))
+                }
+            })?;
+
+        match flow {
+            ControlFlow::Continue(()) => return Ok(()),
+            ControlFlow::Break(next) => start = next,
+        }
+
+        std::thread::yield_now();
+    }
+}
+
 #[cfg(test)]
 mod test {
     use memcached_codec::DataType;
 
     use super::*;
-    use crate::{ep_bucket::EPBucket, vbucket};
+    use crate::{
+        ep_bucket::{default_collection_key, v_bucket_hash, EPBucket},
+        vbucket,
+    };
 
     #[test]
     fn test_warmup() {
+        // Warmup persists the vBucket states, so work on a copy
+        let dir = std::env::temp_dir().join(format!("warmup-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for entry in std::fs::read_dir("../test-data/travel-sample").unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), dir.join(entry.file_name())).unwrap();
+        }
+
         let config = Config {
             max_vbuckets: 1024,
             max_shards: 1,
-            dbname: "../test-data/travel-sample".to_string(),
+            dbname: dir.to_str().unwrap().to_string(),
         };
         let store = EPBucket::new(config.clone());
         let mut warmup = Warmup::new(store.clone(), config);
@@ -301,10 +406,22 @@ mod test {
         );
         assert_eq!(warmup.store.vbucket_map.get_num_alive_vbuckets(), 1024);
 
-        let val = store.get(Vec::from("landmark_25686")).unwrap();
+        let key = b"landmark_25686";
+        let vbid = Vbid::from(v_bucket_hash(key, 1024));
+        let val = store.get(vbid, &default_collection_key(key)).unwrap();
         assert_eq!(val.data_type, DataType::SNAPPY | DataType::JSON);
         assert_eq!(val.cas, 1693175504558817280);
-        assert!(val.value.is_some());
         assert!(val.is_resident());
+
+        // The body is stored snappy compressed, without the content meta
+        // saying so, and is compressed once in memory
+        let value = snap::raw::Decoder::new()
+            .decompress_vec(val.value.as_ref().unwrap())
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&value).unwrap();
+        assert_eq!(json["type"], "landmark");
+
+        store.stop_flusher().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -4,12 +4,13 @@ use std::{
 };
 
 use bytes::BytesMut;
-use memcached_codec::{McbpCodec, McbpMessage};
+use memcached_codec::{McbpCodec, McbpMessage, Status};
 use tokio_util::codec::{Decoder, Encoder};
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::operations::{
     dcp::DcpControlRequest,
+    error_map::{ErrorMap, GetErrorMapRequest, GetErrorMapResponse},
     hello::{HelloRequest, HelloResponse},
     sasl_auth::{SaslAuthRequest, SaslAuthResponse},
 };
@@ -19,6 +20,9 @@ pub struct Connection {
     read_buffer: BytesMut,
     write_buffer: BytesMut,
     mcbp_codec: McbpCodec,
+    /// None until it has been fetched, then None inside if the server has no
+    /// error map
+    error_map: Option<Option<ErrorMap>>,
 }
 
 impl Connection {
@@ -28,6 +32,7 @@ impl Connection {
             read_buffer: BytesMut::new(),
             write_buffer: BytesMut::new(),
             mcbp_codec: McbpCodec::new(),
+            error_map: None,
         }
     }
 
@@ -74,7 +79,74 @@ impl Connection {
         SaslAuthResponse::decode(&resp).unwrap()
     }
 
+    /// The server's error map, which is only fetched the first time. None if
+    /// the server doesn't have one.
+    pub fn error_map(&mut self) -> Option<&ErrorMap> {
+        if self.error_map.is_none() {
+            let req = GetErrorMapRequest {
+                version: ErrorMap::VERSION,
+            };
+            self.send(req.encode());
+            let resp = self.recv();
+            let error_map = match GetErrorMapResponse::decode(&resp) {
+                Ok(resp) => Some(resp.error_map),
+                Err(err) => {
+                    warn!("No error map: {}", err);
+                    None
+                }
+            };
+            self.error_map = Some(error_map);
+        }
+        self.error_map.as_ref().unwrap().as_ref()
+    }
+
+    /// Can a request that failed with the status be retried. Statuses newer
+    /// than this client are looked up in the server's error map, and aren't
+    /// retried if it doesn't have one.
+    pub fn is_retryable(&mut self, status: Status) -> bool {
+        match status {
+            Status::Unknown(_) => self
+                .error_map()
+                .is_some_and(|error_map| error_map.is_retryable(status)),
+            status => status.is_retryable(),
+        }
+    }
+
     pub fn send_dcp_control(&mut self, key: String, value: String) {
         self.send(DcpControlRequest { key, value }.encode());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+
+    use memcached_codec::{Magic, McbpMessageBuilder, Opcode};
+
+    use super::*;
+
+    #[test]
+    fn test_no_error_map() {
+        // A server that predates error maps
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut conn = Connection::new(listener.accept().unwrap().0);
+            let req = conn.recv();
+            assert_eq!(req.opcode, Opcode::GetErrorMap);
+            conn.send(
+                McbpMessageBuilder::new(Opcode::GetErrorMap)
+                    .magic(Magic::ClientResponse)
+                    .status(Status::UnknownCommand)
+                    .build(),
+            );
+        });
+
+        let mut conn = Connection::new(TcpStream::connect(addr).unwrap());
+        assert!(!conn.is_retryable(Status::Unknown(0xe0)));
+        // Only asked once
+        assert!(conn.error_map().is_none());
+        assert!(conn.is_retryable(Status::Etmpfail));
+        server.join().unwrap();
+    }
+}
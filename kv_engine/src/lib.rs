@@ -1,2 +1,3 @@
 pub mod connection;
 pub mod operations;
+pub mod server;
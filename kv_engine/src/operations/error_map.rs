@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+
+use bytes::{Buf, BufMut, BytesMut};
+use memcached_codec::{McbpDecodeError, McbpMessage, McbpMessageBuilder, Opcode, Status};
+use serde::{Deserialize, Deserializer, Serializer};
+
+/// The error map describes every status the server may return, so a client
+/// can handle statuses newer than itself
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ErrorMap {
+    pub version: u16,
+    pub revision: u32,
+    /// The errors by status, which are hex strings in the JSON
+    #[serde(
+        serialize_with = "serialize_hex_keys",
+        deserialize_with = "deserialize_hex_keys"
+    )]
+    pub errors: HashMap<u16, ErrorCode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ErrorCode {
+    pub name: String,
+    #[serde(rename = "desc")]
+    pub description: String,
+    pub attrs: Vec<ErrorAttribute>,
+    /// How to retry the request, only in version 2 and above
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetrySpec>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorAttribute {
+    Success,
+    ItemOnly,
+    InvalidInput,
+    FetchConfig,
+    ConnStateInvalidated,
+    Auth,
+    SpecialHandling,
+    Support,
+    Temp,
+    Internal,
+    RetryNow,
+    RetryLater,
+    Subdoc,
+    Dcp,
+    ItemDeleted,
+    ItemLocked,
+    RateLimit,
+    SystemConstraint,
+    /// An attribute newer than this client
+    #[serde(other)]
+    Unknown,
+}
+
+/// How long to wait between retries, all durations are in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RetrySpec {
+    pub strategy: RetryStrategy,
+    pub interval: u32,
+    /// How long to wait before the first retry
+    pub after: u32,
+    /// The longest wait between retries, 0 for no limit
+    pub ceil: u32,
+    /// How long to keep retrying for
+    pub max_duration: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetryStrategy {
+    Constant,
+    Linear,
+    Exponential,
+}
+
+impl ErrorMap {
+    /// The latest version of the error map
+    pub const VERSION: u16 = 2;
+
+    pub fn get(&self, status: Status) -> Option<&ErrorCode> {
+        self.errors.get(&u16::from(status))
+    }
+
+    /// Does the map say requests that fail with the status can be retried
+    pub fn is_retryable(&self, status: Status) -> bool {
+        self.get(status).is_some_and(|code| {
+            code.attrs
+                .iter()
+                .any(|attr| matches!(attr, ErrorAttribute::RetryNow | ErrorAttribute::RetryLater))
+        })
+    }
+
+    /// The map as a client that only understands `version` expects it
+    pub fn downgrade(&self, version: u16) -> ErrorMap {
+        let mut map = self.clone();
+        if version < 2 {
+            map.version = version;
+            for code in map.errors.values_mut() {
+                code.retry = None;
+            }
+        }
+        map
+    }
+}
+
+fn serialize_hex_keys<S>(errors: &HashMap<u16, ErrorCode>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    // Sorted, so the map is the same every time it's sent
+    let mut errors = errors.iter().collect::<Vec<_>>();
+    errors.sort_by_key(|(&status, _)| status);
+    s.collect_map(
+        errors
+            .into_iter()
+            .map(|(status, code)| (format!("{:x}", status), code)),
+    )
+}
+
+fn deserialize_hex_keys<'de, D>(d: D) -> Result<HashMap<u16, ErrorCode>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    HashMap::<String, ErrorCode>::deserialize(d)?
+        .into_iter()
+        .map(|(status, code)| {
+            u16::from_str_radix(&status, 16)
+                .map(|status| (status, code))
+                .map_err(serde::de::Error::custom)
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct GetErrorMapRequest {
+    /// The latest version the client understands
+    pub version: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct GetErrorMapResponse {
+    pub error_map: ErrorMap,
+}
+
+impl GetErrorMapRequest {
+    pub fn encode(&self) -> McbpMessage {
+        let mut value = BytesMut::with_capacity(2);
+        value.put_u16(self.version);
+        McbpMessageBuilder::new(Opcode::GetErrorMap)
+            .value(value.freeze())
+            .build()
+    }
+
+    pub fn decode(message: &McbpMessage) -> Result<GetErrorMapRequest, McbpDecodeError> {
+        if message.value.len() != 2 {
+            return Err(McbpDecodeError::InvalidBodyLength(message.value.len()));
+        }
+        Ok(GetErrorMapRequest {
+            version: (&message.value[..]).get_u16(),
+        })
+    }
+}
+
+impl GetErrorMapResponse {
+    pub fn encode(&self) -> McbpMessage {
+        McbpMessageBuilder::new(Opcode::GetErrorMap)
+            .status(Status::Success)
+            .value(serde_json::to_vec(&self.error_map).unwrap())
+            .build()
+    }
+
+    pub fn decode(resp: &McbpMessage) -> Result<GetErrorMapResponse, McbpDecodeError> {
+        // Servers without an error map fail the request
+        let status = resp.try_status()?;
+        if status != Status::Success {
+            return Err(McbpDecodeError::UnexpectedStatus(status));
+        }
+        let error_map = serde_json::from_slice(&resp.value)
+            .map_err(|err| McbpDecodeError::InvalidValue(err.to_string()))?;
+        Ok(GetErrorMapResponse { error_map })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_map() {
+        let json = r#"{
+            "version": 2,
+            "revision": 1,
+            "errors": {
+                "86": {
+                    "name": "ETMPFAIL",
+                    "desc": "Temporary failure",
+                    "attrs": ["temp", "retry-later"],
+                    "retry": {
+                        "strategy": "exponential",
+                        "interval": 7,
+                        "after": 7,
+                        "ceil": 500,
+                        "max-duration": 2000
+                    }
+                },
+                "d8": {
+                    "name": "NEW_ERROR",
+                    "desc": "Newer than this client",
+                    "attrs": ["item-only", "some-new-attr"]
+                }
+            }
+        }"#;
+        let map: ErrorMap = serde_json::from_str(json).unwrap();
+
+        let etmpfail = map.get(Status::Etmpfail).unwrap();
+        assert_eq!(etmpfail.description, "Temporary failure");
+        assert_eq!(etmpfail.retry.unwrap().strategy, RetryStrategy::Exponential);
+        assert!(map.is_retryable(Status::Etmpfail));
+
+        let unknown = Status::from(0xd8);
+        assert_eq!(
+            map.get(unknown).unwrap().attrs,
+            vec![ErrorAttribute::ItemOnly, ErrorAttribute::Unknown]
+        );
+        assert!(!map.is_retryable(unknown));
+
+        let v1 = map.downgrade(1);
+        assert_eq!(v1.version, 1);
+        assert!(v1.get(Status::Etmpfail).unwrap().retry.is_none());
+
+        let reencoded: ErrorMap =
+            serde_json::from_slice(&serde_json::to_vec(&map).unwrap()).unwrap();
+        assert_eq!(reencoded.version, map.version);
+        assert_eq!(reencoded.get(Status::Etmpfail), map.get(Status::Etmpfail));
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let resp = McbpMessageBuilder::new(Opcode::GetErrorMap)
+            .status(Status::UnknownCommand)
+            .build();
+        assert!(matches!(
+            GetErrorMapResponse::decode(&resp),
+            Err(McbpDecodeError::UnexpectedStatus(Status::UnknownCommand))
+        ));
+
+        let resp = McbpMessageBuilder::new(Opcode::GetErrorMap)
+            .status(Status::Success)
+            .value("{")
+            .build();
+        assert!(matches!(
+            GetErrorMapResponse::decode(&resp),
+            Err(McbpDecodeError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_server_error_map() {
+        let map: ErrorMap = serde_json::from_str(include_str!("../../error_map_v2.json")).unwrap();
+        assert_eq!(map.version, ErrorMap::VERSION);
+
+        // Every status the codec knows is described
+        for code in 0..=u16::MAX {
+            let status = Status::from(code);
+            if !matches!(status, Status::Unknown(_)) {
+                let error = map.get(status).unwrap();
+                assert_eq!(map.is_retryable(status), error.retry.is_some());
+                // The same answer whether or not the client knows the status
+                assert_eq!(
+                    map.is_retryable(status),
+                    status.is_retryable(),
+                    "{:?}",
+                    status
+                );
+            }
+        }
+        assert!(map.is_retryable(Status::Etmpfail));
+        assert!(!map.is_retryable(Status::KeyNotFound));
+    }
+}
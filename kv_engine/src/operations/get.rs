@@ -58,8 +58,9 @@ impl GetResponse {
         if extras.len() == 4 {
             flags = extras.get_u32();
         }
+        let status = resp.try_status()?;
         Ok(GetResponse {
-            value: Some(resp.value.clone()),
+            value: status.is_success().then(|| resp.value.clone()),
             flags,
             cas: resp.cas,
             data_type: resp.data_type,
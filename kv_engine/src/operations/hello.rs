@@ -31,6 +31,7 @@ impl HelloRequest {
             Feature::TcpNodelay,
             Feature::Json,
             Feature::Datatype,
+            Feature::Xerror,
             Feature::Duplex,
             Feature::Collections,
         ]
@@ -1,7 +1,9 @@
 pub mod cluster_config;
 pub mod dcp;
+pub mod error_map;
 pub mod get;
 pub mod hello;
+pub mod remove;
 pub mod sasl_auth;
 pub mod select_bucket;
 pub mod set;
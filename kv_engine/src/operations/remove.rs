@@ -0,0 +1,48 @@
+use bytes::Bytes;
+
+use memcached_codec::{Cas, McbpDecodeError, McbpMessage, McbpMessageBuilder, Opcode, Status};
+
+use super::v_bucket_hash;
+
+#[derive(Debug)]
+pub struct RemoveRequest {
+    pub key: Bytes,
+    pub vbucket: u16,
+    pub cas: Cas,
+}
+
+#[derive(Debug, Clone)]
+pub struct RemoveResponse {
+    pub cas: Cas,
+}
+
+impl RemoveRequest {
+    pub fn encode(&self) -> McbpMessage {
+        McbpMessageBuilder::new(Opcode::Remove)
+            .key(self.key.clone())
+            .vbucket(v_bucket_hash(&self.key, 1024))
+            .cas(self.cas)
+            .build()
+    }
+
+    pub fn decode(resp: &McbpMessage) -> Result<RemoveRequest, McbpDecodeError> {
+        Ok(RemoveRequest {
+            vbucket: resp.try_vbucket().unwrap(),
+            key: resp.key.clone(),
+            cas: resp.cas,
+        })
+    }
+}
+
+impl RemoveResponse {
+    pub fn encode(&self) -> McbpMessage {
+        McbpMessageBuilder::new(Opcode::Remove)
+            .status(Status::Success)
+            .cas(self.cas)
+            .build()
+    }
+
+    pub fn decode(resp: &McbpMessage) -> Result<RemoveResponse, McbpDecodeError> {
+        Ok(RemoveResponse { cas: resp.cas })
+    }
+}
@@ -1,4 +1,4 @@
-use bytes::Bytes;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use memcached_codec::{
     Cas, DataType, McbpDecodeError, McbpMessage, McbpMessageBuilder, Opcode, Status,
@@ -11,6 +11,10 @@ pub struct SetRequest {
     pub key: Bytes,
     pub value: Bytes,
     pub vbucket: u16,
+    pub flags: u32,
+    pub expiry: u32,
+    pub data_type: DataType,
+    pub cas: Cas,
 }
 
 #[derive(Debug, Clone)]
@@ -21,17 +25,34 @@ pub struct SetResponse {
 
 impl SetRequest {
     pub fn encode(&self) -> McbpMessage {
+        let mut extras = BytesMut::with_capacity(8);
+        extras.put_u32(self.flags);
+        extras.put_u32(self.expiry);
         McbpMessageBuilder::new(Opcode::Upsert)
             .key(self.key.clone())
+            .value(self.value.clone())
+            .extras(extras.freeze())
+            .data_type(self.data_type)
             .vbucket(v_bucket_hash(&self.key, 1024))
+            .cas(self.cas)
             .build()
     }
 
     pub fn decode(resp: &McbpMessage) -> Result<SetRequest, McbpDecodeError> {
+        let mut extras = &resp.extras[..];
+        let (mut flags, mut expiry) = (0, 0);
+        if extras.len() == 8 {
+            flags = extras.get_u32();
+            expiry = extras.get_u32();
+        }
         Ok(SetRequest {
             vbucket: resp.try_vbucket().unwrap(),
             key: resp.key.clone(),
             value: resp.value.clone(),
+            flags,
+            expiry,
+            data_type: resp.data_type,
+            cas: resp.cas,
         })
     }
 }
@@ -0,0 +1,616 @@
+use crate::operations::{
+    cluster_config::{ClusterConfig, GetClusterConfigResponse, Node, VBucketServerMap},
+    error_map::{ErrorMap, GetErrorMapRequest, GetErrorMapResponse},
+    get::{GetRequest, GetResponse},
+    hello::{HelloRequest, HelloResponse},
+    remove::{RemoveRequest, RemoveResponse},
+    select_bucket::{SelectBucketRequest, SelectBucketResponse},
+    set::{SetRequest, SetResponse},
+};
+use bytes::Bytes;
+use ep_engine::{
+    ep_bucket::{default_collection_key, EPBucket, EPBucketPtr},
+    item::Item,
+    vbucket::{self, Vbid},
+    warmup::Warmup,
+    Config,
+};
+use futures::{SinkExt, StreamExt};
+use memcached_codec::{
+    feature::Feature, Cas, DataType, Magic, McbpCodec, McbpDecodeError, McbpMessage,
+    McbpMessageBuilder, Opcode, Status,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+use tokio_util::codec::Framed;
+
+/// vBuckets per bucket, all of which are active on this node
+const NUM_VBUCKETS: u16 = 1024;
+
+const NUM_SHARDS: u16 = 4;
+
+/// Most connections served at once. Further clients wait in the listen
+/// backlog until a connection closes.
+const MAX_CONNECTIONS: usize = 1024;
+
+/// Accept connections until the future is dropped
+pub async fn serve(listener: TcpListener, buckets: Arc<Buckets>) {
+    let connection_slots = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+
+    loop {
+        let slot = connection_slots.clone().acquire_owned().await.unwrap();
+
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                println!("Failed to accept connection: {}", err);
+                continue;
+            }
+        };
+
+        let buckets = buckets.clone();
+
+        tokio::spawn(async move {
+            match handle_connection(stream, buckets).await {
+                Ok(()) => println!("{} disconnected", addr),
+                Err(err) => println!("Closing connection from {}: {}", addr, err),
+            }
+            drop(slot);
+        });
+    }
+}
+
+/// Every bucket served, keyed by the name clients select it with
+pub struct Buckets {
+    /// The directory holding a directory for each bucket
+    path: PathBuf,
+    buckets: Mutex<HashMap<String, EPBucketPtr>>,
+}
+
+impl Buckets {
+    /// Warm up every bucket in `path`
+    pub fn load(path: impl Into<PathBuf>) -> std::io::Result<Buckets> {
+        let path = path.into();
+        std::fs::create_dir_all(&path)?;
+
+        let mut buckets = HashMap::new();
+        for entry in std::fs::read_dir(&path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            buckets.insert(name.clone(), open_bucket(&path, &name));
+        }
+
+        Ok(Buckets {
+            path,
+            buckets: Mutex::new(buckets),
+        })
+    }
+
+    /// The bucket called `name`, which is created if it doesn't exist
+    fn get_or_create(&self, name: &str) -> std::io::Result<EPBucketPtr> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get(name) {
+            return Ok(bucket.clone());
+        }
+
+        std::fs::create_dir_all(self.path.join(name))?;
+
+        let bucket = open_bucket(&self.path, name);
+        buckets.insert(name.to_string(), bucket.clone());
+        Ok(bucket)
+    }
+
+    /// Persist every outstanding write. Every bucket is stopped even if some
+    /// fail to persist, returns whether they all succeeded.
+    pub fn stop_flushers(&self) -> bool {
+        let mut persisted = true;
+        for (name, bucket) in self.buckets.lock().unwrap().iter() {
+            if let Err(err) = bucket.stop_flusher() {
+                println!("Writes to bucket {} were lost: {}", name, err);
+                persisted = false;
+            }
+        }
+        persisted
+    }
+}
+
+/// Build the bucket and warm it up from disk. Every vBucket is active on
+/// this node, so any that have never been persisted are created empty.
+fn open_bucket(path: &Path, name: &str) -> EPBucketPtr {
+    let config = Config {
+        max_vbuckets: NUM_VBUCKETS,
+        max_shards: NUM_SHARDS,
+        dbname: path.join(name).to_string_lossy().into_owned(),
+    };
+    let bucket = EPBucket::new(config.clone());
+    Warmup::new(bucket.clone(), config).warmup();
+
+    for vbid in (0..NUM_VBUCKETS).map(Vbid::new) {
+        // A vBucket whose file couldn't be read is left out rather than
+        // replaced by an empty one
+        if bucket.get_vbucket(vbid).is_none() && !bucket.is_vbucket_failed(vbid) {
+            bucket.create_vbucket(vbid, vbucket::State::Active);
+        }
+    }
+
+    println!("Warmed up bucket {}", name);
+
+    bucket
+}
+
+/// Features a client can negotiate with HELLO
+const SUPPORTED_FEATURES: &[Feature] = &[
+    Feature::Datatype,
+    Feature::SelectBucket,
+    Feature::Json,
+    Feature::Xerror,
+    Feature::Snappy,
+];
+
+/// Uncompressed values at least this large are compressed for clients that
+/// negotiated snappy, None leaves them uncompressed
+const SNAPPY_COMPRESSION_THRESHOLD: Option<usize> = Some(256);
+
+struct State {
+    buckets: Arc<Buckets>,
+    bucket: Option<(String, EPBucketPtr)>,
+    /// Did the client negotiate snappy compressed values
+    snappy: bool,
+    /// Compressed values are inflated into this to check them, so it's only
+    /// allocated once per connection
+    snappy_buffer: Vec<u8>,
+}
+
+impl State {
+    fn new(buckets: Arc<Buckets>) -> Self {
+        State {
+            buckets,
+            bucket: None,
+            snappy: false,
+            snappy_buffer: Vec::new(),
+        }
+    }
+
+    fn bucket(&self) -> Result<&EPBucketPtr, Status> {
+        self.bucket
+            .as_ref()
+            .map(|(_, bucket)| bucket)
+            .ok_or(Status::NoBucket)
+    }
+}
+
+/// Serve requests until the client disconnects, or sends something that
+/// can't be decoded, in which case the connection is closed
+async fn handle_connection(
+    stream: TcpStream,
+    buckets: Arc<Buckets>,
+) -> Result<(), McbpDecodeError> {
+    let mut framed = Framed::new(stream, McbpCodec::new());
+    let mut state = State::new(buckets);
+
+    while let Some(req) = framed.next().await {
+        let req = req?;
+
+        println!("Received message: {:?}", req);
+        let to_send = handle_message(&mut state, &req).await;
+        if let Some(mut resp) = to_send {
+            resp.opaque = req.opaque;
+            resp.magic = if resp.framing_extras.is_empty() {
+                Magic::ClientResponse
+            } else {
+                Magic::AltClientResponse
+            };
+
+            println!("Sending message: {:?}", resp);
+
+            framed.send(resp).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_message(state: &mut State, message: &McbpMessage) -> Option<McbpMessage> {
+    let resp = match check_data_type(state, message) {
+        Ok(()) => dispatch(state, message).await,
+        Err(status) => Err(status),
+    };
+    let resp = resp.and_then(|resp| resp.map(|resp| encode_value(state, resp)).transpose());
+    match resp {
+        Ok(resp) => resp,
+        Err(status) => Some(
+            McbpMessageBuilder::new(message.opcode)
+                .status(status)
+                .build(),
+        ),
+    }
+}
+
+/// Only clients that negotiated snappy may send compressed values
+fn check_data_type(state: &State, message: &McbpMessage) -> Result<(), Status> {
+    if message.data_type.contains(DataType::SNAPPY) && !state.snappy {
+        return Err(Status::InvalidArguments);
+    }
+    Ok(())
+}
+
+/// Compressed values are stored as sent, so check they inflate first
+fn check_snappy(buffer: &mut Vec<u8>, value: &[u8]) -> Result<(), Status> {
+    let len = snap::raw::decompress_len(value).map_err(|_| Status::InvalidArguments)?;
+    buffer.resize(len, 0);
+    snap::raw::Decoder::new()
+        .decompress(value, buffer)
+        .map_err(|_| Status::InvalidArguments)?;
+    Ok(())
+}
+
+/// Compress or inflate the response's value to suit the client
+fn encode_value(state: &State, mut resp: McbpMessage) -> Result<McbpMessage, Status> {
+    if !state.snappy {
+        resp.decompress().map_err(|_| Status::InternalError)?;
+    } else if let Some(threshold) = SNAPPY_COMPRESSION_THRESHOLD {
+        if resp.opcode.is_compressible() && resp.value.len() >= threshold {
+            resp.compress();
+        }
+    }
+    Ok(resp)
+}
+
+/// Requests are served from memory on the connection's task, only those that
+/// go to disk are moved to a blocking thread
+async fn dispatch(state: &mut State, message: &McbpMessage) -> Result<Option<McbpMessage>, Status> {
+    let invalid = |_| Status::InvalidArguments;
+
+    let resp = match message.opcode {
+        Opcode::Get => {
+            let req = GetRequest::decode(message).map_err(invalid)?;
+            let bucket = state.bucket()?;
+            let stored = bucket
+                .get(Vbid::new(req.vbucket), &default_collection_key(&req.key))
+                .map_err(engine_status)?;
+            // Every value is loaded during warmup, there's no background
+            // fetch for evicted values yet
+            let value = stored.value.ok_or(Status::Etmpfail)?;
+            // Sent as stored, compressed or not, encode_value suits it to
+            // the client
+            GetResponse {
+                value: Some(Bytes::from(value)),
+                flags: stored.flags,
+                cas: Cas::from(stored.cas),
+                data_type: stored.data_type,
+            }
+            .encode()
+        }
+        Opcode::Upsert => {
+            let req = SetRequest::decode(message).map_err(invalid)?;
+            let vbid = Vbid::new(req.vbucket);
+            if req.data_type.contains(DataType::SNAPPY) {
+                check_snappy(&mut state.snappy_buffer, &req.value)?;
+            }
+            let bucket = state.bucket()?;
+            let mut item = Item::new(
+                default_collection_key(&req.key),
+                req.value.to_vec(),
+                req.flags,
+                req.expiry,
+                req.data_type,
+            );
+            // A non-zero CAS must match the current value's
+            item.cas = req.cas.into();
+            let stored = bucket.set(vbid, item).map_err(engine_status)?;
+            SetResponse {
+                cas: Cas::from(stored.cas),
+                data_type: DataType::RAW,
+            }
+            .encode()
+        }
+        Opcode::Remove => {
+            let req = RemoveRequest::decode(message).map_err(invalid)?;
+            let vbid = Vbid::new(req.vbucket);
+            let bucket = state.bucket()?;
+            let tombstone = bucket
+                .delete(vbid, &default_collection_key(&req.key), req.cas.into())
+                .map_err(engine_status)?;
+            RemoveResponse {
+                cas: Cas::from(tombstone.cas),
+            }
+            .encode()
+        }
+        Opcode::Hello => {
+            let req = HelloRequest::decode(message).map_err(invalid)?;
+            let supported_features = req
+                .features
+                .into_iter()
+                .filter(|feature| SUPPORTED_FEATURES.contains(feature))
+                .collect::<Vec<_>>();
+            state.snappy = supported_features.contains(&Feature::Snappy);
+            HelloResponse { supported_features }.encode()
+        }
+        Opcode::SelectBucket => {
+            let req = SelectBucketRequest::decode(message).map_err(invalid)?;
+            // Warms the bucket up from disk the first time it's selected
+            let buckets = state.buckets.clone();
+            let name = req.bucket.clone();
+            let bucket = tokio::task::spawn_blocking(move || buckets.get_or_create(&name))
+                .await
+                .map_err(|_| Status::InternalError)?
+                .map_err(|err| storage_status(err.into()))?;
+
+            state.bucket = Some((req.bucket, bucket));
+
+            SelectBucketResponse {}.encode()
+        }
+        Opcode::GetClusterConfig => {
+            let config = if state.bucket.is_some() {
+                default_bucket_config(state)
+            } else {
+                default_cluster_config()
+            };
+            GetClusterConfigResponse { config }.encode()
+        }
+        Opcode::SaslListMechs => McbpMessageBuilder::new(Opcode::SaslListMechs)
+            .value("PLAIN")
+            .build(),
+        Opcode::SaslAuth => McbpMessageBuilder::new(Opcode::SaslAuth)
+            .status(Status::Success)
+            .build(),
+        Opcode::GetErrorMap => {
+            let req = GetErrorMapRequest::decode(message).map_err(invalid)?;
+            if req.version == 0 {
+                return Err(Status::InvalidArguments);
+            }
+            GetErrorMapResponse {
+                error_map: error_map().downgrade(req.version),
+            }
+            .encode()
+        }
+        _ => {
+            println!("Unsupported opcode: {:?}", message.opcode);
+            return Err(Status::UnknownCommand);
+        }
+    };
+
+    Ok(Some(resp))
+}
+
+/// The error map sent to clients, describing every status the server returns
+fn error_map() -> &'static ErrorMap {
+    static ERROR_MAP: OnceLock<ErrorMap> = OnceLock::new();
+    ERROR_MAP.get_or_init(|| serde_json::from_str(include_str!("../error_map_v2.json")).unwrap())
+}
+
+/// Map an engine error to a response status
+fn engine_status(err: ep_engine::Error) -> Status {
+    match err {
+        ep_engine::Error::NotMyVbucket(_) => Status::NotMyVBucket,
+        ep_engine::Error::KeyNotFound => Status::KeyNotFound,
+        ep_engine::Error::KeyExists => Status::KeyExists,
+        ep_engine::Error::Storage(err) => storage_status(err),
+        ep_engine::Error::NotPersisted(_) => Status::InternalError,
+    }
+}
+
+/// Map a storage error to a response status so one bad vbucket file doesn't
+/// take down the connection
+fn storage_status(err: couchstore::Error) -> Status {
+    match err {
+        couchstore::Error::DocNotFound => Status::KeyNotFound,
+        err => {
+            println!("Storage error: {}", err);
+            Status::InternalError
+        }
+    }
+}
+
+fn default_bucket_config(state: &State) -> ClusterConfig {
+    let bucket = state.bucket.as_ref().unwrap().0.clone();
+    ClusterConfig {
+        rev: 1,
+        rev_epoch: 1,
+        bucket_capabilities_ver: Some(String::new()),
+        bucket_capabilities: Some(
+            vec![
+                "durableWrite",
+                "tombstonedUserXAttrs",
+                "couchapi",
+                "dcp.IgnorePurgedTombstones",
+                "dcp",
+                "cbhello",
+                "touch",
+                "cccp",
+                "xdcrCheckpointing",
+                "nodesExt",
+                "xattr",
+            ]
+            .into_iter()
+            .map(|s| s.to_string())
+            .collect(),
+        ),
+        name: Some(bucket.clone()),
+        uri: Some(format!(
+            "/pools/default/buckets/{bucket}?bucket_uuid=c4730ffcb639bd2c54d11944c80ffb31"
+        )),
+        streaming_uri: Some(format!(
+            "/pools/default/bucketsStreaming/{bucket}?bucket_uuid=c4730ffcb639bd2c54d11944c80ffb31"
+        )),
+        nodes: Some(vec![Node {
+            couch_api_base: format!(
+                "http://127.0.0.1:8092/{bucket}%2Bc4730ffcb639bd2c54d11944c80ffb31"
+            ),
+            hostname: Some("127.0.0.1:8091".to_string()),
+            ports: maplit::hashmap! {
+                "direct".to_string() =>11210,
+            },
+        }]),
+        node_locator: Some("vbucket".to_string()),
+        uuid: Some("c4730ffcb639bd2c54d11944c80ffb31".to_string()),
+        ddocs: None,
+        v_bucket_server_map: Some(VBucketServerMap {
+            hash_algorithm: "CRC".to_string(),
+            num_replicas: 0,
+            server_list: vec!["127.0.0.1:11210".to_string()],
+            v_bucket_map: vec![vec![0]; 1024],
+        }),
+    }
+}
+
+fn default_cluster_config() -> ClusterConfig {
+    ClusterConfig {
+        rev: 1,
+        rev_epoch: 1,
+        bucket_capabilities_ver: Some(String::new()),
+        bucket_capabilities: Some(
+            vec![
+                "durableWrite",
+                "tombstonedUserXAttrs",
+                "couchapi",
+                "dcp.IgnorePurgedTombstones",
+                "dcp",
+                "cbhello",
+                "touch",
+                "cccp",
+                "xdcrCheckpointing",
+                "nodesExt",
+                "xattr",
+            ]
+            .into_iter()
+            .map(|s| s.to_string())
+            .collect(),
+        ),
+        name: None,
+        uri: None,
+        streaming_uri: None,
+        nodes: Some(vec![Node {
+            couch_api_base: "".to_string(),
+            hostname: Some("127.0.0.1:8091".to_string()),
+            ports: maplit::hashmap! {
+                "direct".to_string() =>11210,
+            },
+        }]),
+        node_locator: Some("vbucket".to_string()),
+        uuid: Some("c4730ffcb639bd2c54d11944c80ffb31".to_string()),
+        ddocs: None,
+        v_bucket_server_map: Some(VBucketServerMap {
+            hash_algorithm: "CRC".to_string(),
+            num_replicas: 0,
+            server_list: vec!["127.0.0.1:11210".to_string()],
+            v_bucket_map: vec![vec![0]; 1024],
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn selected(buckets: &Arc<Buckets>, features: Vec<Feature>) -> State {
+        let mut state = State::new(buckets.clone());
+        let hello = HelloRequest {
+            features,
+            user_agent: "test".to_string(),
+        };
+        handle_message(&mut state, &hello.encode()).await.unwrap();
+        let select = SelectBucketRequest {
+            bucket: "default".to_string(),
+        };
+        let resp = handle_message(&mut state, &select.encode()).await.unwrap();
+        assert_eq!(resp.try_status().unwrap(), Status::Success);
+        state
+    }
+
+    async fn set(state: &mut State, key: &str, value: Vec<u8>, data_type: DataType) -> Status {
+        let req = SetRequest {
+            key: Bytes::from(key.to_string()),
+            value: Bytes::from(value),
+            vbucket: 0,
+            flags: 0,
+            expiry: 0,
+            data_type,
+            cas: Cas::from(0),
+        };
+        handle_message(state, &req.encode())
+            .await
+            .unwrap()
+            .try_status()
+            .unwrap()
+    }
+
+    async fn get(state: &mut State, key: &str) -> GetResponse {
+        let req = GetRequest {
+            key: Bytes::from(key.to_string()),
+            vbucket: 0,
+        };
+        let resp = handle_message(state, &req.encode()).await.unwrap();
+        GetResponse::decode(&resp).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_snappy_values() {
+        let dir = std::env::temp_dir().join(format!("kv-server-snappy-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let buckets = Arc::new(Buckets::load(&dir).unwrap());
+
+        let mut plain = selected(&buckets, vec![Feature::Datatype]).await;
+        let mut snappy = selected(&buckets, vec![Feature::Datatype, Feature::Snappy]).await;
+
+        let threshold = SNAPPY_COMPRESSION_THRESHOLD.unwrap();
+        let large = vec![b'a'; threshold];
+        let small = vec![b'a'; threshold - 1];
+        assert_eq!(
+            set(&mut plain, "large", large.clone(), DataType::RAW).await,
+            Status::Success
+        );
+        assert_eq!(
+            set(&mut plain, "small", small.clone(), DataType::RAW).await,
+            Status::Success
+        );
+
+        // Values at or above the threshold are compressed for snappy clients
+        let resp = get(&mut snappy, "large").await;
+        assert!(resp.data_type.contains(DataType::SNAPPY));
+        let value = resp.value.unwrap();
+        assert!(value.len() < large.len());
+        assert_eq!(
+            snap::raw::Decoder::new().decompress_vec(&value).unwrap(),
+            large
+        );
+
+        let resp = get(&mut snappy, "small").await;
+        assert!(!resp.data_type.contains(DataType::SNAPPY));
+        assert_eq!(resp.value.unwrap(), small);
+
+        // Values stored compressed are inflated for clients without snappy
+        let compressed = snap::raw::Encoder::new().compress_vec(&large).unwrap();
+        assert_eq!(
+            set(&mut snappy, "compressed", compressed, DataType::SNAPPY).await,
+            Status::Success
+        );
+        let resp = get(&mut plain, "compressed").await;
+        assert!(!resp.data_type.contains(DataType::SNAPPY));
+        assert_eq!(resp.value.unwrap(), large);
+
+        // Only valid snappy, from clients that negotiated it, is stored
+        let compressed = snap::raw::Encoder::new().compress_vec(&small).unwrap();
+        assert_eq!(
+            set(&mut plain, "rejected", compressed, DataType::SNAPPY).await,
+            Status::InvalidArguments
+        );
+        assert_eq!(
+            set(&mut snappy, "rejected", vec![0xff; 8], DataType::SNAPPY).await,
+            Status::InvalidArguments
+        );
+
+        assert!(buckets.stop_flushers());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -17,6 +17,9 @@ impl McbpCodec {
 
 const HEADER_LEN: usize = 24;
 
+/// Largest body accepted, the same as memcached's default `max_packet_size`
+pub const MAX_BODY_LEN: usize = 30 * 1024 * 1024;
+
 impl Decoder for McbpCodec {
     type Item = McbpMessage;
 
@@ -28,8 +31,16 @@ impl Decoder for McbpCodec {
             return Ok(None);
         }
 
+        // Check the header before waiting for the body, so garbage from a
+        // misbehaving client is rejected rather than buffered
+        Magic::try_from(src[0])?;
+
         let total_body_length = (&src[8..12]).get_u32() as usize;
 
+        if total_body_length > MAX_BODY_LEN {
+            return Err(McbpDecodeError::FrameTooLarge(total_body_length));
+        }
+
         if src.len() < HEADER_LEN + total_body_length {
             // The full frame has not yet arrived
             //
@@ -57,13 +68,15 @@ impl Decoder for McbpCodec {
         let data_type = DataType::try_from(src.get_u8())?;
         let vbucket_or_status = src.get_u16();
         let total_body_length = src.get_u32() as usize;
+        let value_length = total_body_length
+            .checked_sub(extras_length + framing_extras_length + key_length)
+            .ok_or(McbpDecodeError::InvalidBodyLength(total_body_length))?;
         let opaque = src.get_u32();
         let cas = Cas(src.get_u64());
-        let extras = src.copy_to_bytes(extras_length);
         let framing_extras = src.copy_to_bytes(framing_extras_length);
+        let extras = src.copy_to_bytes(extras_length);
         let key = src.copy_to_bytes(key_length);
-        let value = src
-            .copy_to_bytes(total_body_length - extras_length - framing_extras_length - key_length);
+        let value = src.copy_to_bytes(value_length);
 
         let specific = if magic.is_request() {
             Specific::Vbucket(vbucket_or_status)
@@ -90,6 +103,30 @@ impl Encoder<McbpMessage> for McbpCodec {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: McbpMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // The lengths would be truncated in the header, corrupting the frame
+        let too_large = |what: &str, len: usize| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} too large ({} bytes)", what, len),
+            )
+        };
+        if item.magic.is_alternative_encoding() {
+            if item.framing_extras.len() > u8::MAX as usize {
+                return Err(too_large("framing extras", item.framing_extras.len()));
+            }
+            if item.key.len() > u8::MAX as usize {
+                return Err(too_large("key", item.key.len()));
+            }
+        } else if !item.framing_extras.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "framing extras need the alternative encoding",
+            ));
+        }
+        if item.extras.len() > u8::MAX as usize {
+            return Err(too_large("extras", item.extras.len()));
+        }
+
         let total_body_length =
             item.framing_extras.len() + item.key.len() + item.value.len() + item.extras.len();
         let len = HEADER_LEN + total_body_length;
@@ -103,7 +140,6 @@ impl Encoder<McbpMessage> for McbpCodec {
             dst.put_u8(item.framing_extras.len() as u8);
             dst.put_u8(item.key.len() as u8);
         } else {
-            assert!(item.framing_extras.is_empty());
             dst.put_u16(item.key.len() as u16);
         }
 
@@ -122,8 +158,8 @@ impl Encoder<McbpMessage> for McbpCodec {
         dst.put_u32(total_body_length as u32);
         dst.put_u32(item.opaque);
         dst.put_u64(item.cas.0);
-        dst.put(item.extras);
         dst.put(item.framing_extras);
+        dst.put(item.extras);
         dst.put(item.key);
         dst.put(item.value);
 
@@ -134,6 +170,7 @@ impl Encoder<McbpMessage> for McbpCodec {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::McbpMessageBuilder;
     use bytes::Bytes;
     use std::iter::FromIterator;
 
@@ -160,6 +197,51 @@ mod test {
         assert_eq!(message, decoded_message);
     }
 
+    #[test]
+    fn test_framing_extras_order() {
+        let mut codec = McbpCodec::new();
+        // Framing extras come before the extras, key and value
+        let frame = vec![
+            0x08, 0x01, 0x04, 0x01, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x00, 0x00,
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Header
+            0x13, 0x01, 0x00, 0x64, // Framing extras
+            0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00, // Extras
+            0x6b, // Key
+            0x76, 0x76, // Value
+        ];
+        let mut buf = BytesMut::from_iter(frame.clone());
+        let message = codec.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(message.magic, Magic::AltClientRequest);
+        assert_eq!(message.opcode, Opcode::Upsert);
+        assert_eq!(
+            message.frame_infos().unwrap(),
+            vec![crate::FrameInfo::DurabilityRequirement {
+                level: crate::DurabilityLevel::Majority,
+                timeout: Some(100),
+            }]
+        );
+        assert_eq!(&message.extras[..], &[0, 0, 0, 6, 0, 0, 0, 0]);
+        assert_eq!(&message.key[..], b"k");
+        assert_eq!(&message.value[..], b"vv");
+
+        codec.encode(message, &mut buf).unwrap();
+        assert_eq!(&buf[..], &frame[..]);
+    }
+
+    #[test]
+    fn test_encode_too_large() {
+        let mut codec = McbpCodec::new();
+        let mut buf = BytesMut::new();
+        let message = McbpMessageBuilder::new(Opcode::Upsert)
+            .framing_extras(vec![0x00; 256])
+            .build();
+        assert_eq!(message.magic, Magic::AltClientRequest);
+        let error = codec.encode(message, &mut buf).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+    }
+
     #[test]
     fn test_unknown_status() {
         let mut codec = McbpCodec::new();
@@ -206,4 +288,28 @@ mod test {
         let error = codec.decode(&mut buf).unwrap_err();
         assert!(matches!(error, McbpDecodeError::InvalidDataType(0xff)));
     }
+
+    #[test]
+    fn test_frame_too_large() {
+        let mut codec = McbpCodec::new();
+        // A huge body length shouldn't be buffered
+        let mut buf = BytesMut::from_iter(vec![
+            0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x42, 0x42, 0x42, 0x42, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        let error = codec.decode(&mut buf).unwrap_err();
+        assert!(matches!(error, McbpDecodeError::FrameTooLarge(0x42424242)));
+    }
+
+    #[test]
+    fn test_invalid_body_length() {
+        let mut codec = McbpCodec::new();
+        // A key longer than the whole body
+        let mut buf = BytesMut::from_iter(vec![
+            0x80, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x61, 0x62,
+        ]);
+        let error = codec.decode(&mut buf).unwrap_err();
+        assert!(matches!(error, McbpDecodeError::InvalidBodyLength(2)));
+    }
 }
@@ -2,6 +2,8 @@ use std::io;
 
 use thiserror::Error;
 
+use crate::Status;
+
 /// Errors that can be produced by the encoder or decoder
 #[derive(Error, Debug)]
 pub enum McbpDecodeError {
@@ -13,10 +15,33 @@ pub enum McbpDecodeError {
     InvalidDataType(u8),
     #[error("invalid feature ({0})")]
     InvalidFeature(u16),
+    #[error("invalid frame info ({0})")]
+    InvalidFrameInfo(u16),
+    #[error("frame info too large ({0} byte payload)")]
+    FrameInfoTooLarge(usize),
+    #[error("framing extras too large ({0} bytes)")]
+    FramingExtrasTooLarge(usize),
+    #[error("truncated frame info ({0})")]
+    TruncatedFrameInfo(u16),
+    #[error("invalid durability level ({0})")]
+    InvalidDurabilityLevel(u8),
+    #[error("unexpected status ({0:?})")]
+    UnexpectedStatus(Status),
+    #[error("invalid value ({0})")]
+    InvalidValue(String),
     #[error("missing status")]
     MissingStatus,
     #[error("missing vbucket")]
     MissingVbucket,
+    #[error("frame too large ({0} byte body)")]
+    FrameTooLarge(usize),
+    #[error("invalid body length ({0})")]
+    InvalidBodyLength(usize),
+    #[error(transparent)]
+    Snappy {
+        #[from]
+        source: snap::Error,
+    },
     #[error(transparent)]
     Io {
         #[from]
@@ -0,0 +1,334 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{convert::TryFrom, time::Duration};
+
+use crate::{error::McbpDecodeError, magic::Magic};
+
+/// A nibble of 15 means the id or length continues in the following byte
+const ESCAPE: u8 = 0x0f;
+
+/// The largest id or length a frame info can have, an escaped nibble plus a
+/// byte
+pub const MAX_FRAME_INFO_LEN: usize = ESCAPE as usize + u8::MAX as usize;
+
+/// The framing extras length is a single byte in the header
+pub const MAX_FRAMING_EXTRAS_LEN: usize = u8::MAX as usize;
+
+/// The durability a mutation must reach before it is reported as successful
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurabilityLevel {
+    /// Held in memory on a majority of the nodes
+    Majority,
+    /// Held in memory on a majority of the nodes, and persisted on the active
+    MajorityAndPersistOnMaster,
+    /// Persisted on a majority of the nodes
+    PersistToMajority,
+}
+
+impl From<DurabilityLevel> for u8 {
+    fn from(level: DurabilityLevel) -> Self {
+        match level {
+            DurabilityLevel::Majority => 0x01,
+            DurabilityLevel::MajorityAndPersistOnMaster => 0x02,
+            DurabilityLevel::PersistToMajority => 0x03,
+        }
+    }
+}
+
+impl TryFrom<u8> for DurabilityLevel {
+    type Error = McbpDecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0x01 => DurabilityLevel::Majority,
+            0x02 => DurabilityLevel::MajorityAndPersistOnMaster,
+            0x03 => DurabilityLevel::PersistToMajority,
+            _ => return Err(McbpDecodeError::InvalidDurabilityLevel(value)),
+        })
+    }
+}
+
+/// FrameInfo is a single entry of the flexible framing extras, which are only
+/// present in messages using the alternative encoding. Each entry starts with
+/// a byte holding the id in the high nibble and the length in the low, either
+/// of which is followed by an extra byte holding the remainder if it is 15 or
+/// more.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameInfo {
+    /// Don't execute the request until all those before it have completed,
+    /// nor any after it until it has
+    Barrier,
+    /// Make the mutation a synchronous write. The timeout is in milliseconds,
+    /// None uses the server's default.
+    DurabilityRequirement {
+        level: DurabilityLevel,
+        timeout: Option<u16>,
+    },
+    /// The DCP stream the request is for
+    DcpStreamId(u16),
+    /// The tracing context to attach the request's spans to
+    OpenTracingContext(Bytes),
+    /// Run the request as the given user, prefixed with `^` for an external
+    /// user
+    Impersonate(String),
+    /// Keep the document's existing expiry when mutating it
+    PreserveTtl,
+    /// How long the server took to handle the request, only sent in responses
+    ServerRecvSendDuration(Duration),
+}
+
+impl FrameInfo {
+    /// The frame info's id, responses have ids of their own
+    pub fn id(&self) -> u16 {
+        match self {
+            FrameInfo::Barrier => 0x00,
+            FrameInfo::DurabilityRequirement { .. } => 0x01,
+            FrameInfo::DcpStreamId(_) => 0x02,
+            FrameInfo::OpenTracingContext(_) => 0x03,
+            FrameInfo::Impersonate(_) => 0x04,
+            FrameInfo::PreserveTtl => 0x05,
+            FrameInfo::ServerRecvSendDuration(_) => 0x00,
+        }
+    }
+
+    /// Is this frame info sent in responses rather than requests
+    pub fn is_response(&self) -> bool {
+        matches!(self, FrameInfo::ServerRecvSendDuration(_))
+    }
+
+    /// Append the frame info to the framing extras in `dst`. Fails, leaving
+    /// `dst` unchanged, if the payload is longer than [MAX_FRAME_INFO_LEN].
+    pub fn encode(&self, dst: &mut BytesMut) -> Result<(), McbpDecodeError> {
+        let mut payload = BytesMut::new();
+        match self {
+            FrameInfo::Barrier | FrameInfo::PreserveTtl => {}
+            FrameInfo::DurabilityRequirement { level, timeout } => {
+                payload.put_u8((*level).into());
+                if let Some(timeout) = timeout {
+                    payload.put_u16(*timeout);
+                }
+            }
+            FrameInfo::DcpStreamId(id) => payload.put_u16(*id),
+            FrameInfo::OpenTracingContext(context) => payload.put_slice(context),
+            FrameInfo::Impersonate(user) => payload.put_slice(user.as_bytes()),
+            FrameInfo::ServerRecvSendDuration(duration) => {
+                payload.put_u16(encode_server_duration(*duration))
+            }
+        }
+
+        if payload.len() > MAX_FRAME_INFO_LEN {
+            return Err(McbpDecodeError::FrameInfoTooLarge(payload.len()));
+        }
+        let (id, escaped_id) = split_nibble(self.id() as usize);
+        let (len, escaped_len) = split_nibble(payload.len());
+        dst.reserve(3 + payload.len());
+        dst.put_u8(id << 4 | len);
+        if let Some(escaped_id) = escaped_id {
+            dst.put_u8(escaped_id);
+        }
+        if let Some(escaped_len) = escaped_len {
+            dst.put_u8(escaped_len);
+        }
+        dst.put(payload);
+        Ok(())
+    }
+
+    /// Encode the frame infos as the framing extras of a message, which must
+    /// fit in [MAX_FRAMING_EXTRAS_LEN]
+    pub fn encode_all<'a>(
+        frame_infos: impl IntoIterator<Item = &'a FrameInfo>,
+    ) -> Result<Bytes, McbpDecodeError> {
+        let mut dst = BytesMut::new();
+        for frame_info in frame_infos {
+            frame_info.encode(&mut dst)?;
+        }
+        check_framing_extras_len(dst.len())?;
+        Ok(dst.freeze())
+    }
+
+    /// Decode the first frame info in `src`, advancing past it. The magic of
+    /// the message tells request and response ids apart.
+    pub fn decode(src: &mut Bytes, magic: Magic) -> Result<FrameInfo, McbpDecodeError> {
+        let header = get_u8(src)?;
+        let mut id = (header >> 4) as u16;
+        let mut len = (header & 0x0f) as usize;
+        if id == ESCAPE as u16 {
+            id += get_u8(src)? as u16;
+        }
+        if len == ESCAPE as usize {
+            len += get_u8(src)? as usize;
+        }
+        if src.len() < len {
+            return Err(McbpDecodeError::TruncatedFrameInfo(id));
+        }
+        let mut payload = src.split_to(len);
+
+        let invalid = || McbpDecodeError::InvalidFrameInfo(id);
+        let frame_info = match (id, magic.is_request()) {
+            (0x00, true) if len == 0 => FrameInfo::Barrier,
+            (0x01, true) if len == 1 || len == 3 => FrameInfo::DurabilityRequirement {
+                level: DurabilityLevel::try_from(payload.get_u8())?,
+                timeout: (len == 3).then(|| payload.get_u16()),
+            },
+            (0x02, true) if len == 2 => FrameInfo::DcpStreamId(payload.get_u16()),
+            (0x03, true) if len > 0 => FrameInfo::OpenTracingContext(payload),
+            (0x04, true) if len > 0 => {
+                let user = String::from_utf8(payload.to_vec()).map_err(|_| invalid())?;
+                FrameInfo::Impersonate(user)
+            }
+            (0x05, true) if len == 0 => FrameInfo::PreserveTtl,
+            (0x00, false) if len == 2 => {
+                FrameInfo::ServerRecvSendDuration(decode_server_duration(payload.get_u16()))
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(frame_info)
+    }
+
+    /// Decode all of the framing extras of a message
+    pub fn decode_all(
+        framing_extras: &Bytes,
+        magic: Magic,
+    ) -> Result<Vec<FrameInfo>, McbpDecodeError> {
+        let mut src = framing_extras.clone();
+        let mut frame_infos = vec![];
+        while src.has_remaining() {
+            frame_infos.push(FrameInfo::decode(&mut src, magic)?);
+        }
+        Ok(frame_infos)
+    }
+}
+
+pub(crate) fn check_framing_extras_len(len: usize) -> Result<(), McbpDecodeError> {
+    if len > MAX_FRAMING_EXTRAS_LEN {
+        return Err(McbpDecodeError::FramingExtrasTooLarge(len));
+    }
+    Ok(())
+}
+
+/// Split an id or length of at most [MAX_FRAME_INFO_LEN] into its nibble and,
+/// if escaped, the extra byte
+fn split_nibble(value: usize) -> (u8, Option<u8>) {
+    if value < ESCAPE as usize {
+        (value as u8, None)
+    } else {
+        (ESCAPE, Some((value - ESCAPE as usize) as u8))
+    }
+}
+
+fn get_u8(src: &mut Bytes) -> Result<u8, McbpDecodeError> {
+    if !src.has_remaining() {
+        return Err(McbpDecodeError::TruncatedFrameInfo(0));
+    }
+    Ok(src.get_u8())
+}
+
+/// The server duration is sent as `(micros * 2) ^ (1 / 1.74)`, which covers
+/// up to about two minutes in 16 bits
+fn encode_server_duration(duration: Duration) -> u16 {
+    let micros = duration.as_micros() as f64;
+    (micros * 2.0).powf(1.0 / 1.74).round().min(u16::MAX as f64) as u16
+}
+
+fn decode_server_duration(encoded: u16) -> Duration {
+    let micros = (encoded as f64).powf(1.74) / 2.0;
+    Duration::from_micros(micros.round() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let frame_infos = vec![
+            FrameInfo::Barrier,
+            FrameInfo::DurabilityRequirement {
+                level: DurabilityLevel::Majority,
+                timeout: None,
+            },
+            FrameInfo::DurabilityRequirement {
+                level: DurabilityLevel::PersistToMajority,
+                timeout: Some(2500),
+            },
+            FrameInfo::DcpStreamId(7),
+            FrameInfo::OpenTracingContext(Bytes::from_static(b"trace")),
+            // Long enough for the length to be escaped
+            FrameInfo::Impersonate("^someone@example.com".to_string()),
+            FrameInfo::PreserveTtl,
+        ];
+
+        let encoded = FrameInfo::encode_all(&frame_infos).unwrap();
+        assert_eq!(&encoded[..4], &[0x00, 0x11, 0x01, 0x13]);
+        let decoded = FrameInfo::decode_all(&encoded, Magic::AltClientRequest).unwrap();
+        assert_eq!(decoded, frame_infos);
+    }
+
+    #[test]
+    fn test_escaped() {
+        let user = "a".repeat(20);
+        let mut encoded = BytesMut::new();
+        FrameInfo::Impersonate(user.clone())
+            .encode(&mut encoded)
+            .unwrap();
+        assert_eq!(&encoded[..2], &[0x4f, 20 - 15]);
+
+        // An id of 15 or more is escaped before the length
+        let mut src = Bytes::from_static(&[0xf1, 0x02, 0x00]);
+        assert!(matches!(
+            FrameInfo::decode(&mut src, Magic::AltClientRequest),
+            Err(McbpDecodeError::InvalidFrameInfo(17))
+        ));
+        assert!(src.is_empty());
+
+        let mut src = Bytes::from_static(&[0x12, 0x00]);
+        assert!(matches!(
+            FrameInfo::decode(&mut src, Magic::AltClientRequest),
+            Err(McbpDecodeError::TruncatedFrameInfo(1))
+        ));
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut encoded = BytesMut::new();
+        let largest = FrameInfo::Impersonate("a".repeat(MAX_FRAME_INFO_LEN));
+        largest.encode(&mut encoded).unwrap();
+        assert_eq!(&encoded[..2], &[0x4f, 0xff]);
+
+        let mut encoded = BytesMut::new();
+        let context = Bytes::from(vec![0; MAX_FRAME_INFO_LEN + 1]);
+        assert!(matches!(
+            FrameInfo::OpenTracingContext(context).encode(&mut encoded),
+            Err(McbpDecodeError::FrameInfoTooLarge(271))
+        ));
+        assert!(encoded.is_empty());
+
+        // Each fits, but not both
+        let user = FrameInfo::Impersonate("a".repeat(200));
+        assert_eq!(FrameInfo::encode_all([&user]).unwrap().len(), 202);
+        assert!(matches!(
+            FrameInfo::encode_all([&user, &user]),
+            Err(McbpDecodeError::FramingExtrasTooLarge(404))
+        ));
+    }
+
+    #[test]
+    fn test_server_duration() {
+        let duration = Duration::from_micros(1500);
+        let mut encoded = BytesMut::new();
+        FrameInfo::ServerRecvSendDuration(duration)
+            .encode(&mut encoded)
+            .unwrap();
+        assert_eq!(encoded[0], 0x02);
+
+        let decoded = FrameInfo::decode_all(&encoded.freeze(), Magic::AltClientResponse).unwrap();
+        let [FrameInfo::ServerRecvSendDuration(decoded)] = decoded[..] else {
+            panic!("unexpected frame infos {:?}", decoded);
+        };
+        // The encoding loses precision as durations grow
+        assert!(decoded.abs_diff(duration) < duration / 50);
+
+        // Response ids mean something else in a request
+        let mut src = Bytes::from_static(&[0x02, 0x00, 0x01]);
+        assert!(FrameInfo::decode(&mut src, Magic::AltClientRequest).is_err());
+    }
+}
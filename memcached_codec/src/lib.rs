@@ -3,6 +3,7 @@ pub mod codec;
 pub mod data_type;
 pub mod error;
 pub mod feature;
+pub mod frame_info;
 pub mod magic;
 pub mod message;
 pub mod opcode;
@@ -12,6 +13,7 @@ pub use cas::Cas;
 pub use codec::McbpCodec;
 pub use data_type::DataType;
 pub use error::McbpDecodeError;
+pub use frame_info::{DurabilityLevel, FrameInfo};
 pub use magic::Magic;
 pub use message::{McbpMessage, McbpMessageBuilder};
 pub use opcode::Opcode;
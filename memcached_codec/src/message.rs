@@ -1,5 +1,8 @@
-use crate::{Cas, DataType, Magic, McbpDecodeError, Opcode, Status};
-use bytes::Bytes;
+use crate::{
+    frame_info::check_framing_extras_len, Cas, DataType, FrameInfo, Magic, McbpDecodeError, Opcode,
+    Status,
+};
+use bytes::{Bytes, BytesMut};
 
 /// McbpMessage defines the fields contained in a full message sent between client and server
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -39,6 +42,45 @@ impl McbpMessage {
             _ => Err(McbpDecodeError::MissingVbucket),
         }
     }
+
+    /// Snappy compress the value, unless it is already compressed or
+    /// compressing it doesn't make it any smaller. Returns whether the value
+    /// is compressed.
+    pub fn compress(&mut self) -> bool {
+        if self.data_type.contains(DataType::SNAPPY) {
+            return true;
+        }
+        if self.value.is_empty() {
+            return false;
+        }
+
+        // Compression only fails for values larger than snappy supports
+        let Ok(compressed) = snap::raw::Encoder::new().compress_vec(&self.value) else {
+            return false;
+        };
+        if compressed.len() >= self.value.len() {
+            return false;
+        }
+        self.value = Bytes::from(compressed);
+        self.data_type.insert(DataType::SNAPPY);
+        true
+    }
+
+    /// Inflate the value if it is snappy compressed
+    pub fn decompress(&mut self) -> Result<(), McbpDecodeError> {
+        if !self.data_type.contains(DataType::SNAPPY) {
+            return Ok(());
+        }
+        let value = snap::raw::Decoder::new().decompress_vec(&self.value)?;
+        self.value = Bytes::from(value);
+        self.data_type.remove(DataType::SNAPPY);
+        Ok(())
+    }
+
+    /// Decode the frame infos in the framing extras
+    pub fn frame_infos(&self) -> Result<Vec<FrameInfo>, McbpDecodeError> {
+        FrameInfo::decode_all(&self.framing_extras, self.magic)
+    }
 }
 
 /// McbpMessageBuilder can be used to build an [McbpMessage]
@@ -111,6 +153,25 @@ impl McbpMessageBuilder {
         self
     }
 
+    /// Append a frame info to the framing extras
+    pub fn frame_info(self, frame_info: FrameInfo) -> Result<Self, McbpDecodeError> {
+        self.frame_infos([frame_info])
+    }
+
+    /// Append the frame infos to the framing extras. Fails if they don't fit.
+    pub fn frame_infos(
+        mut self,
+        frame_infos: impl IntoIterator<Item = FrameInfo>,
+    ) -> Result<Self, McbpDecodeError> {
+        let mut framing_extras = BytesMut::from(&self.framing_extras[..]);
+        for frame_info in frame_infos {
+            frame_info.encode(&mut framing_extras)?;
+        }
+        check_framing_extras_len(framing_extras.len())?;
+        self.framing_extras = framing_extras.freeze();
+        Ok(self)
+    }
+
     pub fn key(mut self, key: impl Into<Bytes>) -> Self {
         self.key = key.into();
         self
@@ -121,9 +182,16 @@ impl McbpMessageBuilder {
         self
     }
 
+    /// Build the message, switching to the alternative encoding if it has
+    /// framing extras
     pub fn build(self) -> McbpMessage {
+        let magic = match self.magic {
+            Magic::ClientRequest if !self.framing_extras.is_empty() => Magic::AltClientRequest,
+            Magic::ClientResponse if !self.framing_extras.is_empty() => Magic::AltClientResponse,
+            magic => magic,
+        };
         McbpMessage {
-            magic: self.magic,
+            magic,
             opcode: self.opcode,
             data_type: self.data_type,
             specific: self.specific,
@@ -136,3 +204,79 @@ impl McbpMessageBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DurabilityLevel;
+
+    #[test]
+    fn test_compression() {
+        let value = "{\"k\":\"".to_string() + &"v".repeat(100) + "\"}";
+        let mut message = McbpMessageBuilder::new(Opcode::Get)
+            .data_type(DataType::JSON)
+            .value(value.clone())
+            .build();
+
+        assert!(message.compress());
+        assert_eq!(message.data_type, DataType::JSON | DataType::SNAPPY);
+        assert!(message.value.len() < value.len());
+        // Already compressed
+        let compressed = message.value.clone();
+        assert!(message.compress());
+        assert_eq!(message.value, compressed);
+
+        message.decompress().unwrap();
+        assert_eq!(message.data_type, DataType::JSON);
+        assert_eq!(message.value, value);
+        message.decompress().unwrap();
+        assert_eq!(message.value, value);
+
+        // Too small to be worth it
+        let mut message = McbpMessageBuilder::new(Opcode::Get).value("v").build();
+        assert!(!message.compress());
+        assert_eq!(message.data_type, DataType::RAW);
+
+        let mut message = McbpMessageBuilder::new(Opcode::Get)
+            .data_type(DataType::SNAPPY)
+            .value("not snappy")
+            .build();
+        assert!(matches!(
+            message.decompress(),
+            Err(McbpDecodeError::Snappy { .. })
+        ));
+    }
+
+    #[test]
+    fn test_frame_infos() {
+        let message = McbpMessageBuilder::new(Opcode::Upsert).key("key").build();
+        assert_eq!(message.magic, Magic::ClientRequest);
+        assert!(message.frame_infos().unwrap().is_empty());
+
+        let durability = FrameInfo::DurabilityRequirement {
+            level: DurabilityLevel::Majority,
+            timeout: Some(100),
+        };
+        let message = McbpMessageBuilder::new(Opcode::Upsert)
+            .key("key")
+            .frame_info(FrameInfo::PreserveTtl)
+            .unwrap()
+            .frame_info(durability.clone())
+            .unwrap()
+            .build();
+        assert_eq!(message.magic, Magic::AltClientRequest);
+        assert_eq!(
+            message.frame_infos().unwrap(),
+            vec![FrameInfo::PreserveTtl, durability]
+        );
+
+        let user = FrameInfo::Impersonate("a".repeat(200));
+        let builder = McbpMessageBuilder::new(Opcode::Upsert)
+            .frame_info(user.clone())
+            .unwrap();
+        assert!(matches!(
+            builder.frame_info(user),
+            Err(McbpDecodeError::FramingExtrasTooLarge(404))
+        ));
+    }
+}
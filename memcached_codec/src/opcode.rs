@@ -9,17 +9,86 @@ pub enum Opcode {
     Insert,
     Replace,
     Remove,
+    Increment,
+    Decrement,
+    Quit,
+    Flush,
+    GetQuiet,
+    Noop,
+    Version,
+    GetKey,
+    GetKeyQuiet,
+    Append,
+    Prepend,
+    Stat,
+    UpsertQuiet,
+    InsertQuiet,
+    ReplaceQuiet,
+    RemoveQuiet,
+    IncrementQuiet,
+    DecrementQuiet,
+    QuitQuiet,
+    FlushQuiet,
+    AppendQuiet,
+    PrependQuiet,
+    Verbosity,
+    Touch,
+    GetAndTouch,
+    GetAndTouchQuiet,
     Hello,
     SaslListMechs,
     SaslAuth,
     SaslStep,
+    SetVbucket,
+    GetVbucket,
+    DelVbucket,
+    GetAllVbSeqnos,
+    GetReplica,
     SelectBucket,
+    ObserveSeqno,
+    Observe,
+    GetLocked,
+    Unlock,
+    GetMeta,
+    GetMetaQuiet,
+    SetWithMeta,
+    SetWithMetaQuiet,
+    AddWithMeta,
+    AddWithMetaQuiet,
+    DelWithMeta,
+    DelWithMetaQuiet,
+    GetClusterConfig,
+    GetRandomKey,
+    SeqnoPersistence,
+    GetKeys,
+    CollectionsSetManifest,
     GetCollectionsManifest,
     GetCollectionId,
     GetScopeId,
-    GetClusterConfig,
     GetErrorMap,
 
+    // Subdoc
+    SubdocGet,
+    SubdocExists,
+    SubdocDictAdd,
+    SubdocDictUpsert,
+    SubdocDelete,
+    SubdocReplace,
+    SubdocArrayPushLast,
+    SubdocArrayPushFirst,
+    SubdocArrayInsert,
+    SubdocArrayAddUnique,
+    SubdocCounter,
+    SubdocMultiLookup,
+    SubdocMultiMutation,
+    SubdocGetCount,
+    SubdocReplaceBodyWithXattr,
+
+    // Range scan
+    RangeScanCreate,
+    RangeScanContinue,
+    RangeScanCancel,
+
     // DCP
     DcpOpenConnection,
     DcpAddStream,
@@ -45,6 +114,8 @@ pub enum Opcode {
 
     // Server
     ClusterMapChangeNotification,
+    Authenticate,
+    ActiveExternalUsers,
 }
 
 impl From<Opcode> for u8 {
@@ -56,16 +127,85 @@ impl From<Opcode> for u8 {
             Opcode::Insert => 0x02,
             Opcode::Replace => 0x03,
             Opcode::Remove => 0x04,
+            Opcode::Increment => 0x05,
+            Opcode::Decrement => 0x06,
+            Opcode::Quit => 0x07,
+            Opcode::Flush => 0x08,
+            Opcode::GetQuiet => 0x09,
+            Opcode::Noop => 0x0a,
+            Opcode::Version => 0x0b,
+            Opcode::GetKey => 0x0c,
+            Opcode::GetKeyQuiet => 0x0d,
+            Opcode::Append => 0x0e,
+            Opcode::Prepend => 0x0f,
+            Opcode::Stat => 0x10,
+            Opcode::UpsertQuiet => 0x11,
+            Opcode::InsertQuiet => 0x12,
+            Opcode::ReplaceQuiet => 0x13,
+            Opcode::RemoveQuiet => 0x14,
+            Opcode::IncrementQuiet => 0x15,
+            Opcode::DecrementQuiet => 0x16,
+            Opcode::QuitQuiet => 0x17,
+            Opcode::FlushQuiet => 0x18,
+            Opcode::AppendQuiet => 0x19,
+            Opcode::PrependQuiet => 0x1a,
+            Opcode::Verbosity => 0x1b,
+            Opcode::Touch => 0x1c,
+            Opcode::GetAndTouch => 0x1d,
+            Opcode::GetAndTouchQuiet => 0x1e,
             Opcode::Hello => 0x1f,
             Opcode::SaslListMechs => 0x20,
             Opcode::SaslAuth => 0x21,
             Opcode::SaslStep => 0x22,
+            Opcode::SetVbucket => 0x3d,
+            Opcode::GetVbucket => 0x3e,
+            Opcode::DelVbucket => 0x3f,
+            Opcode::GetAllVbSeqnos => 0x48,
+            Opcode::GetReplica => 0x83,
+            Opcode::SelectBucket => 0x89,
+            Opcode::ObserveSeqno => 0x91,
+            Opcode::Observe => 0x92,
+            Opcode::GetLocked => 0x94,
+            Opcode::Unlock => 0x95,
+            Opcode::GetMeta => 0xa0,
+            Opcode::GetMetaQuiet => 0xa1,
+            Opcode::SetWithMeta => 0xa2,
+            Opcode::SetWithMetaQuiet => 0xa3,
+            Opcode::AddWithMeta => 0xa4,
+            Opcode::AddWithMetaQuiet => 0xa5,
+            Opcode::DelWithMeta => 0xa8,
+            Opcode::DelWithMetaQuiet => 0xa9,
+            Opcode::GetClusterConfig => 0xb5,
+            Opcode::GetRandomKey => 0xb6,
+            Opcode::SeqnoPersistence => 0xb7,
+            Opcode::GetKeys => 0xb8,
+            Opcode::CollectionsSetManifest => 0xb9,
             Opcode::GetCollectionsManifest => 0xba,
             Opcode::GetCollectionId => 0xbb,
             Opcode::GetScopeId => 0xbc,
             Opcode::GetErrorMap => 0xfe,
-            Opcode::SelectBucket => 0x89,
-            Opcode::GetClusterConfig => 0xb5,
+
+            // Subdoc
+            Opcode::SubdocGet => 0xc5,
+            Opcode::SubdocExists => 0xc6,
+            Opcode::SubdocDictAdd => 0xc7,
+            Opcode::SubdocDictUpsert => 0xc8,
+            Opcode::SubdocDelete => 0xc9,
+            Opcode::SubdocReplace => 0xca,
+            Opcode::SubdocArrayPushLast => 0xcb,
+            Opcode::SubdocArrayPushFirst => 0xcc,
+            Opcode::SubdocArrayInsert => 0xcd,
+            Opcode::SubdocArrayAddUnique => 0xce,
+            Opcode::SubdocCounter => 0xcf,
+            Opcode::SubdocMultiLookup => 0xd0,
+            Opcode::SubdocMultiMutation => 0xd1,
+            Opcode::SubdocGetCount => 0xd2,
+            Opcode::SubdocReplaceBodyWithXattr => 0xd3,
+
+            // Range scan
+            Opcode::RangeScanCreate => 0xda,
+            Opcode::RangeScanContinue => 0xdb,
+            Opcode::RangeScanCancel => 0xdc,
 
             // DCP
             Opcode::DcpOpenConnection => 0x50,
@@ -92,6 +232,8 @@ impl From<Opcode> for u8 {
 
             // Server
             Opcode::ClusterMapChangeNotification => 0x01,
+            Opcode::Authenticate => 0x02,
+            Opcode::ActiveExternalUsers => 0x03,
         }
     }
 }
@@ -100,29 +242,101 @@ impl Opcode {
     /// Decode the opcode from the raw u8. The magic is requried to differentiate between
     /// client and server opcodes that have the same raw value
     pub fn from_u8(value: u8, magic: Magic) -> Result<Opcode, McbpDecodeError> {
+        if magic.is_server_magic() {
+            return Ok(match value {
+                0x01 => Opcode::ClusterMapChangeNotification,
+                0x02 => Opcode::Authenticate,
+                0x03 => Opcode::ActiveExternalUsers,
+                _ => return Err(McbpDecodeError::InvalidOpcode(value)),
+            });
+        }
+
         Ok(match value {
             0x00 => Opcode::Get,
-            0x01 => {
-                if magic.is_client_magic() {
-                    Opcode::Upsert
-                } else {
-                    Opcode::ClusterMapChangeNotification
-                }
-            }
+            0x01 => Opcode::Upsert,
             0x02 => Opcode::Insert,
             0x03 => Opcode::Replace,
             0x04 => Opcode::Remove,
+            0x05 => Opcode::Increment,
+            0x06 => Opcode::Decrement,
+            0x07 => Opcode::Quit,
+            0x08 => Opcode::Flush,
+            0x09 => Opcode::GetQuiet,
+            0x0a => Opcode::Noop,
+            0x0b => Opcode::Version,
+            0x0c => Opcode::GetKey,
+            0x0d => Opcode::GetKeyQuiet,
+            0x0e => Opcode::Append,
+            0x0f => Opcode::Prepend,
+            0x10 => Opcode::Stat,
+            0x11 => Opcode::UpsertQuiet,
+            0x12 => Opcode::InsertQuiet,
+            0x13 => Opcode::ReplaceQuiet,
+            0x14 => Opcode::RemoveQuiet,
+            0x15 => Opcode::IncrementQuiet,
+            0x16 => Opcode::DecrementQuiet,
+            0x17 => Opcode::QuitQuiet,
+            0x18 => Opcode::FlushQuiet,
+            0x19 => Opcode::AppendQuiet,
+            0x1a => Opcode::PrependQuiet,
+            0x1b => Opcode::Verbosity,
+            0x1c => Opcode::Touch,
+            0x1d => Opcode::GetAndTouch,
+            0x1e => Opcode::GetAndTouchQuiet,
             0x1f => Opcode::Hello,
             0x20 => Opcode::SaslListMechs,
             0x21 => Opcode::SaslAuth,
             0x22 => Opcode::SaslStep,
+            0x3d => Opcode::SetVbucket,
+            0x3e => Opcode::GetVbucket,
+            0x3f => Opcode::DelVbucket,
+            0x48 => Opcode::GetAllVbSeqnos,
+            0x83 => Opcode::GetReplica,
             0x89 => Opcode::SelectBucket,
+            0x91 => Opcode::ObserveSeqno,
+            0x92 => Opcode::Observe,
+            0x94 => Opcode::GetLocked,
+            0x95 => Opcode::Unlock,
+            0xa0 => Opcode::GetMeta,
+            0xa1 => Opcode::GetMetaQuiet,
+            0xa2 => Opcode::SetWithMeta,
+            0xa3 => Opcode::SetWithMetaQuiet,
+            0xa4 => Opcode::AddWithMeta,
+            0xa5 => Opcode::AddWithMetaQuiet,
+            0xa8 => Opcode::DelWithMeta,
+            0xa9 => Opcode::DelWithMetaQuiet,
+            0xb5 => Opcode::GetClusterConfig,
+            0xb6 => Opcode::GetRandomKey,
+            0xb7 => Opcode::SeqnoPersistence,
+            0xb8 => Opcode::GetKeys,
+            0xb9 => Opcode::CollectionsSetManifest,
             0xba => Opcode::GetCollectionsManifest,
             0xbb => Opcode::GetCollectionId,
             0xbc => Opcode::GetScopeId,
-            0xb5 => Opcode::GetClusterConfig,
             0xfe => Opcode::GetErrorMap,
 
+            // Subdoc
+            0xc5 => Opcode::SubdocGet,
+            0xc6 => Opcode::SubdocExists,
+            0xc7 => Opcode::SubdocDictAdd,
+            0xc8 => Opcode::SubdocDictUpsert,
+            0xc9 => Opcode::SubdocDelete,
+            0xca => Opcode::SubdocReplace,
+            0xcb => Opcode::SubdocArrayPushLast,
+            0xcc => Opcode::SubdocArrayPushFirst,
+            0xcd => Opcode::SubdocArrayInsert,
+            0xce => Opcode::SubdocArrayAddUnique,
+            0xcf => Opcode::SubdocCounter,
+            0xd0 => Opcode::SubdocMultiLookup,
+            0xd1 => Opcode::SubdocMultiMutation,
+            0xd2 => Opcode::SubdocGetCount,
+            0xd3 => Opcode::SubdocReplaceBodyWithXattr,
+
+            // Range scan
+            0xda => Opcode::RangeScanCreate,
+            0xdb => Opcode::RangeScanContinue,
+            0xdc => Opcode::RangeScanCancel,
+
             // DCP
             0x50 => Opcode::DcpOpenConnection,
             0x51 => Opcode::DcpAddStream,
@@ -149,43 +363,272 @@ impl Opcode {
             _ => return Err(McbpDecodeError::InvalidOpcode(value)),
         })
     }
+    /// Is the opcode a quiet variant, which only gets a response if it fails
+    pub fn is_quiet(&self) -> bool {
+        matches!(
+            self,
+            Opcode::GetQuiet
+                | Opcode::GetKeyQuiet
+                | Opcode::UpsertQuiet
+                | Opcode::InsertQuiet
+                | Opcode::ReplaceQuiet
+                | Opcode::RemoveQuiet
+                | Opcode::IncrementQuiet
+                | Opcode::DecrementQuiet
+                | Opcode::QuitQuiet
+                | Opcode::FlushQuiet
+                | Opcode::AppendQuiet
+                | Opcode::PrependQuiet
+                | Opcode::GetAndTouchQuiet
+                | Opcode::GetMetaQuiet
+                | Opcode::SetWithMetaQuiet
+                | Opcode::AddWithMetaQuiet
+                | Opcode::DelWithMetaQuiet
+        )
+    }
 
-    /// Does the opcode support snappy compression
-    pub fn is_compressible(&self) -> bool {
-        matches!(self, Opcode::Get | Opcode::Upsert | Opcode::Insert)
+    /// Is the opcode one of the subdoc range
+    pub fn is_subdoc(&self) -> bool {
+        (0xc5..=0xd3).contains(&u8::from(*self))
     }
 
-    /// Does the provided opcode support durability or not
-    pub fn is_durability_supported(&self) -> bool {
+    /// Does the subdoc opcode modify the document
+    pub fn is_subdoc_mutation(&self) -> bool {
         matches!(
             self,
-            Opcode::Upsert | Opcode::Insert | Opcode::Replace | Opcode::Remove
+            Opcode::SubdocDictAdd
+                | Opcode::SubdocDictUpsert
+                | Opcode::SubdocDelete
+                | Opcode::SubdocReplace
+                | Opcode::SubdocArrayPushLast
+                | Opcode::SubdocArrayPushFirst
+                | Opcode::SubdocArrayInsert
+                | Opcode::SubdocArrayAddUnique
+                | Opcode::SubdocCounter
+                | Opcode::SubdocMultiMutation
+                | Opcode::SubdocReplaceBodyWithXattr
         )
     }
 
-    /// Does the provided opcode support reordering
-    pub fn is_reorder_supported(&self) -> bool {
+    /// Is the opcode part of a range scan
+    pub fn is_range_scan(&self) -> bool {
         matches!(
             self,
-            Opcode::Get | Opcode::Upsert | Opcode::Insert | Opcode::Replace | Opcode::Remove
+            Opcode::RangeScanCreate | Opcode::RangeScanContinue | Opcode::RangeScanCancel
         )
     }
 
-    /// Does the command carry a key which contains a collection identifier
-    pub fn is_collection_command(&self) -> bool {
+    /// Is the opcode used on DCP connections
+    pub fn is_dcp(&self) -> bool {
+        (0x50..=0x65).contains(&u8::from(*self)) && !self.is_server()
+    }
+
+    /// Is the opcode sent by the server to the client
+    pub fn is_server(&self) -> bool {
         matches!(
             self,
-            Opcode::Get | Opcode::Upsert | Opcode::Insert | Opcode::Replace | Opcode::Remove
+            Opcode::ClusterMapChangeNotification
+                | Opcode::Authenticate
+                | Opcode::ActiveExternalUsers
         )
     }
 
+    /// Does the opcode support snappy compression
+    pub fn is_compressible(&self) -> bool {
+        self.is_client_writing_data()
+            || matches!(
+                self,
+                Opcode::Get
+                    | Opcode::GetQuiet
+                    | Opcode::GetKey
+                    | Opcode::GetKeyQuiet
+                    | Opcode::GetAndTouch
+                    | Opcode::GetAndTouchQuiet
+                    | Opcode::GetLocked
+                    | Opcode::GetReplica
+                    | Opcode::GetRandomKey
+            )
+    }
+
+    /// Does the provided opcode support durability or not
+    pub fn is_durability_supported(&self) -> bool {
+        self.is_subdoc_mutation()
+            || matches!(
+                self,
+                Opcode::Upsert
+                    | Opcode::UpsertQuiet
+                    | Opcode::Insert
+                    | Opcode::InsertQuiet
+                    | Opcode::Replace
+                    | Opcode::ReplaceQuiet
+                    | Opcode::Remove
+                    | Opcode::RemoveQuiet
+                    | Opcode::Increment
+                    | Opcode::IncrementQuiet
+                    | Opcode::Decrement
+                    | Opcode::DecrementQuiet
+                    | Opcode::Append
+                    | Opcode::AppendQuiet
+                    | Opcode::Prepend
+                    | Opcode::PrependQuiet
+            )
+    }
+
+    /// Does the provided opcode support reordering
+    pub fn is_reorder_supported(&self) -> bool {
+        self.is_subdoc()
+            || matches!(
+                self,
+                Opcode::Get
+                    | Opcode::GetQuiet
+                    | Opcode::GetKey
+                    | Opcode::GetKeyQuiet
+                    | Opcode::Upsert
+                    | Opcode::UpsertQuiet
+                    | Opcode::Insert
+                    | Opcode::InsertQuiet
+                    | Opcode::Replace
+                    | Opcode::ReplaceQuiet
+                    | Opcode::Remove
+                    | Opcode::RemoveQuiet
+                    | Opcode::Increment
+                    | Opcode::IncrementQuiet
+                    | Opcode::Decrement
+                    | Opcode::DecrementQuiet
+                    | Opcode::Append
+                    | Opcode::AppendQuiet
+                    | Opcode::Prepend
+                    | Opcode::PrependQuiet
+                    | Opcode::Touch
+                    | Opcode::GetAndTouch
+                    | Opcode::GetAndTouchQuiet
+                    | Opcode::GetLocked
+                    | Opcode::Unlock
+                    | Opcode::GetReplica
+                    | Opcode::GetMeta
+                    | Opcode::GetMetaQuiet
+            )
+    }
+
+    /// Does the command carry a key which contains a collection identifier
+    pub fn is_collection_command(&self) -> bool {
+        self.is_subdoc()
+            || matches!(
+                self,
+                Opcode::Get
+                    | Opcode::GetQuiet
+                    | Opcode::GetKey
+                    | Opcode::GetKeyQuiet
+                    | Opcode::Upsert
+                    | Opcode::UpsertQuiet
+                    | Opcode::Insert
+                    | Opcode::InsertQuiet
+                    | Opcode::Replace
+                    | Opcode::ReplaceQuiet
+                    | Opcode::Remove
+                    | Opcode::RemoveQuiet
+                    | Opcode::Increment
+                    | Opcode::IncrementQuiet
+                    | Opcode::Decrement
+                    | Opcode::DecrementQuiet
+                    | Opcode::Append
+                    | Opcode::AppendQuiet
+                    | Opcode::Prepend
+                    | Opcode::PrependQuiet
+                    | Opcode::Touch
+                    | Opcode::GetAndTouch
+                    | Opcode::GetAndTouchQuiet
+                    | Opcode::GetLocked
+                    | Opcode::Unlock
+                    | Opcode::GetReplica
+                    | Opcode::GetMeta
+                    | Opcode::GetMetaQuiet
+                    | Opcode::SetWithMeta
+                    | Opcode::SetWithMetaQuiet
+                    | Opcode::AddWithMeta
+                    | Opcode::AddWithMetaQuiet
+                    | Opcode::DelWithMeta
+                    | Opcode::DelWithMetaQuiet
+                    | Opcode::GetKeys
+            )
+    }
+
     /// Does the provided opcode support preserving TTL
     pub fn is_preserve_ttl_supported(&self) -> bool {
-        matches!(self, Opcode::Upsert | Opcode::Replace)
+        self.is_subdoc_mutation()
+            || matches!(
+                self,
+                Opcode::Upsert
+                    | Opcode::UpsertQuiet
+                    | Opcode::Replace
+                    | Opcode::ReplaceQuiet
+                    | Opcode::Increment
+                    | Opcode::IncrementQuiet
+                    | Opcode::Decrement
+                    | Opcode::DecrementQuiet
+            )
     }
 
     /// Does the client write data with this opcode
     pub fn is_client_writing_data(&self) -> bool {
-        matches!(self, Opcode::Upsert | Opcode::Insert | Opcode::Replace)
+        matches!(
+            self,
+            Opcode::Upsert
+                | Opcode::UpsertQuiet
+                | Opcode::Insert
+                | Opcode::InsertQuiet
+                | Opcode::Replace
+                | Opcode::ReplaceQuiet
+                | Opcode::Append
+                | Opcode::AppendQuiet
+                | Opcode::Prepend
+                | Opcode::PrependQuiet
+                | Opcode::SetWithMeta
+                | Opcode::SetWithMetaQuiet
+                | Opcode::AddWithMeta
+                | Opcode::AddWithMetaQuiet
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        for magic in [Magic::ClientRequest, Magic::ServerRequest] {
+            for value in 0..=u8::MAX {
+                if let Ok(opcode) = Opcode::from_u8(value, magic) {
+                    assert_eq!(u8::from(opcode), value);
+                    assert_eq!(opcode.is_server(), magic.is_server_magic());
+                }
+            }
+        }
+
+        assert_eq!(
+            Opcode::from_u8(0x02, Magic::ClientRequest).unwrap(),
+            Opcode::Insert
+        );
+        assert_eq!(
+            Opcode::from_u8(0x02, Magic::ServerRequest).unwrap(),
+            Opcode::Authenticate
+        );
+        assert!(Opcode::from_u8(0x04, Magic::ServerRequest).is_err());
+        assert!(Opcode::from_u8(0xff, Magic::ClientRequest).is_err());
+    }
+
+    #[test]
+    fn test_predicates() {
+        assert!(Opcode::SubdocMultiMutation.is_subdoc_mutation());
+        assert!(Opcode::SubdocMultiMutation.is_durability_supported());
+        assert!(!Opcode::SubdocMultiLookup.is_subdoc_mutation());
+        assert!(Opcode::SubdocMultiLookup.is_collection_command());
+        assert!(Opcode::UpsertQuiet.is_quiet());
+        assert!(Opcode::DcpOsoSnapshot.is_dcp());
+        assert!(!Opcode::SelectBucket.is_dcp());
+        assert!(Opcode::RangeScanContinue.is_range_scan());
+        assert!(Opcode::Append.is_client_writing_data());
+        assert!(!Opcode::Touch.is_client_writing_data());
     }
 }
@@ -11,28 +11,369 @@ pub enum Status {
     /// The key exists in the cluster (with another CAS value)
     KeyExists,
 
+    /// The document exceeds the maximum size
+    TooBig,
+
     /// Invalid request
     InvalidArguments,
 
+    /// The document was not stored, e.g. an append to a missing key
+    NotStored,
+
+    /// The document isn't a number, so can't be incremented or decremented
+    DeltaBadval,
+
     /// The server is not responsible for the requested vbucket
     NotMyVBucket,
 
+    /// The connection hasn't selected a bucket
+    NoBucket,
+
+    /// The document is locked by another client
+    Locked,
+
+    /// There is no DCP stream for the vbucket
+    DcpStreamNotFound,
+
+    /// The opaque doesn't match the DCP stream's
+    OpaqueNoMatch,
+
+    /// The request would be throttled if sent
+    WouldThrottle,
+
+    /// The bucket only serves the cluster map
+    ConfigOnly,
+
+    /// The document isn't locked, so can't be unlocked
+    NotLocked,
+
+    /// The CAS isn't valid for the operation
+    CasValueInvalid,
+
+    /// The user's authentication has expired
+    AuthenticationStale,
+
     /// Could not authenticate successfully
     AuthenticationError,
 
+    /// The authentication needs another step
+    AuthenticationContinue,
+
+    /// The requested value is outside the legal range
+    OutOfRange,
+
+    /// The DCP consumer must roll back to the seqno in the body
+    Rollback,
+
+    /// The user doesn't have the privilege for the operation
+    NoAccess,
+
+    /// The node is still being initialized
+    NotInitialized,
+
+    /// The tenant has sent too much data
+    RateLimitedNetworkIngress,
+
+    /// The tenant has received too much data
+    RateLimitedNetworkEgress,
+
+    /// The tenant has too many connections
+    RateLimitedMaxConnections,
+
+    /// The tenant has sent too many commands
+    RateLimitedMaxCommands,
+
+    /// The scope has reached its data limit
+    ScopeSizeLimitExceeded,
+
+    /// The bucket has reached its data limit
+    BucketSizeLimitExceeded,
+
+    /// Too little of the bucket is resident in memory
+    BucketResidentRatioTooLow,
+
+    /// The bucket holds more data than it may
+    BucketDataSizeTooBig,
+
+    /// The bucket's disk is running out of space
+    BucketDiskSpaceTooLow,
+
+    /// The server doesn't know the opcode
+    UnknownCommand,
+
+    /// The server is out of memory
+    OutOfMemory,
+
+    /// The server knows the command but doesn't support it
+    NotSupported,
+
+    /// An error in the server, which should be logged there
+    InternalError,
+
+    /// The server is too busy to handle the request
+    Busy,
+
+    /// A temporary failure, the request can be retried
+    Etmpfail,
+
+    /// The extended attributes are invalid
+    XattrInvalid,
+
+    /// The collection doesn't exist
+    UnknownCollection,
+
+    /// There is no collections manifest
+    NoCollectionsManifest,
+
+    /// The collections manifest couldn't be applied
+    CannotApplyCollectionsManifest,
+
+    /// The client's collections manifest is ahead of the server's
+    CollectionsManifestIsAhead,
+
+    /// The scope doesn't exist
+    UnknownScope,
+
+    /// The DCP stream id is missing or invalid
+    DcpStreamIdInvalid,
+
+    /// The durability level isn't valid
+    DurabilityInvalidLevel,
+
+    /// There aren't enough nodes to meet the durability requirement
+    DurabilityImpossible,
+
+    /// A synchronous write to the key is in progress
+    SyncWriteInProgress,
+
+    /// The synchronous write may or may not have succeeded
+    SyncWriteAmbiguous,
+
+    /// A synchronous write to the key is being committed again
+    SyncWriteReCommitInProgress,
+
+    /// The range scan was cancelled
+    RangeScanCancelled,
+
+    /// The range scan has more results to continue with
+    RangeScanMore,
+
+    /// The range scan has returned every result
+    RangeScanComplete,
+
+    /// The vbucket uuid doesn't match
+    VbUuidNotEqual,
+
+    /// The path doesn't exist in the document
+    SubdocPathNotFound,
+
+    /// The path doesn't match the document's structure
+    SubdocPathMismatch,
+
+    /// The path isn't valid syntax
+    SubdocPathInvalid,
+
+    /// The path is too long
+    SubdocPathTooBig,
+
+    /// The document is nested too deeply to parse
+    SubdocDocTooDeep,
+
+    /// The value would make the document invalid
+    SubdocValueCantInsert,
+
+    /// The document isn't JSON
+    SubdocDocNotJson,
+
+    /// The number at the path is out of range
+    SubdocNumOutOfRange,
+
+    /// The delta isn't a valid number
+    SubdocDeltaInvalid,
+
+    /// The path already exists in the document
+    SubdocPathExists,
+
+    /// Inserting the value would nest the document too deeply
+    SubdocValueTooDeep,
+
+    /// The combination of subdoc commands isn't valid
+    SubdocInvalidCombo,
+
+    /// Some of the paths of a multi-path lookup or mutation failed
+    SubdocMultiPathFailure,
+
+    /// The operation succeeded on a deleted document
+    SubdocSuccessDeleted,
+
+    /// The combination of xattr flags isn't valid
+    SubdocXattrInvalidFlagCombo,
+
+    /// The combination of xattr keys isn't valid
+    SubdocXattrInvalidKeyCombo,
+
+    /// The macro isn't known
+    SubdocXattrUnknownMacro,
+
+    /// The virtual attribute isn't known
+    SubdocXattrUnknownVattr,
+
+    /// Virtual attributes can't be modified
+    SubdocXattrCantModifyVattr,
+
+    /// Some of the paths failed, on a deleted document
+    SubdocMultiPathFailureDeleted,
+
+    /// Xattr paths must come before the document's
+    SubdocInvalidXattrOrder,
+
+    /// The virtual attribute macro isn't known
+    SubdocXattrUnknownVattrMacro,
+
+    /// Only deleted documents can be revived
+    SubdocCanOnlyReviveDeletedDocuments,
+
+    /// A deleted document can't have a value
+    SubdocDeletedDocumentCantHaveValue,
+
     /// An error we don't know about. Use the error map returned from the server to decode the status
     Unknown(u16),
 }
 
+impl Status {
+    /// Did the operation succeed. Some statuses other than [Status::Success]
+    /// carry a result too.
+    pub fn is_success(&self) -> bool {
+        matches!(
+            self,
+            Status::Success
+                | Status::SubdocSuccessDeleted
+                | Status::SubdocMultiPathFailure
+                | Status::SubdocMultiPathFailureDeleted
+                | Status::RangeScanMore
+                | Status::RangeScanComplete
+        )
+    }
+
+    /// Is the failure temporary, so the same request may succeed if sent
+    /// again later, after refreshing the cluster map for
+    /// [Status::NotMyVBucket]. Whether an unknown status is retryable is in
+    /// the error map.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Status::NotMyVBucket
+                | Status::Locked
+                | Status::WouldThrottle
+                | Status::NotInitialized
+                | Status::RateLimitedNetworkIngress
+                | Status::RateLimitedNetworkEgress
+                | Status::RateLimitedMaxConnections
+                | Status::RateLimitedMaxCommands
+                | Status::OutOfMemory
+                | Status::Busy
+                | Status::Etmpfail
+                | Status::SyncWriteInProgress
+                | Status::SyncWriteReCommitInProgress
+        )
+    }
+
+    /// Did some paths of a multi-path subdoc operation fail, the status of
+    /// each is in the body
+    pub fn is_subdoc_multi_path_failure(&self) -> bool {
+        matches!(
+            self,
+            Status::SubdocMultiPathFailure | Status::SubdocMultiPathFailureDeleted
+        )
+    }
+
+    /// Is the status one of the subdoc range
+    pub fn is_subdoc(&self) -> bool {
+        (0xc0..=0xdf).contains(&u16::from(*self))
+    }
+}
+
 impl From<Status> for u16 {
     fn from(status: Status) -> Self {
         match status {
             Status::Success => 0x0000,
             Status::KeyNotFound => 0x0001,
             Status::KeyExists => 0x0002,
+            Status::TooBig => 0x0003,
             Status::InvalidArguments => 0x0004,
+            Status::NotStored => 0x0005,
+            Status::DeltaBadval => 0x0006,
             Status::NotMyVBucket => 0x0007,
+            Status::NoBucket => 0x0008,
+            Status::Locked => 0x0009,
+            Status::DcpStreamNotFound => 0x000a,
+            Status::OpaqueNoMatch => 0x000b,
+            Status::WouldThrottle => 0x000c,
+            Status::ConfigOnly => 0x000d,
+            Status::NotLocked => 0x000e,
+            Status::CasValueInvalid => 0x000f,
+            Status::AuthenticationStale => 0x001f,
             Status::AuthenticationError => 0x0020,
+            Status::AuthenticationContinue => 0x0021,
+            Status::OutOfRange => 0x0022,
+            Status::Rollback => 0x0023,
+            Status::NoAccess => 0x0024,
+            Status::NotInitialized => 0x0025,
+            Status::RateLimitedNetworkIngress => 0x0030,
+            Status::RateLimitedNetworkEgress => 0x0031,
+            Status::RateLimitedMaxConnections => 0x0032,
+            Status::RateLimitedMaxCommands => 0x0033,
+            Status::ScopeSizeLimitExceeded => 0x0034,
+            Status::BucketSizeLimitExceeded => 0x0035,
+            Status::BucketResidentRatioTooLow => 0x0036,
+            Status::BucketDataSizeTooBig => 0x0037,
+            Status::BucketDiskSpaceTooLow => 0x0038,
+            Status::UnknownCommand => 0x0081,
+            Status::OutOfMemory => 0x0082,
+            Status::NotSupported => 0x0083,
+            Status::InternalError => 0x0084,
+            Status::Busy => 0x0085,
+            Status::Etmpfail => 0x0086,
+            Status::XattrInvalid => 0x0087,
+            Status::UnknownCollection => 0x0088,
+            Status::NoCollectionsManifest => 0x0089,
+            Status::CannotApplyCollectionsManifest => 0x008a,
+            Status::CollectionsManifestIsAhead => 0x008b,
+            Status::UnknownScope => 0x008c,
+            Status::DcpStreamIdInvalid => 0x008d,
+            Status::DurabilityInvalidLevel => 0x00a0,
+            Status::DurabilityImpossible => 0x00a1,
+            Status::SyncWriteInProgress => 0x00a2,
+            Status::SyncWriteAmbiguous => 0x00a3,
+            Status::SyncWriteReCommitInProgress => 0x00a4,
+            Status::RangeScanCancelled => 0x00a5,
+            Status::RangeScanMore => 0x00a6,
+            Status::RangeScanComplete => 0x00a7,
+            Status::VbUuidNotEqual => 0x00a8,
+            Status::SubdocPathNotFound => 0x00c0,
+            Status::SubdocPathMismatch => 0x00c1,
+            Status::SubdocPathInvalid => 0x00c2,
+            Status::SubdocPathTooBig => 0x00c3,
+            Status::SubdocDocTooDeep => 0x00c4,
+            Status::SubdocValueCantInsert => 0x00c5,
+            Status::SubdocDocNotJson => 0x00c6,
+            Status::SubdocNumOutOfRange => 0x00c7,
+            Status::SubdocDeltaInvalid => 0x00c8,
+            Status::SubdocPathExists => 0x00c9,
+            Status::SubdocValueTooDeep => 0x00ca,
+            Status::SubdocInvalidCombo => 0x00cb,
+            Status::SubdocMultiPathFailure => 0x00cc,
+            Status::SubdocSuccessDeleted => 0x00cd,
+            Status::SubdocXattrInvalidFlagCombo => 0x00ce,
+            Status::SubdocXattrInvalidKeyCombo => 0x00cf,
+            Status::SubdocXattrUnknownMacro => 0x00d0,
+            Status::SubdocXattrUnknownVattr => 0x00d1,
+            Status::SubdocXattrCantModifyVattr => 0x00d2,
+            Status::SubdocMultiPathFailureDeleted => 0x00d3,
+            Status::SubdocInvalidXattrOrder => 0x00d4,
+            Status::SubdocXattrUnknownVattrMacro => 0x00d5,
+            Status::SubdocCanOnlyReviveDeletedDocuments => 0x00d6,
+            Status::SubdocDeletedDocumentCantHaveValue => 0x00d7,
             Status::Unknown(status) => status,
         }
     }
@@ -44,10 +385,112 @@ impl From<u16> for Status {
             0x0000 => Status::Success,
             0x0001 => Status::KeyNotFound,
             0x0002 => Status::KeyExists,
+            0x0003 => Status::TooBig,
             0x0004 => Status::InvalidArguments,
+            0x0005 => Status::NotStored,
+            0x0006 => Status::DeltaBadval,
             0x0007 => Status::NotMyVBucket,
+            0x0008 => Status::NoBucket,
+            0x0009 => Status::Locked,
+            0x000a => Status::DcpStreamNotFound,
+            0x000b => Status::OpaqueNoMatch,
+            0x000c => Status::WouldThrottle,
+            0x000d => Status::ConfigOnly,
+            0x000e => Status::NotLocked,
+            0x000f => Status::CasValueInvalid,
+            0x001f => Status::AuthenticationStale,
             0x0020 => Status::AuthenticationError,
+            0x0021 => Status::AuthenticationContinue,
+            0x0022 => Status::OutOfRange,
+            0x0023 => Status::Rollback,
+            0x0024 => Status::NoAccess,
+            0x0025 => Status::NotInitialized,
+            0x0030 => Status::RateLimitedNetworkIngress,
+            0x0031 => Status::RateLimitedNetworkEgress,
+            0x0032 => Status::RateLimitedMaxConnections,
+            0x0033 => Status::RateLimitedMaxCommands,
+            0x0034 => Status::ScopeSizeLimitExceeded,
+            0x0035 => Status::BucketSizeLimitExceeded,
+            0x0036 => Status::BucketResidentRatioTooLow,
+            0x0037 => Status::BucketDataSizeTooBig,
+            0x0038 => Status::BucketDiskSpaceTooLow,
+            0x0081 => Status::UnknownCommand,
+            0x0082 => Status::OutOfMemory,
+            0x0083 => Status::NotSupported,
+            0x0084 => Status::InternalError,
+            0x0085 => Status::Busy,
+            0x0086 => Status::Etmpfail,
+            0x0087 => Status::XattrInvalid,
+            0x0088 => Status::UnknownCollection,
+            0x0089 => Status::NoCollectionsManifest,
+            0x008a => Status::CannotApplyCollectionsManifest,
+            0x008b => Status::CollectionsManifestIsAhead,
+            0x008c => Status::UnknownScope,
+            0x008d => Status::DcpStreamIdInvalid,
+            0x00a0 => Status::DurabilityInvalidLevel,
+            0x00a1 => Status::DurabilityImpossible,
+            0x00a2 => Status::SyncWriteInProgress,
+            0x00a3 => Status::SyncWriteAmbiguous,
+            0x00a4 => Status::SyncWriteReCommitInProgress,
+            0x00a5 => Status::RangeScanCancelled,
+            0x00a6 => Status::RangeScanMore,
+            0x00a7 => Status::RangeScanComplete,
+            0x00a8 => Status::VbUuidNotEqual,
+            0x00c0 => Status::SubdocPathNotFound,
+            0x00c1 => Status::SubdocPathMismatch,
+            0x00c2 => Status::SubdocPathInvalid,
+            0x00c3 => Status::SubdocPathTooBig,
+            0x00c4 => Status::SubdocDocTooDeep,
+            0x00c5 => Status::SubdocValueCantInsert,
+            0x00c6 => Status::SubdocDocNotJson,
+            0x00c7 => Status::SubdocNumOutOfRange,
+            0x00c8 => Status::SubdocDeltaInvalid,
+            0x00c9 => Status::SubdocPathExists,
+            0x00ca => Status::SubdocValueTooDeep,
+            0x00cb => Status::SubdocInvalidCombo,
+            0x00cc => Status::SubdocMultiPathFailure,
+            0x00cd => Status::SubdocSuccessDeleted,
+            0x00ce => Status::SubdocXattrInvalidFlagCombo,
+            0x00cf => Status::SubdocXattrInvalidKeyCombo,
+            0x00d0 => Status::SubdocXattrUnknownMacro,
+            0x00d1 => Status::SubdocXattrUnknownVattr,
+            0x00d2 => Status::SubdocXattrCantModifyVattr,
+            0x00d3 => Status::SubdocMultiPathFailureDeleted,
+            0x00d4 => Status::SubdocInvalidXattrOrder,
+            0x00d5 => Status::SubdocXattrUnknownVattrMacro,
+            0x00d6 => Status::SubdocCanOnlyReviveDeletedDocuments,
+            0x00d7 => Status::SubdocDeletedDocumentCantHaveValue,
             _ => Status::Unknown(status),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        for code in 0..=u16::MAX {
+            let status = Status::from(code);
+            assert_eq!(u16::from(status), code);
+        }
+        assert_eq!(Status::from(0x86), Status::Etmpfail);
+        assert_eq!(Status::from(0xe0), Status::Unknown(0xe0));
+    }
+
+    #[test]
+    fn test_classification() {
+        assert!(Status::Success.is_success());
+        assert!(Status::SubdocMultiPathFailure.is_success());
+        assert!(Status::SubdocMultiPathFailure.is_subdoc_multi_path_failure());
+        assert!(Status::SubdocPathNotFound.is_subdoc());
+        assert!(!Status::SubdocPathNotFound.is_success());
+        assert!(!Status::SubdocPathNotFound.is_subdoc_multi_path_failure());
+
+        assert!(Status::Etmpfail.is_retryable());
+        assert!(Status::SyncWriteInProgress.is_retryable());
+        assert!(!Status::KeyNotFound.is_retryable());
+        assert!(!Status::Unknown(0xff).is_retryable());
+    }
+}